@@ -0,0 +1,92 @@
+// Example: Allocator API
+//
+// This example shows how to use the allocators of `r-efi-alloc` with the
+// collections of the rust `alloc` crate, without going through the global
+// allocator. This relies on the unstable `allocator_api` feature of rust,
+// which allows parameterizing collections with an allocator object.
+//
+// The example creates two allocators with different UEFI memory types. The
+// first one serves a vector of UTF-16 characters, which is then printed to
+// console-out. The second one is used for a short parsing phase, in which
+// all allocations are placed in `BootServicesData`. Once the phase is over,
+// the allocator is dropped, which is statically guaranteed to happen after
+// all of its allocations were released.
+//
+// Note that no global allocator is used for any of this. Every collection
+// carries a reference to its allocator, and releases its memory to it.
+// However, linking the `alloc` crate requires a global allocator to be
+// registered. We register a bridge without ever attaching an allocator, so
+// any accidental use of the global allocator fails.
+
+#![feature(allocator_api)]
+#![no_main]
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use r_efi::efi;
+use r_efi_alloc::alloc::Allocator;
+
+#[global_allocator]
+static GLOBAL_ALLOCATOR: r_efi_alloc::global::Bridge = r_efi_alloc::global::Bridge::new();
+
+#[panic_handler]
+fn rust_panic_handler(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// A parsing phase with its own allocator. The parsed values are collected in
+// a vector that lives in the memory of the allocator passed in. Since the
+// vector borrows the allocator, the compiler guarantees it is released before
+// the allocator goes away.
+fn parse_phase(allocator: &Allocator, input: &str) -> u32 {
+    let mut values: Vec<u32, &Allocator> = Vec::new_in(allocator);
+
+    for word in input.split(' ') {
+        if let Ok(v) = word.parse::<u32>() {
+            values.push(v);
+        }
+    }
+
+    values.iter().sum()
+}
+
+pub fn efi_run(_h: efi::Handle, st: *mut efi::SystemTable) -> efi::Status {
+    // Create an allocator for `LoaderData`, which serves all the data that
+    // we print to the console.
+    let loader = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+
+    // Run a parsing phase on top of a dedicated allocator for
+    // `BootServicesData`. It is torn down once the phase is over.
+    let sum = {
+        let phase = unsafe { Allocator::from_system_table(st, efi::BOOT_SERVICES_DATA) };
+        parse_phase(&phase, "1 2 3 4 5")
+    };
+
+    // Box a value in the memory of the loader allocator.
+    let boxed: Box<u32, &Allocator> = Box::new_in(sum, &loader);
+
+    // Build the UTF-16 output string in a vector that uses the loader
+    // allocator. UEFI requires a terminating NUL.
+    let mut v: Vec<u16, &Allocator> = Vec::new_in(&loader);
+    v.extend("Sum: ".encode_utf16());
+    v.push(u16::from(b'0') + (*boxed / 10) as u16);
+    v.push(u16::from(b'0') + (*boxed % 10) as u16);
+    v.extend("\r\n".encode_utf16());
+    v.push(0);
+
+    // Print the string on console-out.
+    let r = unsafe { ((*(*st).con_out).output_string)((*st).con_out, v.as_mut_ptr()) };
+    if r.is_error() {
+        return r;
+    }
+
+    efi::Status::SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn efi_main(h: efi::Handle, st: *mut efi::SystemTable) -> efi::Status {
+    efi_run(h, st)
+}
@@ -14,7 +14,37 @@
 //! `allocator_api` feature is enabled. This requires a nightly / unstable
 //! compiler. If the feature is not enabled, only the raw interface is
 //! available.
+//!
+//! Additionally, a `PageAllocator` type is provided, which forwards memory
+//! requests to the UEFI page allocator rather than the pool allocator. It
+//! serves page-granular, page-aligned memory blocks. For UEFI runtime
+//! drivers, the `RuntimeAllocator` type serves runtime-services data and
+//! survives the transition to the operating system.
+//!
+//! The `DebugAllocator` type wraps an `Allocator` and guards every block with
+//! redzones, to detect heap corruption. It is only available with the
+//! `debug` feature.
+//!
+//! The `TrackingAllocator` type records all live blocks, to detect leaks and
+//! to release everything allocated after a checkpoint. It is always
+//! available.
+//!
+//! The `PromotingAllocator` wrapper moves large blocks to the page allocator,
+//! so buffers that are grown repeatedly are not copied on every step.
+//!
+//! The `MemType` type validates memory types before they are passed to the
+//! firmware, and provides presets for the commonly used ones.
+//!
+//! Optional behaviors, like clearing or poisoning blocks, are selected at
+//! runtime via the `Strategy` of an allocator, rather than at compile time.
+//! To simulate the memory pressure of smaller platforms, the memory an
+//! allocator serves can be capped via `Allocator::with_pool_cap()` and a
+//! shared `PoolCap`.
+//!
+//! For memory that must be located below 4GiB, `PageAllocator::low_memory()`
+//! and the `LowMemoryAllocator` wrapper of the pool allocator are provided.
 
+use core::sync::atomic;
 use r_efi::efi;
 
 /// Memory Allocator
@@ -28,9 +58,179 @@ use r_efi::efi;
 /// Hence, this allocator can also be used to back the global memory-allocator
 /// of `liballoc` (or `libstd`). See the `Global` type for an implementation of
 /// the global allocator, based on this type.
+///
+/// A clone of an allocator serves the same memory type from the same
+/// system-table, with the same observer, checks, strategy and pool cap. It
+/// starts with the `last_error()` of the original, but records its own
+/// errors from then on. With the `stats` feature, it starts with fresh
+/// counters. Blocks can be released through any clone, but the counters of
+/// an allocator only stay balanced if its blocks are released through it.
+///
+/// The allocator is deliberately not `Copy`. Its last error and counters
+/// are atomic state, which an implicit copy would silently fork.
 pub struct Allocator {
     system_table: *mut efi::SystemTable,
     memory_type: efi::MemoryType,
+    last_error: atomic::AtomicUsize,
+    observer: Option<&'static dyn crate::observe::AllocObserver>,
+    ap_check: Option<ApCheck>,
+    strategy: atomic::AtomicU32,
+    pool_cap: Option<&'static PoolCap>,
+    #[cfg(feature = "stats")]
+    stats: crate::stats::Stats,
+}
+
+/// Allocation Strategy
+///
+/// A set of optional behaviors an `Allocator` applies to the blocks it
+/// serves. Unlike cargo features, the strategy is selected at runtime (see
+/// `Allocator::set_strategy()`), so a single binary can enable diagnostics
+/// based on its command-line or a UEFI variable, without being rebuilt.
+///
+/// Strategies are combined via `|`. The empty strategy is the default and
+/// leaves blocks untouched.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Strategy(u32);
+
+impl Strategy {
+    /// No optional behavior.
+    pub const NONE: Strategy = Strategy(0);
+    /// Clear every block before it is returned to the caller.
+    pub const ZERO: Strategy = Strategy(1 << 0);
+    /// Fill every block with a poison pattern before it is released, so
+    /// stale accesses read garbage rather than the old data.
+    pub const POISON: Strategy = Strategy(1 << 1);
+
+    // Names of all strategies, as accepted by `parse()`.
+    const NAMES: [(&'static str, Strategy); 2] =
+        [("zero", Strategy::ZERO), ("poison", Strategy::POISON)];
+
+    /// Check for Strategy
+    ///
+    /// Return whether all behaviors of `other` are enabled in this strategy.
+    pub fn contains(self, other: Strategy) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Parse Strategy
+    ///
+    /// Parse a comma-separated list of strategy names (`zero`, `poison`), as
+    /// given on a command-line or stored in a UEFI variable. Surrounding
+    /// whitespace and empty entries are ignored. This yields `None` if any
+    /// entry is not a known strategy name.
+    pub fn parse(s: &str) -> Option<Strategy> {
+        let mut strategy = Strategy::NONE;
+
+        for entry in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let (_, v) = Strategy::NAMES.iter().find(|(n, _)| *n == entry)?;
+            strategy = strategy | *v;
+        }
+
+        Some(strategy)
+    }
+}
+
+impl core::ops::BitOr for Strategy {
+    type Output = Strategy;
+
+    fn bitor(self, other: Strategy) -> Strategy {
+        Strategy(self.0 | other.0)
+    }
+}
+
+/// Pool Cap
+///
+/// A budget of pool memory, shared by all allocators it is attached to via
+/// `Allocator::with_pool_cap()`. Requests that would exceed the budget fail
+/// with `OUT_OF_RESOURCES`, exactly like the firmware reports an exhausted
+/// pool. This allows validating on development machines that an image stays
+/// within the memory budget of the smallest platform it supports.
+///
+/// The cap counts the bytes requested by the callers, not the overhead of
+/// the firmware pool. It is meant to be declared as a static variable, so
+/// blocks can be released through any allocator sharing the cap. Blocks that
+/// were not counted against the cap (e.g., served by an allocator without
+/// it) must not be released through an allocator with it. With debug
+/// assertions, this is caught when the cap would underflow.
+pub struct PoolCap {
+    cap: usize,
+    used: atomic::AtomicUsize,
+    peak: atomic::AtomicUsize,
+}
+
+impl PoolCap {
+    /// Create Pool Cap
+    ///
+    /// Create a new pool cap with a budget of `bytes`.
+    pub const fn new(bytes: usize) -> PoolCap {
+        PoolCap {
+            cap: bytes,
+            used: atomic::AtomicUsize::new(0),
+            peak: atomic::AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the budget of this cap in bytes
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// Query Pool Usage
+    ///
+    /// Return the number of bytes currently counted against this cap.
+    pub fn used(&self) -> usize {
+        self.used.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Query Peak Pool Usage
+    ///
+    /// Return the highest number of bytes that were counted against this
+    /// cap at any time. Compared against the budget of a platform, this
+    /// tells how much headroom an image has left.
+    pub fn peak(&self) -> usize {
+        self.peak.load(atomic::Ordering::Relaxed)
+    }
+
+    // Count @bytes against the cap. This fails if the cap would be exceeded,
+    // in which case nothing is counted. The counters are purely diagnostic
+    // and carry no dependent state, so relaxed ordering suffices.
+    fn charge(&self, bytes: usize) -> bool {
+        let r = self.used.fetch_update(
+            atomic::Ordering::Relaxed,
+            atomic::Ordering::Relaxed,
+            |v| v.checked_add(bytes).filter(|v| *v <= self.cap),
+        );
+
+        match r {
+            Ok(v) => {
+                self.peak.fetch_max(v + bytes, atomic::Ordering::Relaxed);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Return @bytes previously counted via `charge()`. Releasing more than
+    // was charged means a block that was never counted against this cap was
+    // released through an allocator using it. This is a bug of the caller,
+    // which trips a debug assertion. Otherwise, the counter is left
+    // untouched rather than wrapping around.
+    fn release(&self, bytes: usize) {
+        let r = self.used.fetch_update(
+            atomic::Ordering::Relaxed,
+            atomic::Ordering::Relaxed,
+            |v| v.checked_sub(bytes),
+        );
+        debug_assert!(r.is_ok(), "pool cap released more than was charged");
+    }
+}
+
+// State of the application-processor check of an allocator. This caches the
+// MP-Services protocol and the processor number of the BSP.
+#[derive(Clone, Copy)]
+struct ApCheck {
+    mp: *mut efi::protocols::mp_services::Protocol,
+    bsp: usize,
 }
 
 impl Allocator {
@@ -42,10 +242,12 @@ impl Allocator {
     /// given System-Table. Allocations will always use the memory type given
     /// as `memtype`.
     ///
-    /// Note that this interface is unsafe, since the caller must guarantee
-    /// that the System-Table is valid for as long as the Allocator is.
-    /// Furthermore, the caller must guarantee validity of the
-    /// system-table-interface. The latter is usually guaranteed by the
+    /// Safety
+    /// ------
+    ///
+    /// The caller must guarantee that the System-Table is valid for as long
+    /// as the Allocator is. Furthermore, the caller must guarantee validity
+    /// of the system-table-interface. The latter is usually guaranteed by the
     /// provider of the System-Table. The former is usually just a matter of
     /// tearing down the allocator before returning from your application
     /// entry-point.
@@ -56,6 +258,292 @@ impl Allocator {
         Allocator {
             system_table: st,
             memory_type: memtype,
+            last_error: atomic::AtomicUsize::new(
+                efi::Status::SUCCESS.as_usize(),
+            ),
+            observer: None,
+            ap_check: None,
+            strategy: atomic::AtomicU32::new(0),
+            pool_cap: None,
+            #[cfg(feature = "stats")]
+            stats: crate::stats::Stats::new()
+                .with_parent(&crate::stats::GLOBAL),
+        }
+    }
+
+    /// Create Allocator from UEFI Image Handle
+    ///
+    /// This creates a new Allocator object for the image given as `handle`,
+    /// using the data type of the image as memory type. That is, the
+    /// `LoadedImage` protocol of the image is queried, and allocations use
+    /// the memory type its data sections were loaded as (`LOADER_DATA` for
+    /// applications, `BOOT_SERVICES_DATA` for boot-service drivers, and
+    /// `RUNTIME_SERVICES_DATA` for runtime drivers). This classifies
+    /// allocations like the image itself, without hard-coding a memory type.
+    ///
+    /// If the `LoadedImage` protocol cannot be queried, the status code of
+    /// the firmware is returned.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `from_system_table()` apply.
+    /// Furthermore, `handle` must be a valid image handle.
+    pub unsafe fn from_image_handle(
+        handle: efi::Handle,
+        st: *mut efi::SystemTable,
+    ) -> Result<Allocator, efi::Status> {
+        let mut guid = efi::protocols::loaded_image::PROTOCOL_GUID;
+        let mut image: *mut core::ffi::c_void = core::ptr::null_mut();
+
+        let r = ((*(*st).boot_services).handle_protocol)(
+            handle, &mut guid, &mut image,
+        );
+        if r.is_error() {
+            return Err(r);
+        }
+
+        let image = image as *mut efi::protocols::loaded_image::Protocol;
+        Ok(Allocator::from_system_table(st, (*image).image_data_type))
+    }
+
+    /// Attach an Observer
+    ///
+    /// Attach the observer given as `observer` to this allocator, replacing
+    /// any observer attached before. The observer is notified about every
+    /// allocation, deallocation and allocation failure of this allocator.
+    /// See the `observe` module for details.
+    pub fn with_observer(
+        mut self,
+        observer: &'static dyn crate::observe::AllocObserver,
+    ) -> Allocator {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Forbid Allocations on Application Processors
+    ///
+    /// Enable a debug check that verifies every allocation and deallocation
+    /// on this allocator is performed on the bootstrap processor (BSP). UEFI
+    /// boot-services must not be called from application processors (APs),
+    /// and doing so usually corrupts firmware state or hangs the system.
+    ///
+    /// With this check enabled, the allocator queries `WhoAmI()` of the
+    /// MP-Services protocol given as `mp` before calling into the firmware.
+    /// Allocations on an AP fail with `ACCESS_DENIED` (see `last_error()`),
+    /// deallocations on an AP panic.
+    ///
+    /// This function must be called on the BSP, since it records the
+    /// processor number of the caller as BSP. It panics if `WhoAmI()` fails.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The caller must guarantee that `mp` points to a valid MP-Services
+    /// protocol for as long as the allocator is used.
+    pub unsafe fn with_ap_check(
+        mut self,
+        mp: *mut efi::protocols::mp_services::Protocol,
+    ) -> Allocator {
+        let mut bsp = 0;
+        let r = ((*mp).who_am_i)(mp, &mut bsp);
+        assert!(!r.is_error());
+
+        self.ap_check = Some(ApCheck { mp, bsp });
+        self
+    }
+
+    /// Select Allocation Strategy
+    ///
+    /// Replace the strategy of this allocator with `strategy`. This can be
+    /// called at any time, and affects all subsequent allocations and
+    /// deallocations. See `Strategy` for details.
+    pub fn set_strategy(&self, strategy: Strategy) {
+        self.strategy.store(strategy.0, atomic::Ordering::Relaxed);
+    }
+
+    /// Query Allocation Strategy
+    ///
+    /// Return the strategy currently selected on this allocator.
+    pub fn strategy(&self) -> Strategy {
+        Strategy(self.strategy.load(atomic::Ordering::Relaxed))
+    }
+
+    /// Simulate Pool Pressure
+    ///
+    /// Count all memory this allocator serves against the cap given as
+    /// `cap`, regardless of the memory the firmware has available. See
+    /// `PoolCap` for details. Allocators derived via `with_memory_type()` or
+    /// `clone()` share the cap, so blocks can be released through any of
+    /// them.
+    pub fn with_pool_cap(mut self, cap: &'static PoolCap) -> Allocator {
+        self.pool_cap = Some(cap);
+        self
+    }
+
+    // Count @bytes against the pool cap, if any. This fails if the cap would
+    // be exceeded.
+    fn pool_charge(&self, bytes: usize) -> bool {
+        match self.pool_cap {
+            Some(cap) => cap.charge(bytes),
+            None => true,
+        }
+    }
+
+    // Return @bytes to the pool cap, if any.
+    fn pool_release(&self, bytes: usize) {
+        if let Some(cap) = self.pool_cap {
+            cap.release(bytes);
+        }
+    }
+
+    // Check whether the caller runs on the BSP. If the AP-check is not
+    // enabled, this always returns true.
+    fn on_bsp(&self) -> bool {
+        match self.ap_check {
+            None => true,
+            Some(ref check) => {
+                let mut id = 0;
+                let r = unsafe { ((*check.mp).who_am_i)(check.mp, &mut id) };
+                !r.is_error() && id == check.bsp
+            }
+        }
+    }
+
+    // Forward an allocation request to the raw allocator and record the
+    // status code of the firmware, which is cleared again if the request
+    // succeeds. The status is stored with relaxed ordering, since it is
+    // purely diagnostic and carries no dependent state. Any attached
+    // observer is notified of the outcome.
+    unsafe fn alloc_recorded(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.alloc_typed(layout, self.memory_type)
+    }
+
+    // Same as `alloc_recorded()`, but with the given memory type rather than
+    // the memory type of the allocator.
+    unsafe fn alloc_typed(
+        &self,
+        layout: core::alloc::Layout,
+        memory_type: efi::MemoryType,
+    ) -> *mut u8 {
+        let r = self.alloc_unrecorded(layout, memory_type);
+        self.record_alloc_result(layout, r)
+    }
+
+    // Forward an allocation request to the raw allocator, charging it to the
+    // pool cap, if any. Neither the status nor the outcome are recorded, so
+    // the caller can still reject the block via `dealloc_unrecorded()`
+    // before passing the result to `record_alloc_result()`.
+    unsafe fn alloc_unrecorded(
+        &self,
+        layout: core::alloc::Layout,
+        memory_type: efi::MemoryType,
+    ) -> Result<*mut u8, efi::Status> {
+        if !self.on_bsp() {
+            Err(efi::Status::ACCESS_DENIED)
+        } else if !self.pool_charge(layout.size()) {
+            Err(efi::Status::OUT_OF_RESOURCES)
+        } else {
+            let r = crate::raw::alloc_status(
+                self.system_table,
+                layout,
+                memory_type,
+            );
+            if r.is_err() {
+                self.pool_release(layout.size());
+            }
+            r
+        }
+    }
+
+    // Release a block of `alloc_unrecorded()` that was never passed to
+    // `record_alloc_result()`.
+    unsafe fn dealloc_unrecorded(
+        &self,
+        ptr: *mut u8,
+        layout: core::alloc::Layout,
+    ) {
+        self.pool_release(layout.size());
+        crate::raw::dealloc(self.system_table, ptr, layout)
+    }
+
+    // Record the outcome @r of an allocation of @layout in the last error,
+    // the statistics and the observer, and return the block, or NULL on
+    // failure.
+    unsafe fn record_alloc_result(
+        &self,
+        layout: core::alloc::Layout,
+        r: Result<*mut u8, efi::Status>,
+    ) -> *mut u8 {
+        match r {
+            Ok(ptr) => {
+                if self.strategy().contains(Strategy::ZERO) {
+                    self.set_bytes(ptr, layout.size(), 0);
+                }
+                self.last_error.store(
+                    efi::Status::SUCCESS.as_usize(),
+                    atomic::Ordering::Relaxed,
+                );
+                #[cfg(feature = "stats")]
+                self.stats.record_alloc(layout.size());
+                if let Some(observer) = self.observer {
+                    observer.on_alloc(ptr, layout);
+                }
+                ptr
+            }
+            Err(status) => {
+                #[cfg(feature = "stats")]
+                self.stats.record_failure();
+                self.last_error
+                    .store(status.as_usize(), atomic::Ordering::Relaxed);
+                if let Some(observer) = self.observer {
+                    observer.on_failure(layout);
+                }
+                core::ptr::null_mut()
+            }
+        }
+    }
+
+    /// Return the System-Table
+    ///
+    /// Return the system-table this allocator was created with.
+    pub fn system_table(&self) -> *mut efi::SystemTable {
+        self.system_table
+    }
+
+    /// Return the Memory Type
+    ///
+    /// Return the memory type this allocator serves allocations with.
+    pub fn memory_type(&self) -> efi::MemoryType {
+        self.memory_type
+    }
+
+    /// Query Allocation Statistics
+    ///
+    /// Return a snapshot of the allocation counters of this allocator. This
+    /// is only available with the `stats` feature.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::Snapshot {
+        self.stats.snapshot()
+    }
+
+    /// Query Status of Last Failed Allocation
+    ///
+    /// Return the UEFI status code of the most recent allocation request on
+    /// this allocator, if it failed. If no request failed so far, or if the
+    /// most recent request succeeded, this yields `None`.
+    ///
+    /// The allocator interfaces of the rust standard library report failures
+    /// as a bare error without further information. This function allows
+    /// retrieving the reason the firmware gave (usually `OUT_OF_RESOURCES`,
+    /// but possibly `INVALID_PARAMETER` for invalid memory types).
+    pub fn last_error(&self) -> Option<efi::Status> {
+        let v = self.last_error.load(atomic::Ordering::Relaxed);
+
+        if v == efi::Status::SUCCESS.as_usize() {
+            None
+        } else {
+            Some(efi::Status::from_usize(v))
         }
     }
 
@@ -82,7 +570,47 @@ impl Allocator {
     ///    this when forwarding the pointer to other allocation services
     ///    outside of this module.
     pub unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        crate::raw::alloc(self.system_table, layout, self.memory_type)
+        self.alloc_recorded(layout)
+    }
+
+    /// Allocate Memory of a Given Memory Type
+    ///
+    /// This behaves like `alloc()`, but uses the memory type given as
+    /// `memtype` rather than the memory type of the allocator. This allows
+    /// serving the occasional block of a different type (e.g.,
+    /// `LOADER_CODE` for a trampoline) from the same allocator. The block is
+    /// released via `dealloc()` like any other block.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `alloc()` apply.
+    pub unsafe fn alloc_with_type(
+        &self,
+        layout: core::alloc::Layout,
+        memtype: efi::MemoryType,
+    ) -> *mut u8 {
+        self.alloc_typed(layout, memtype)
+    }
+
+    /// Derive Allocator with a Different Memory Type
+    ///
+    /// Create a new allocator on the same system-table, with the same
+    /// observer, checks and strategy, but serving allocations of the memory
+    /// type given as `memtype`. The new allocator starts with a clean
+    /// `last_error()` and, with the `stats` feature, fresh counters. Hence,
+    /// blocks should be released via the allocator that served them, so its
+    /// counters stay balanced.
+    pub fn with_memory_type(&self, memtype: efi::MemoryType) -> Allocator {
+        // The system-table was validated when `self` was created.
+        let mut allocator =
+            unsafe { Allocator::from_system_table(self.system_table, memtype) };
+
+        allocator.observer = self.observer;
+        allocator.ap_check = self.ap_check;
+        allocator.set_strategy(self.strategy());
+        allocator.pool_cap = self.pool_cap;
+        allocator
     }
 
     /// Deallocate Memory from UEFI Boot-Services
@@ -101,44 +629,2656 @@ impl Allocator {
     ///  * The passed layout must match the layout used to allocate the memory
     ///    block.
     pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        crate::raw::dealloc(self.system_table, ptr, layout)
+        self.dealloc_recorded(ptr, layout)
     }
-}
 
-#[cfg(feature = "allocator_api")]
-unsafe impl core::alloc::Allocator for Allocator {
-    fn allocate(
+    /// Allocate Uninitialized Slice
+    ///
+    /// Allocate memory for `n` elements of type `T` and return it as a slice
+    /// of uninitialized elements. This serves buffers that are filled by
+    /// the firmware (e.g., via `ReadBlocks()`), without casting raw pointers
+    /// by hand. Once initialized, use `assume_init_slice()` to convert the
+    /// slice.
+    ///
+    /// This yields `None` if the allocation fails, or if the size of the
+    /// slice overflows the address-space. Zero-sized slices do not allocate
+    /// memory. The slice must be released via `free_uninit_slice()` or
+    /// `free_slice()`.
+    pub fn alloc_uninit_slice<T>(
         &self,
-        layout: core::alloc::Layout,
-    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
-        let size = layout.size();
+        n: usize,
+    ) -> Option<core::ptr::NonNull<[core::mem::MaybeUninit<T>]>> {
+        let layout = core::alloc::Layout::array::<T>(n).ok()?;
 
-        let ptr = if size > 0 {
-            unsafe {
-                crate::raw::alloc(self.system_table, layout, self.memory_type)
-            }
+        let ptr = if layout.size() == 0 {
+            core::ptr::NonNull::<core::mem::MaybeUninit<T>>::dangling()
         } else {
-            layout.dangling().as_ptr() as *mut _
+            let ptr = unsafe { self.alloc_recorded(layout) };
+            core::ptr::NonNull::new(ptr as *mut core::mem::MaybeUninit<T>)?
         };
 
-        if ptr.is_null() {
-            Err(core::alloc::AllocError)
-        } else {
-            Ok(
-                core::ptr::NonNull::new(
-                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
-                ).unwrap(),
-            )
+        core::ptr::NonNull::new(core::ptr::slice_from_raw_parts_mut(
+            ptr.as_ptr(),
+            n,
+        ))
+    }
+
+    /// Assume Slice is Initialized
+    ///
+    /// Convert a slice returned by `alloc_uninit_slice()` into a slice of
+    /// initialized elements. No memory is copied.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// All elements of the slice must be initialized.
+    pub unsafe fn assume_init_slice<T>(
+        slice: core::ptr::NonNull<[core::mem::MaybeUninit<T>]>,
+    ) -> core::ptr::NonNull<[T]> {
+        core::ptr::NonNull::new_unchecked(slice.as_ptr() as *mut [T])
+    }
+
+    /// Release Uninitialized Slice
+    ///
+    /// Release a slice previously allocated via `alloc_uninit_slice()`. The
+    /// elements are not dropped.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The slice must be the same as previously returned by
+    /// `alloc_uninit_slice()` on this allocator, with its original length.
+    /// Every slice must be released exactly once.
+    pub unsafe fn free_uninit_slice<T>(
+        &self,
+        slice: core::ptr::NonNull<[core::mem::MaybeUninit<T>]>,
+    ) {
+        // The layout was already verified by `alloc_uninit_slice()`.
+        let layout = core::alloc::Layout::array::<T>(slice.len()).unwrap();
+
+        if layout.size() > 0 {
+            self.dealloc_recorded(slice.as_ptr() as *mut u8, layout);
         }
     }
 
-    unsafe fn deallocate(
+    /// Drop and Release Slice
+    ///
+    /// Drop all elements of a slice converted via `assume_init_slice()`,
+    /// then release its memory.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same requirements as for `free_uninit_slice()` apply. Furthermore,
+    /// all elements must be initialized.
+    pub unsafe fn free_slice<T>(&self, slice: core::ptr::NonNull<[T]>) {
+        core::ptr::drop_in_place(slice.as_ptr());
+        self.free_uninit_slice(core::ptr::NonNull::new_unchecked(
+            slice.as_ptr() as *mut [core::mem::MaybeUninit<T>],
+        ));
+    }
+
+    // Fill a memory block via `SetMem()` of the boot-services. This avoids
+    // relying on a `memset()` implementation, which might not be linked in
+    // early-boot environments.
+    unsafe fn set_bytes(&self, ptr: *mut u8, len: usize, value: u8) {
+        ((*(*self.system_table).boot_services).set_mem)(
+            ptr as *mut core::ffi::c_void,
+            len,
+            value,
+        );
+    }
+
+    // Clear a memory block via `SetMem()` of the boot-services.
+    #[cfg(feature = "allocator_api")]
+    unsafe fn set_zero(&self, ptr: *mut u8, len: usize) {
+        self.set_bytes(ptr, len, 0);
+    }
+
+    // Resize a block to a new layout in place, if it has room for it. The
+    // pool accounting, statistics and observer see a release of the block
+    // with the old layout plus an allocation with the new one. This fails,
+    // leaving the block untouched, if the block cannot be reused or the
+    // resize exceeds the pool cap.
+    pub(crate) unsafe fn resize_in_place(
         &self,
-        ptr: core::ptr::NonNull<u8>,
+        ptr: *mut u8,
+        old: core::alloc::Layout,
+        new: core::alloc::Layout,
+    ) -> bool {
+        if !crate::raw::fits_in_place(ptr, old, new) {
+            return false;
+        }
+
+        // Move the block to its new size in the pool accounting first, so a
+        // resize beyond the cap fails like a new allocation would.
+        if new.size() < old.size() {
+            self.pool_release(old.size() - new.size());
+        } else if !self.pool_charge(new.size() - old.size()) {
+            self.last_error.store(
+                efi::Status::OUT_OF_RESOURCES.as_usize(),
+                atomic::Ordering::Relaxed,
+            );
+            return false;
+        }
+
+        self.record_resize(ptr, old, new);
+        true
+    }
+
+    // Record the resize of a block in place. The statistics and observer see
+    // it as release plus allocation, so they see the same block with the new
+    // layout.
+    fn record_resize(
+        &self,
+        ptr: *mut u8,
+        old: core::alloc::Layout,
+        new: core::alloc::Layout,
+    ) {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.record_dealloc(old.size());
+            self.stats.record_alloc(new.size());
+        }
+        if let Some(observer) = self.observer {
+            observer.on_dealloc(ptr, old);
+            observer.on_alloc(ptr, new);
+        }
+    }
+
+    // Return a page allocator for the system-table and memory type of this
+    // allocator.
+    fn page_allocator(&self) -> PageAllocator {
+        // The system-table was validated when `self` was created.
+        let (st, memtype) = (self.system_table, self.memory_type);
+        unsafe { PageAllocator::from_system_table(st, memtype) }
+    }
+
+    // Serve an allocation from pages rather than the pool, with the same
+    // checks and records as `alloc_recorded()`. The block counts against the
+    // pool cap like any other block. This is used by wrappers that move
+    // large blocks to pages (see `PromotingAllocator`).
+    pub(crate) unsafe fn alloc_pages_recorded(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> *mut u8 {
+        let r = if !self.on_bsp() {
+            Err(efi::Status::ACCESS_DENIED)
+        } else if !self.pool_charge(layout.size()) {
+            Err(efi::Status::OUT_OF_RESOURCES)
+        } else {
+            let ptr = self.page_allocator().alloc(layout);
+            if ptr.is_null() {
+                self.pool_release(layout.size());
+                Err(efi::Status::OUT_OF_RESOURCES)
+            } else {
+                Ok(ptr)
+            }
+        };
+
+        self.record_alloc_result(layout, r)
+    }
+
+    // Release a block of `alloc_pages_recorded()`, with the same checks and
+    // records as `dealloc_recorded()`.
+    pub(crate) unsafe fn dealloc_pages_recorded(
+        &self,
+        ptr: *mut u8,
         layout: core::alloc::Layout,
     ) {
-        if layout.size() != 0 {
-            crate::raw::dealloc(self.system_table, ptr.as_ptr(), layout)
+        self.record_dealloc(ptr, layout);
+        self.page_allocator().dealloc(ptr, layout)
+    }
+
+    // Resize a block of `alloc_pages_recorded()` in place, by releasing the
+    // pages behind it or by allocating the pages right behind it. The resize
+    // is recorded like with `resize_in_place()`. This fails, leaving the
+    // block untouched, if the block is not suitably aligned for the new
+    // layout, the pages behind it are not available, or the resize exceeds
+    // the pool cap.
+    pub(crate) unsafe fn resize_pages_in_place(
+        &self,
+        ptr: *mut u8,
+        old: core::alloc::Layout,
+        new: core::alloc::Layout,
+    ) -> bool {
+        let bs = (*self.system_table).boot_services;
+        let (old_pages, new_pages) =
+            (PageAllocator::page_count(old), PageAllocator::page_count(new));
+        let end = ptr as usize + new_pages.min(old_pages) * PAGE_SIZE;
+
+        if ptr as usize & (new.align() - 1) != 0 {
+            return false;
         }
+
+        let r = if new.size() < old.size() {
+            self.pool_release(old.size() - new.size());
+            Ok(())
+        } else if !self.pool_charge(new.size() - old.size()) {
+            Err(efi::Status::OUT_OF_RESOURCES)
+        } else if new_pages > old_pages {
+            let r = crate::raw::alloc_pages(
+                bs,
+                AllocateType::Address(end as efi::PhysicalAddress),
+                self.memory_type,
+                new_pages - old_pages,
+            );
+            if r.is_err() {
+                self.pool_release(new.size() - old.size());
+            }
+            r.map(|_| ())
+        } else {
+            Ok(())
+        };
+
+        if let Err(status) = r {
+            self.last_error
+                .store(status.as_usize(), atomic::Ordering::Relaxed);
+            return false;
+        }
+
+        if new_pages < old_pages {
+            crate::raw::free_pages(
+                bs,
+                crate::raw::Pages {
+                    address: end as efi::PhysicalAddress,
+                    pages: old_pages - new_pages,
+                },
+            );
+        }
+
+        self.record_resize(ptr, old, new);
+        true
+    }
+
+    // Move a block to a new layout. If the block has room for the new
+    // layout, it is reused in place. Otherwise, a new block is allocated, the
+    // content is copied over and the old block is released. If `zeroed` is
+    // set, any bytes beyond the old size are cleared. On failure, the old
+    // block is left untouched and NULL is returned.
+    #[cfg(feature = "allocator_api")]
+    unsafe fn realloc_recorded(
+        &self,
+        ptr: *mut u8,
+        old: core::alloc::Layout,
+        new: core::alloc::Layout,
+        zeroed: bool,
+    ) -> *mut u8 {
+        let target = if self.resize_in_place(ptr, old, new) {
+            ptr
+        } else if crate::raw::fits_in_place(ptr, old, new) {
+            // The block has room, but the pool cap is exhausted.
+            return core::ptr::null_mut();
+        } else {
+            let target = self.alloc_recorded(new);
+            if target.is_null() {
+                return target;
+            }
+
+            let n = core::cmp::min(old.size(), new.size());
+            core::ptr::copy_nonoverlapping(ptr, target, n);
+            self.dealloc_recorded(ptr, old);
+            target
+        };
+
+        if zeroed && new.size() > old.size() {
+            self.set_zero(target.add(old.size()), new.size() - old.size());
+        }
+
+        target
+    }
+
+    // Forward a deallocation request to the raw allocator. Any attached
+    // observer is notified before the block is released.
+    unsafe fn dealloc_recorded(
+        &self,
+        ptr: *mut u8,
+        layout: core::alloc::Layout,
+    ) {
+        self.record_dealloc(ptr, layout);
+        crate::raw::dealloc(self.system_table, ptr, layout)
+    }
+
+    // Record the release of a block in the statistics, the observer and the
+    // pool cap, and apply the strategy to it. The caller releases the block
+    // afterwards.
+    unsafe fn record_dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        assert!(
+            self.on_bsp(),
+            "memory deallocation attempted on an application processor",
+        );
+
+        #[cfg(feature = "stats")]
+        self.stats.record_dealloc(layout.size());
+        if let Some(observer) = self.observer {
+            observer.on_dealloc(ptr, layout);
+        }
+        if self.strategy().contains(Strategy::POISON) {
+            self.set_bytes(ptr, layout.size(), POISON_BYTE);
+        }
+
+        self.pool_release(layout.size());
+    }
+}
+
+// An allocator is cloned via `with_memory_type()`, retaining the recorded
+// error. See the type documentation for the semantics.
+impl Clone for Allocator {
+    fn clone(&self) -> Allocator {
+        let allocator = self.with_memory_type(self.memory_type);
+
+        allocator.last_error.store(
+            self.last_error.load(atomic::Ordering::Relaxed),
+            atomic::Ordering::Relaxed,
+        );
+        allocator
+    }
+}
+
+pub(crate) use crate::raw::PAGE_SIZE;
+
+/// Highest Address of Low Memory
+///
+/// This is the highest physical address below 4GiB. Memory up to and
+/// including this address is reachable by 32-bit payloads and by devices
+/// limited to 32-bit DMA.
+pub const LOW_MEMORY_LIMIT: efi::PhysicalAddress = 0xffff_ffffu64;
+
+/// Validated Memory Type
+///
+/// This wraps an `efi::MemoryType` that is valid for allocations via
+/// `AllocatePool()` and `AllocatePages()`. Presets are provided for the
+/// memory types commonly allocated by UEFI images. Other values are
+/// validated via `MemType::new()`, so nonsense values are caught early,
+/// rather than failing at runtime in the firmware.
+///
+/// Use `efi::MemoryType::from()` (or `into()`) to pass the memory type to
+/// the allocators of this crate.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemType(efi::MemoryType);
+
+impl MemType {
+    /// Code of UEFI applications and OS loaders.
+    pub const LOADER_CODE: MemType = MemType(efi::LOADER_CODE);
+    /// Data of UEFI applications and OS loaders.
+    pub const LOADER_DATA: MemType = MemType(efi::LOADER_DATA);
+    /// Code of boot-service drivers.
+    pub const BOOT_SERVICES_CODE: MemType = MemType(efi::BOOT_SERVICES_CODE);
+    /// Data of boot-service drivers.
+    pub const BOOT_SERVICES_DATA: MemType = MemType(efi::BOOT_SERVICES_DATA);
+    /// Code of runtime drivers, preserved for the operating system.
+    pub const RUNTIME_SERVICES_CODE: MemType =
+        MemType(efi::RUNTIME_SERVICES_CODE);
+    /// Data of runtime drivers, preserved for the operating system.
+    pub const RUNTIME_SERVICES_DATA: MemType =
+        MemType(efi::RUNTIME_SERVICES_DATA);
+    /// ACPI tables, reclaimable by the operating system once parsed.
+    pub const ACPI_RECLAIM: MemType = MemType(efi::ACPI_RECLAIM_MEMORY);
+    /// ACPI non-volatile storage, preserved for the firmware.
+    pub const ACPI_NVS: MemType = MemType(efi::ACPI_MEMORY_NVS);
+
+    /// Validate Memory Type
+    ///
+    /// Return the memory type given as `memtype`, if it is valid for
+    /// allocations. This rejects the memory types that describe free or
+    /// unusable memory, or memory that is not backed by RAM (conventional,
+    /// unusable, memory-mapped I/O and persistent memory), as well as the
+    /// range reserved by the specification. OEM and operating-system defined
+    /// memory types (`0x70000000` and above) are accepted.
+    ///
+    /// `INVALID_PARAMETER` is returned for invalid memory types, matching
+    /// the status code of the firmware.
+    pub fn new(memtype: efi::MemoryType) -> Result<MemType, efi::Status> {
+        match memtype {
+            efi::CONVENTIONAL_MEMORY
+            | efi::UNUSABLE_MEMORY
+            | efi::MEMORY_MAPPED_IO
+            | efi::MEMORY_MAPPED_IO_PORT_SPACE
+            | efi::PERSISTENT_MEMORY => Err(efi::Status::INVALID_PARAMETER),
+            v if v > efi::PERSISTENT_MEMORY && v < 0x7000_0000 => {
+                Err(efi::Status::INVALID_PARAMETER)
+            }
+            v => Ok(MemType(v)),
+        }
+    }
+}
+
+impl From<MemType> for efi::MemoryType {
+    fn from(memtype: MemType) -> efi::MemoryType {
+        memtype.0
+    }
+}
+
+pub use crate::raw::AllocateType;
+
+/// Page Allocator
+///
+/// This is the equivalent of `Allocator`, but it forwards all memory
+/// requests to the `AllocatePages()` UEFI system, rather than the pool
+/// allocator. Every allocation is thus backed by an integral number of pages
+/// and is at least page-aligned. This is useful for memory that is shared
+/// with hardware or the firmware (e.g., DMA buffers or page tables).
+///
+/// Alignments beyond the page size are supported by over-allocating and
+/// releasing the excess pages again right away.
+pub struct PageAllocator {
+    system_table: *mut efi::SystemTable,
+    memory_type: efi::MemoryType,
+    allocate_type: AllocateType,
+}
+
+impl PageAllocator {
+    /// Create Page Allocator from UEFI System-Table
+    ///
+    /// This creates a new page allocator object from a UEFI System-Table
+    /// pointer and the memory-type to use for allocations. Pages are
+    /// allocated at any available address, unless `with_allocate_type()` is
+    /// used to restrict them.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::from_system_table()`
+    /// apply.
+    pub unsafe fn from_system_table(
+        st: *mut efi::SystemTable,
+        memtype: efi::MemoryType,
+    ) -> PageAllocator {
+        PageAllocator {
+            system_table: st,
+            memory_type: memtype,
+            allocate_type: AllocateType::AnyPages,
+        }
+    }
+
+    /// Select Page Allocation Type
+    ///
+    /// Change the allocation type used for all further allocations of this
+    /// allocator to `allocate_type`.
+    ///
+    /// Note that with `AllocateType::Address`, every allocation requests the
+    /// same address. Hence, only a single allocation can be live at a time,
+    /// and it fails if the address does not satisfy the requested alignment.
+    pub fn with_allocate_type(
+        mut self,
+        allocate_type: AllocateType,
+    ) -> PageAllocator {
+        self.allocate_type = allocate_type;
+        self
+    }
+
+    /// Create Page Allocator for Low Memory
+    ///
+    /// This creates a new page allocator like `from_system_table()`, but
+    /// restricts all allocations to memory below 4GiB (see
+    /// `LOW_MEMORY_LIMIT`). This is a shortcut for
+    /// `AllocateType::MaxAddress`, as needed for 32-bit payloads, ACPI tables
+    /// or devices limited to 32-bit DMA.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `from_system_table()` apply.
+    pub unsafe fn low_memory(
+        st: *mut efi::SystemTable,
+        memtype: efi::MemoryType,
+    ) -> PageAllocator {
+        Self::from_system_table(st, memtype)
+            .with_allocate_type(AllocateType::MaxAddress(LOW_MEMORY_LIMIT))
+    }
+
+    /// Return the Number of Pages backing a Layout
+    ///
+    /// Return the number of pages that back an allocation of the given
+    /// layout on a page allocator. This is the size rounded up to the page
+    /// size.
+    pub fn page_count(layout: core::alloc::Layout) -> usize {
+        let size = layout.size();
+
+        size / PAGE_SIZE + usize::from(size & (PAGE_SIZE - 1) != 0)
+    }
+
+    // Forward a request to `AllocatePages()`, using the configured allocation
+    // type. This returns the start address of the allocation, or `None` if
+    // the request failed.
+    unsafe fn allocate_pages(&self, pages: usize) -> Option<usize> {
+        match crate::raw::alloc_pages(
+            (*self.system_table).boot_services,
+            self.allocate_type,
+            self.memory_type,
+            pages,
+        ) {
+            Ok(v) => Some(v.address as usize),
+            Err(_) => None,
+        }
+    }
+
+    // Release pages via `FreePages()`.
+    unsafe fn free_pages(&self, addr: usize, pages: usize) {
+        crate::raw::free_pages(
+            (*self.system_table).boot_services,
+            crate::raw::Pages {
+                address: addr as efi::PhysicalAddress,
+                pages,
+            },
+        );
+    }
+
+    /// Allocate Memory from UEFI Boot-Services
+    ///
+    /// Use the UEFI `allocate_pages` boot-services to request a block of
+    /// memory satisfying the given memory layout. The memory type and
+    /// allocation type tied to this allocator object are used. The block is
+    /// backed by `page_count()` pages.
+    ///
+    /// This returns a null-pointer if the allocator could not serve the
+    /// request. Otherwise, a non-null pointer to the block is returned.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// To ensure safety of this interface, the caller must guarantee:
+    ///
+    ///  * The allocation size must not be 0. The function will panic
+    ///    otherwise.
+    ///
+    ///  * It must be safe for this function to call `allocate_pages` and
+    ///    `free_pages` of the boot-services provided via the system-table.
+    pub unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        assert!(layout.size() > 0);
+
+        let pages = Self::page_count(layout);
+        let align = layout.align();
+
+        if align <= PAGE_SIZE {
+            return match self.allocate_pages(pages) {
+                Some(addr) => addr as *mut u8,
+                None => core::ptr::null_mut(),
+            };
+        }
+
+        // A fixed address cannot be moved to satisfy the alignment, so
+        // either it is suitably aligned, or the request fails.
+        if let AllocateType::Address(v) = self.allocate_type {
+            if v as usize & (align - 1) != 0 {
+                return core::ptr::null_mut();
+            }
+            return match self.allocate_pages(pages) {
+                Some(addr) => addr as *mut u8,
+                None => core::ptr::null_mut(),
+            };
+        }
+
+        // Over-allocate by the alignment, so the block can be aligned within
+        // the allocation. Then release the excess pages in front and behind
+        // the aligned block, so only `pages` pages remain allocated.
+        let total = match pages.checked_add(align / PAGE_SIZE - 1) {
+            Some(v) => v,
+            None => return core::ptr::null_mut(),
+        };
+        let addr = match self.allocate_pages(total) {
+            Some(v) => v,
+            None => return core::ptr::null_mut(),
+        };
+
+        let aligned = (addr + align - 1) & !(align - 1);
+        let head = (aligned - addr) / PAGE_SIZE;
+        let tail = total - pages - head;
+
+        if head > 0 {
+            self.free_pages(addr, head);
+        }
+        if tail > 0 {
+            self.free_pages(aligned + pages * PAGE_SIZE, tail);
+        }
+
+        aligned as *mut u8
+    }
+
+    /// Deallocate Memory from UEFI Boot-Services
+    ///
+    /// Use the UEFI `free_pages` boot-services to release a block of memory
+    /// previously allocated through `alloc()`.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// To ensure safety of this interface, the caller must guarantee:
+    ///
+    ///  * The memory block must be the same as previously returned by a call
+    ///    to `alloc()`. Every memory block must be released exactly once.
+    ///
+    ///  * The passed layout must match the layout used to allocate the memory
+    ///    block.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        assert!(!ptr.is_null());
+
+        self.free_pages(ptr as usize, Self::page_count(layout));
+    }
+}
+
+/// Runtime-Services Allocator
+///
+/// This is a pool allocator for UEFI runtime drivers. It allocates memory of
+/// type `RUNTIME_SERVICES_DATA`, which stays reserved after the operating
+/// system took over. Unlike `Allocator`, it keeps working across
+/// `ExitBootServices()` and `SetVirtualAddressMap()`, within the limits of
+/// what UEFI permits:
+///
+///  * Once the boot-services were exited (see `set_exited()`), allocations
+///    fail and deallocations are ignored, since the pool allocator is no
+///    longer available. Blocks allocated before stay valid, since the
+///    operating system must retain runtime-services data.
+///
+///  * When the virtual address map is set, the stored system-table pointer
+///    must be converted via `convert_pointers()`, so `system_table()`
+///    yields a pointer usable in the virtual address space.
+///
+/// Both transitions can be hooked automatically via `watch_events()`.
+pub struct RuntimeAllocator {
+    system_table: atomic::AtomicPtr<efi::SystemTable>,
+    exited: atomic::AtomicBool,
+}
+
+impl RuntimeAllocator {
+    /// Create Runtime Allocator from UEFI System-Table
+    ///
+    /// This creates a new runtime allocator object from a UEFI System-Table
+    /// pointer. All allocations use `RUNTIME_SERVICES_DATA`.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::from_system_table()`
+    /// apply, until the boot-services are exited.
+    pub unsafe fn from_system_table(
+        st: *mut efi::SystemTable,
+    ) -> RuntimeAllocator {
+        RuntimeAllocator {
+            system_table: atomic::AtomicPtr::new(st),
+            exited: atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Return the System-Table
+    ///
+    /// Return the system-table of this allocator. After `convert_pointers()`
+    /// was called, this is the virtual address of the system-table.
+    pub fn system_table(&self) -> *mut efi::SystemTable {
+        self.system_table.load(atomic::Ordering::Acquire)
+    }
+
+    /// Mark boot-services as exited
+    ///
+    /// Mark this allocator as exited. From then on, allocations fail and
+    /// deallocations are ignored, since the boot-services are no longer
+    /// available. This cannot be undone.
+    pub fn set_exited(&self) {
+        self.exited.store(true, atomic::Ordering::Release);
+    }
+
+    /// Check whether the allocator was marked as exited
+    pub fn is_exited(&self) -> bool {
+        self.exited.load(atomic::Ordering::Acquire)
+    }
+
+    /// Convert Pointers to Virtual Addresses
+    ///
+    /// Convert the stored system-table pointer to its virtual address via
+    /// `ConvertPointer()` of the runtime-services. This must be called from
+    /// within an `EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE` notification, which is
+    /// the only time `ConvertPointer()` can be used. It implies
+    /// `set_exited()`.
+    ///
+    /// Blocks allocated from this allocator must be converted by their
+    /// owners, since the allocator does not track them.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The caller must guarantee this is called during the virtual address
+    /// change, with the system-table still at its physical address.
+    pub unsafe fn convert_pointers(&self) -> Result<(), efi::Status> {
+        self.set_exited();
+
+        let st = self.system_table();
+        let mut ptr = st as *mut core::ffi::c_void;
+        let r = ((*(*st).runtime_services).convert_pointer)(0, &mut ptr);
+        if r.is_error() {
+            return Err(r);
+        }
+
+        self.system_table
+            .store(ptr as *mut efi::SystemTable, atomic::Ordering::Release);
+        Ok(())
+    }
+
+    /// Hook Runtime Transitions
+    ///
+    /// Create events for `ExitBootServices()` and `SetVirtualAddressMap()`,
+    /// which invoke `set_exited()` and `convert_pointers()` on this
+    /// allocator, respectively. This returns both events, in this order.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The caller must guarantee that it is safe to call `CreateEvent()` of
+    /// the boot-services of the system-table of this allocator.
+    pub unsafe fn watch_events(
+        &'static self,
+    ) -> Result<(efi::Event, efi::Event), efi::Status> {
+        let bs = (*self.system_table()).boot_services;
+        let context = self as *const RuntimeAllocator as *mut core::ffi::c_void;
+
+        let mut exit: efi::Event = core::ptr::null_mut();
+        let r = ((*bs).create_event)(
+            efi::EVT_SIGNAL_EXIT_BOOT_SERVICES,
+            efi::TPL_NOTIFY,
+            Some(runtime_exit_notify),
+            context,
+            &mut exit,
+        );
+        if r.is_error() {
+            return Err(r);
+        }
+
+        let mut virt: efi::Event = core::ptr::null_mut();
+        let r = ((*bs).create_event)(
+            efi::EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE,
+            efi::TPL_NOTIFY,
+            Some(runtime_virtual_notify),
+            context,
+            &mut virt,
+        );
+        if r.is_error() {
+            ((*bs).close_event)(exit);
+            return Err(r);
+        }
+
+        Ok((exit, virt))
+    }
+
+    /// Allocate Runtime-Services Memory
+    ///
+    /// Allocate a block of `RUNTIME_SERVICES_DATA` satisfying the given
+    /// layout, just like `Allocator::alloc()`. Once the boot-services were
+    /// exited, this always returns a null-pointer.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same requirements as for `Allocator::alloc()` apply.
+    pub unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if self.is_exited() {
+            return core::ptr::null_mut();
+        }
+
+        crate::raw::alloc(
+            self.system_table(),
+            layout,
+            efi::RUNTIME_SERVICES_DATA,
+        )
+    }
+
+    /// Deallocate Runtime-Services Memory
+    ///
+    /// Release a block previously allocated through `alloc()`. Once the
+    /// boot-services were exited, this is a no-op and the block stays
+    /// reserved.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same requirements as for `Allocator::dealloc()` apply. Blocks
+    /// must be released with their physical address.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        if self.is_exited() {
+            return;
+        }
+
+        crate::raw::dealloc(self.system_table(), ptr, layout)
+    }
+}
+
+extern "efiapi" fn runtime_exit_notify(
+    _event: efi::Event,
+    context: *mut core::ffi::c_void,
+) {
+    let allocator = unsafe { &*(context as *const RuntimeAllocator) };
+
+    allocator.set_exited();
+}
+
+extern "efiapi" fn runtime_virtual_notify(
+    _event: efi::Event,
+    context: *mut core::ffi::c_void,
+) {
+    let allocator = unsafe { &*(context as *const RuntimeAllocator) };
+
+    // There is no way to report failure from a notification function. The
+    // system-table pointer then remains physical, which is no worse than
+    // not converting it at all.
+    let _ = unsafe { allocator.convert_pointers() };
+}
+
+// Size of the redzones of `DebugAllocator`, and the byte patterns used to
+// fill the redzones and to poison released memory. The poison pattern is also
+// used by `Strategy::POISON`.
+#[cfg(feature = "debug")]
+pub(crate) const REDZONE_SIZE: usize = 16usize;
+#[cfg(feature = "debug")]
+const REDZONE_BYTE: u8 = 0xfdu8;
+const POISON_BYTE: u8 = 0xddu8;
+
+/// Debug Memory Allocator
+///
+/// This wraps an `Allocator` and places redzones in front of and behind
+/// every block it serves. The redzones are filled with a guard pattern,
+/// which is verified when the block is released. If the pattern was
+/// modified, the allocator panics with the address of the offending block.
+/// Furthermore, released blocks are poisoned before they are returned to
+/// the firmware, so stale accesses read garbage rather than the old data.
+///
+/// This is meant to track down heap corruption. It increases the memory
+/// footprint of every allocation, and the checks add considerable overhead.
+/// This type is only available with the `debug` feature.
+#[cfg(feature = "debug")]
+pub struct DebugAllocator {
+    allocator: Allocator,
+}
+
+#[cfg(feature = "debug")]
+impl DebugAllocator {
+    /// Create Debug Allocator
+    ///
+    /// Create a new debug allocator that forwards all requests to the
+    /// allocator given as `allocator`.
+    pub fn new(allocator: Allocator) -> DebugAllocator {
+        DebugAllocator { allocator }
+    }
+
+    /// Return the Underlying Allocator
+    pub fn into_inner(self) -> Allocator {
+        self.allocator
+    }
+
+    // Return the size of the front redzone for blocks of the given layout.
+    // This is padded to the alignment of the layout, so the block itself
+    // stays properly aligned. The back redzone always has `REDZONE_SIZE`
+    // bytes.
+    fn front(layout: core::alloc::Layout) -> usize {
+        core::cmp::max(REDZONE_SIZE, layout.align())
+    }
+
+    // Return the layout of the block including its redzones, or `None` if
+    // it overflows the address-space.
+    fn padded(layout: core::alloc::Layout) -> Option<core::alloc::Layout> {
+        let size = Self::front(layout)
+            .checked_add(layout.size())?
+            .checked_add(REDZONE_SIZE)?;
+
+        core::alloc::Layout::from_size_align(size, layout.align()).ok()
+    }
+
+    /// Allocate Memory with Redzones
+    ///
+    /// This behaves like `Allocator::alloc()`, but surrounds the block with
+    /// redzones.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::alloc()` apply.
+    pub unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let padded = match Self::padded(layout) {
+            Some(v) => v,
+            None => return core::ptr::null_mut(),
+        };
+
+        let base = self.allocator.alloc(padded);
+        if base.is_null() {
+            return base;
+        }
+
+        let front = Self::front(layout);
+        let ptr = base.add(front);
+        core::ptr::write_bytes(base, REDZONE_BYTE, front);
+        core::ptr::write_bytes(
+            ptr.add(layout.size()),
+            REDZONE_BYTE,
+            REDZONE_SIZE,
+        );
+        ptr
+    }
+
+    /// Deallocate Memory with Redzones
+    ///
+    /// This behaves like `Allocator::dealloc()`, but verifies the redzones
+    /// of the block before releasing it. It panics if either redzone was
+    /// modified. The block is poisoned before it is released.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::dealloc()` apply.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        // The layout was already verified by `alloc()`.
+        let padded = Self::padded(layout).unwrap();
+        let front = Self::front(layout);
+        let base = ptr.sub(front);
+
+        let head = core::slice::from_raw_parts(base, front);
+        assert!(
+            head.iter().all(|v| *v == REDZONE_BYTE),
+            "memory corruption in front of block {:p}",
+            ptr,
+        );
+
+        let tail = core::slice::from_raw_parts(
+            ptr.add(layout.size()),
+            REDZONE_SIZE,
+        );
+        assert!(
+            tail.iter().all(|v| *v == REDZONE_BYTE),
+            "memory corruption behind block {:p}",
+            ptr,
+        );
+
+        core::ptr::write_bytes(base, POISON_BYTE, padded.size());
+        self.allocator.dealloc(base, padded);
+    }
+}
+
+// Initial number of entries of the side table of `TrackingAllocator`.
+const TRACKING_MIN_CAPACITY: usize = 16usize;
+
+/// Record of a Live Allocation
+///
+/// This describes a memory block served by a `TrackingAllocator`, which has
+/// not been released, yet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AllocationRecord {
+    /// Identifier of the allocation (see `TrackingAllocator::id_of()`).
+    pub id: u64,
+    /// Address of the memory block.
+    pub ptr: *mut u8,
+    /// Layout the memory block was allocated with.
+    pub layout: core::alloc::Layout,
+}
+
+/// Allocation Checkpoint
+///
+/// This marks a point in the allocation history of a `TrackingAllocator`.
+/// See `TrackingAllocator::checkpoint()` for details.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Checkpoint {
+    id: u64,
+}
+
+/// Tracking Memory Allocator
+///
+/// This wraps an `Allocator` and records every memory block it serves,
+/// until the block is released again. The records are kept in a side table,
+/// which is allocated from the wrapped allocator as well. This allows
+/// verifying that all memory was released at a given point, for instance
+/// before calling `ExitBootServices()` with a memory map that must not
+/// contain any boot-services data of the caller.
+///
+/// Every allocation is assigned an identifier, which increases
+/// monotonically and is never reused by the same allocator. It is part of
+/// all records and leak reports, so memory seen in a firmware debugger or
+/// DMA trace can be correlated with a specific allocation.
+///
+/// Furthermore, checkpoints allow releasing all blocks allocated after a
+/// given point at once, for instance when an optional boot step fails.
+///
+/// Releasing a block that was not served by this allocator panics. If the
+/// side table cannot be grown, the allocation fails.
+pub struct TrackingAllocator {
+    allocator: Allocator,
+    records: core::cell::Cell<*mut AllocationRecord>,
+    len: core::cell::Cell<usize>,
+    capacity: core::cell::Cell<usize>,
+    next_id: core::cell::Cell<u64>,
+}
+
+impl TrackingAllocator {
+    /// Create Tracking Allocator
+    ///
+    /// Create a new tracking allocator that forwards all requests to the
+    /// allocator given as `allocator`. The side table is allocated lazily.
+    pub fn new(allocator: Allocator) -> TrackingAllocator {
+        TrackingAllocator {
+            allocator,
+            records: core::cell::Cell::new(core::ptr::null_mut()),
+            len: core::cell::Cell::new(0),
+            capacity: core::cell::Cell::new(0),
+            next_id: core::cell::Cell::new(0),
+        }
+    }
+
+    // Make room for one more record in the side table. The table is grown
+    // exponentially, and the old table is released once the records were
+    // copied over. Returns `false` if the new table cannot be allocated.
+    unsafe fn reserve(&self) -> bool {
+        let len = self.len.get();
+        let capacity = self.capacity.get();
+
+        if len < capacity {
+            return true;
+        }
+
+        let new_capacity = core::cmp::max(
+            TRACKING_MIN_CAPACITY,
+            capacity.saturating_mul(2),
+        );
+        let layout =
+            match core::alloc::Layout::array::<AllocationRecord>(new_capacity) {
+                Ok(v) => v,
+                Err(_) => return false,
+            };
+        let records = self.allocator.alloc(layout) as *mut AllocationRecord;
+        if records.is_null() {
+            return false;
+        }
+
+        if capacity > 0 {
+            core::ptr::copy_nonoverlapping(self.records.get(), records, len);
+            self.release_table();
+        }
+
+        self.records.set(records);
+        self.capacity.set(new_capacity);
+        true
+    }
+
+    // Release the side table to the wrapped allocator.
+    unsafe fn release_table(&self) {
+        let capacity = self.capacity.get();
+
+        if capacity > 0 {
+            let layout =
+                core::alloc::Layout::array::<AllocationRecord>(capacity)
+                    .unwrap();
+            self.allocator.dealloc(self.records.get() as *mut u8, layout);
+            self.records.set(core::ptr::null_mut());
+            self.capacity.set(0);
+        }
+    }
+
+    /// Allocate and Record Memory
+    ///
+    /// This behaves like `Allocator::alloc()`, but records the block in the
+    /// side table.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::alloc()` apply.
+    pub unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if !self.reserve() {
+            return core::ptr::null_mut();
+        }
+
+        let ptr = self.allocator.alloc(layout);
+        if !ptr.is_null() {
+            let len = self.len.get();
+            let id = self.next_id.get();
+            self.records
+                .get()
+                .add(len)
+                .write(AllocationRecord { id, ptr, layout });
+            self.len.set(len + 1);
+            self.next_id.set(id + 1);
+        }
+
+        ptr
+    }
+
+    /// Deallocate Recorded Memory
+    ///
+    /// This behaves like `Allocator::dealloc()`, but removes the block from
+    /// the side table. It panics if the block was not allocated through this
+    /// allocator.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::dealloc()` apply.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        let len = self.len.get();
+        let records = self.records.get();
+
+        let idx = (0..len).find(|i| (*records.add(*i)).ptr == ptr);
+        let idx = match idx {
+            Some(v) => v,
+            None => panic!("untracked memory block {:p} released", ptr),
+        };
+
+        // Move the last record into the free slot to keep the table dense.
+        *records.add(idx) = *records.add(len - 1);
+        self.len.set(len - 1);
+
+        self.allocator.dealloc(ptr, layout);
+    }
+
+    /// Iterate Live Allocations
+    ///
+    /// Return an iterator over the records of all memory blocks that were
+    /// allocated but not released, yet. The order is unspecified.
+    pub fn leaks(&self) -> impl Iterator<Item = AllocationRecord> + '_ {
+        // Look up the table on every step, since it might be reallocated by
+        // allocations performed while iterating.
+        (0..self.len.get()).filter_map(move |i| {
+            if i < self.len.get() {
+                Some(unsafe { *self.records.get().add(i) })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Look Up Allocation Identifier
+    ///
+    /// Return the identifier of the live allocation that contains the
+    /// address `ptr`, or `None` if no live block of this allocator contains
+    /// it. This maps addresses seen outside of rust (e.g., in a firmware
+    /// debugger) back to the allocation that served them.
+    pub fn id_of(&self, ptr: *const u8) -> Option<u64> {
+        let addr = ptr as usize;
+
+        self.leaks()
+            .find(|v| {
+                let start = v.ptr as usize;
+                addr >= start && addr - start < v.layout.size()
+            })
+            .map(|v| v.id)
+    }
+
+    /// Create Allocation Checkpoint
+    ///
+    /// Return a checkpoint that marks the current point in the allocation
+    /// history of this allocator. A later call to `rollback()` releases all
+    /// blocks that were allocated after the checkpoint and are still live.
+    /// Blocks allocated before the checkpoint are not affected.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            id: self.next_id.get(),
+        }
+    }
+
+    /// Roll Back to Checkpoint
+    ///
+    /// Release all blocks that were allocated after `checkpoint` was created
+    /// and were not released, yet. This allows a failed operation to clean
+    /// up everything it allocated, without tracking ownership of each block.
+    /// The checkpoint stays valid and can be rolled back to again.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The caller must guarantee that none of the released blocks is used
+    /// afterwards. Any objects stored in them are not dropped.
+    pub unsafe fn rollback(&self, checkpoint: Checkpoint) {
+        // Walk backwards, since `dealloc()` moves the last record into the
+        // released slot, which was then already visited.
+        for i in (0..self.len.get()).rev() {
+            let record = *self.records.get().add(i);
+            if record.id >= checkpoint.id {
+                self.dealloc(record.ptr, record.layout);
+            }
+        }
+    }
+
+    /// Assert that all Memory was Released
+    ///
+    /// Panic if any memory block served by this allocator was not released,
+    /// yet. The panic message contains the number of live blocks and the
+    /// address of one of them.
+    pub fn assert_empty(&self) {
+        if let Some(record) = self.leaks().next() {
+            panic!(
+                "{} memory blocks leaked, including #{} at {:p} of {} bytes",
+                self.len.get(),
+                record.id,
+                record.ptr,
+                record.layout.size(),
+            );
+        }
+    }
+}
+
+impl Drop for TrackingAllocator {
+    fn drop(&mut self) {
+        unsafe { self.release_table() };
+    }
+}
+
+/// Low Memory Allocator
+///
+/// This wraps an `Allocator` and only serves blocks that are located below
+/// 4GiB (see `LOW_MEMORY_LIMIT`). The pool allocator of UEFI cannot be
+/// restricted to an address range, so every block is verified after it was
+/// allocated. Blocks that end above the limit are released again right
+/// away, and the request fails with `OUT_OF_RESOURCES` (see
+/// `Allocator::last_error()`).
+///
+/// Whether the pool allocator serves low memory depends on the firmware. If
+/// allocations must not fail, use `PageAllocator::low_memory()` instead,
+/// which lets the firmware pick suitable pages.
+pub struct LowMemoryAllocator {
+    allocator: Allocator,
+}
+
+impl LowMemoryAllocator {
+    /// Create Low Memory Allocator
+    ///
+    /// Create a new low memory allocator that forwards all requests to the
+    /// allocator given as `allocator`.
+    pub fn new(allocator: Allocator) -> LowMemoryAllocator {
+        LowMemoryAllocator { allocator }
+    }
+
+    /// Return the Underlying Allocator
+    pub fn into_inner(self) -> Allocator {
+        self.allocator
+    }
+
+    /// Allocate Memory below 4GiB
+    ///
+    /// This behaves like `Allocator::alloc()`, but fails if the block would
+    /// end above `LOW_MEMORY_LIMIT`. Such a block is released right away, and
+    /// the request is reported as a failure with `OUT_OF_RESOURCES`, as if
+    /// the firmware had no low memory left. Observers and statistics never
+    /// see the rejected block.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::alloc()` apply.
+    pub unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let a = &self.allocator;
+        let r = match a.alloc_unrecorded(layout, a.memory_type) {
+            Ok(ptr) => {
+                let end = (ptr as usize as u64)
+                    .saturating_add(layout.size() as u64);
+                if end - 1 > LOW_MEMORY_LIMIT {
+                    a.dealloc_unrecorded(ptr, layout);
+                    Err(efi::Status::OUT_OF_RESOURCES)
+                } else {
+                    Ok(ptr)
+                }
+            }
+            Err(status) => Err(status),
+        };
+
+        a.record_alloc_result(layout, r)
+    }
+
+    /// Deallocate Memory
+    ///
+    /// This behaves like `Allocator::dealloc()`.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::dealloc()` apply.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        self.allocator.dealloc(ptr, layout)
+    }
+}
+
+/// Default Promotion Threshold
+///
+/// This is the block size from which a `PromotingAllocator` serves blocks
+/// from the page allocator rather than the pool allocator, unless changed
+/// via `PromotingAllocator::with_threshold()`.
+pub const PROMOTE_THRESHOLD: usize = 64 * 1024;
+
+/// Promoting Memory Allocator
+///
+/// This wraps an `Allocator` and serves all blocks of at least the promotion
+/// threshold (see `PROMOTE_THRESHOLD`) from the page allocator of the same
+/// memory type, while smaller blocks are served from the pool allocator.
+///
+/// The pool allocator of UEFI cannot resize blocks, so growing a block
+/// copies it every time. For buffers that are grown repeatedly (e.g., a
+/// vector collecting a file), this results in many copies of megabytes of
+/// data. Once such a block crosses the threshold, it is promoted to pages
+/// (copying it once more). From then on, growing it first tries to allocate
+/// the pages right behind it, and only falls back to copying if they are not
+/// available. Shrinking a page-backed block releases its excess pages in
+/// place, unless it drops below the threshold, in which case it is moved
+/// back to the pool.
+///
+/// Promotion is routed by size alone, not by how often a block was grown. A
+/// block is page-backed whenever its size is at or above the threshold,
+/// including blocks allocated at that size right away. Counting the grows of
+/// a block would need a header or a side table for every block, while the
+/// size is known to every call already.
+///
+/// The backend of a block is derived from its layout, so blocks must be
+/// resized and released with their exact current layout, as required by
+/// the allocator interfaces of rust anyway.
+///
+/// Page-backed blocks are served on behalf of the wrapped allocator. Its
+/// checks, strategy, pool cap, statistics and observer apply to them just
+/// like to blocks of the pool.
+pub struct PromotingAllocator {
+    allocator: Allocator,
+    threshold: usize,
+}
+
+impl PromotingAllocator {
+    /// Create Promoting Allocator
+    ///
+    /// Create a new promoting allocator that serves small blocks from the
+    /// allocator given as `allocator`, and large blocks from pages of its
+    /// memory type.
+    pub fn new(allocator: Allocator) -> PromotingAllocator {
+        PromotingAllocator {
+            allocator,
+            threshold: PROMOTE_THRESHOLD,
+        }
+    }
+
+    /// Change Promotion Threshold
+    ///
+    /// Serve all blocks of at least `threshold` bytes from pages. This must
+    /// not be changed while blocks of the allocator are live, since they
+    /// would be released to the wrong backend.
+    pub fn with_threshold(mut self, threshold: usize) -> PromotingAllocator {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Return the Underlying Allocator
+    pub fn into_inner(self) -> Allocator {
+        self.allocator
+    }
+
+    // Check whether blocks of the given layout are served from pages.
+    fn paged(&self, layout: core::alloc::Layout) -> bool {
+        layout.size() >= self.threshold
+    }
+
+    /// Allocate Memory
+    ///
+    /// This behaves like `Allocator::alloc()`, but serves large blocks from
+    /// pages.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::alloc()` apply.
+    pub unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if self.paged(layout) {
+            self.allocator.alloc_pages_recorded(layout)
+        } else {
+            self.allocator.alloc(layout)
+        }
+    }
+
+    /// Deallocate Memory
+    ///
+    /// This behaves like `Allocator::dealloc()`, releasing the block to the
+    /// backend that served it.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Allocator::dealloc()` apply.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        if self.paged(layout) {
+            self.allocator.dealloc_pages_recorded(ptr, layout)
+        } else {
+            self.allocator.dealloc(ptr, layout)
+        }
+    }
+
+    /// Reallocate Memory
+    ///
+    /// Resize the block at `ptr` from the layout `old` to the layout `new`,
+    /// moving it between pool and pages if it crosses the threshold. The
+    /// content is retained up to the smaller of both sizes. This returns the
+    /// new location of the block, or NULL if the request failed, in which
+    /// case the old block is left untouched.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The block must have been allocated via this allocator with the layout
+    /// `old`. Neither layout may be zero-sized. On success, the old pointer
+    /// must no longer be used, and the block must be released with `new`.
+    pub unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        old: core::alloc::Layout,
+        new: core::alloc::Layout,
+    ) -> *mut u8 {
+        let a = &self.allocator;
+        match (self.paged(old), self.paged(new)) {
+            (false, false) if a.resize_in_place(ptr, old, new) => {
+                return ptr;
+            }
+            (true, true) if a.resize_pages_in_place(ptr, old, new) => {
+                return ptr;
+            }
+            _ => {}
+        }
+
+        let target = self.alloc(new);
+        if !target.is_null() {
+            let n = core::cmp::min(old.size(), new.size());
+            core::ptr::copy_nonoverlapping(ptr, target, n);
+            self.dealloc(ptr, old);
+        }
+
+        target
+    }
+}
+
+/// Run Closure with Temporary Buffer
+///
+/// Provide a temporary buffer of `len` elements of type `T` to the closure
+/// `f` and return its result. If `len` does not exceed `N`, the buffer is
+/// placed on the stack and no allocation is performed. Only larger requests
+/// fall back to allocating the buffer from `allocator`, in which case it is
+/// released again once the closure returns.
+///
+/// This is meant for short-lived, transient buffers (e.g., string
+/// conversions), which are usually small. It avoids pool round-trips into
+/// the firmware for the common case, while still supporting arbitrarily
+/// large requests.
+///
+/// The buffer is passed uninitialized to the closure. This yields `None` if
+/// the fallback allocation fails, in which case the closure is not invoked.
+pub fn with_stack_fallback<T, R, const N: usize>(
+    allocator: &Allocator,
+    len: usize,
+    f: impl FnOnce(&mut [core::mem::MaybeUninit<T>]) -> R,
+) -> Option<R> {
+    if len <= N {
+        // An array of `MaybeUninit` does not require initialization, so it
+        // is safe to assume the outer `MaybeUninit` is initialized.
+        let mut buf: [core::mem::MaybeUninit<T>; N] = unsafe {
+            core::mem::MaybeUninit::uninit().assume_init()
+        };
+
+        return Some(f(&mut buf[..len]));
+    }
+
+    let layout = core::alloc::Layout::array::<T>(len).ok()?;
+    if layout.size() == 0 {
+        // Zero-sized types never need backing memory, so a dangling pointer
+        // serves any length.
+        let ptr = core::ptr::NonNull::<core::mem::MaybeUninit<T>>::dangling();
+        let buf = unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), len) };
+        return Some(f(buf));
+    }
+
+    let ptr = unsafe { allocator.alloc(layout) };
+    if ptr.is_null() {
+        return None;
+    }
+
+    let r = {
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(
+                ptr as *mut core::mem::MaybeUninit<T>,
+                len,
+            )
+        };
+        f(buf)
+    };
+
+    unsafe { allocator.dealloc(ptr, layout) };
+
+    Some(r)
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for Allocator {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let size = layout.size();
+
+        let ptr = if size > 0 {
+            unsafe { self.alloc_recorded(layout) }
+        } else {
+            layout.dangling().as_ptr() as *mut _
+        };
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    fn allocate_zeroed(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let ptr = self.allocate(layout)?;
+
+        // With `Strategy::ZERO`, the block was cleared by the allocation
+        // already.
+        if layout.size() > 0 && !self.strategy().contains(Strategy::ZERO) {
+            unsafe { self.set_zero(ptr.as_ptr() as *mut u8, layout.size()) };
+        }
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        layout: core::alloc::Layout,
+    ) {
+        if layout.size() != 0 {
+            self.dealloc_recorded(ptr.as_ptr(), layout)
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        self.resize(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl Allocator {
+    // Common backend of `grow()`, `grow_zeroed()` and `shrink()`. Zero-sized
+    // blocks are dangling, so they are never passed to `realloc_recorded()`,
+    // but served via `allocate()` and `deallocate()`.
+    unsafe fn resize(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+        zeroed: bool,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        use core::alloc::Allocator as _;
+
+        if old_layout.size() == 0 {
+            return if zeroed {
+                self.allocate_zeroed(new_layout)
+            } else {
+                self.allocate(new_layout)
+            };
+        }
+
+        if new_layout.size() == 0 {
+            self.deallocate(ptr, old_layout);
+            return self.allocate(new_layout);
+        }
+
+        let target =
+            self.realloc_recorded(ptr.as_ptr(), old_layout, new_layout, zeroed);
+
+        if target.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(target, new_layout.size())
+                        as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for RuntimeAllocator {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let size = layout.size();
+
+        let ptr = if size > 0 {
+            unsafe { RuntimeAllocator::alloc(self, layout) }
+        } else {
+            layout.dangling().as_ptr() as *mut _
+        };
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn deallocate(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        layout: core::alloc::Layout,
+    ) {
+        if layout.size() != 0 {
+            RuntimeAllocator::dealloc(self, ptr.as_ptr(), layout)
+        }
+    }
+}
+
+#[cfg(all(feature = "allocator_api", feature = "debug"))]
+unsafe impl core::alloc::Allocator for DebugAllocator {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let size = layout.size();
+
+        let ptr = if size > 0 {
+            unsafe { DebugAllocator::alloc(self, layout) }
+        } else {
+            layout.dangling().as_ptr() as *mut _
+        };
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn deallocate(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        layout: core::alloc::Layout,
+    ) {
+        if layout.size() != 0 {
+            DebugAllocator::dealloc(self, ptr.as_ptr(), layout)
+        }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for LowMemoryAllocator {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let size = layout.size();
+
+        let ptr = if size > 0 {
+            unsafe { LowMemoryAllocator::alloc(self, layout) }
+        } else {
+            layout.dangling().as_ptr() as *mut _
+        };
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn deallocate(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        layout: core::alloc::Layout,
+    ) {
+        if layout.size() != 0 {
+            LowMemoryAllocator::dealloc(self, ptr.as_ptr(), layout)
+        }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for PromotingAllocator {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let size = layout.size();
+
+        let ptr = if size > 0 {
+            unsafe { PromotingAllocator::alloc(self, layout) }
+        } else {
+            layout.dangling().as_ptr() as *mut _
+        };
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn deallocate(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        layout: core::alloc::Layout,
+    ) {
+        if layout.size() != 0 {
+            PromotingAllocator::dealloc(self, ptr.as_ptr(), layout)
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        if old_layout.size() == 0 {
+            return self.allocate(new_layout);
+        }
+
+        let ptr = self.realloc(ptr.as_ptr(), old_layout, new_layout);
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, new_layout.size())
+                        as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: core::alloc::Layout,
+        new_layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        if new_layout.size() == 0 {
+            self.deallocate(ptr, old_layout);
+            return self.allocate(new_layout);
+        }
+
+        self.grow(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for TrackingAllocator {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let size = layout.size();
+
+        let ptr = if size > 0 {
+            unsafe { TrackingAllocator::alloc(self, layout) }
+        } else {
+            layout.dangling().as_ptr() as *mut _
+        };
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn deallocate(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        layout: core::alloc::Layout,
+    ) {
+        if layout.size() != 0 {
+            TrackingAllocator::dealloc(self, ptr.as_ptr(), layout)
+        }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for PageAllocator {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        // Report the full page-granular size of the block, so callers can
+        // make use of the slack at the end of the last page.
+        let (ptr, size) = if layout.size() > 0 {
+            let ptr = unsafe { PageAllocator::alloc(self, layout) };
+            (ptr, PageAllocator::page_count(layout) * PAGE_SIZE)
+        } else {
+            (layout.dangling().as_ptr() as *mut _, 0)
+        };
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn deallocate(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        layout: core::alloc::Layout,
+    ) {
+        if layout.size() != 0 {
+            PageAllocator::dealloc(self, ptr.as_ptr(), layout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify that the page allocator serves page-granular blocks of all
+    // alignments, and releases all pages, including the excess pages of
+    // over-aligned requests.
+    #[test]
+    fn pages() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let pa = unsafe {
+            PageAllocator::from_system_table(st, efi::LOADER_DATA)
+        };
+
+        for (size, pages) in &[(1, 1), (4096, 1), (4097, 2), (3 * 4096, 3)] {
+            for align in &[1, 8, 4096, 8192, 65536] {
+                let layout = core::alloc::Layout::from_size_align(*size, *align)
+                    .unwrap();
+                let pages = *pages;
+                assert_eq!(PageAllocator::page_count(layout), pages);
+
+                unsafe {
+                    let ptr = pa.alloc(layout);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % (*align).max(4096), 0);
+                    assert_eq!(crate::mock::pages_live(), pages);
+
+                    core::ptr::write_bytes(ptr, 0xff, pages * 4096);
+                    pa.dealloc(ptr, layout);
+                    assert_eq!(crate::mock::pages_live(), 0);
+                }
+            }
+        }
+    }
+
+    // Allocate uninitialized slices, initialize them and release them
+    // again. Zero-sized slices must never reach the firmware.
+    #[test]
+    fn uninit_slice() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let allocator =
+            unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+
+        let slice = allocator.alloc_uninit_slice::<u32>(16).unwrap();
+        assert_eq!(slice.len(), 16);
+        assert_eq!(crate::mock::pool_live(), 1);
+        unsafe {
+            for (i, v) in (*slice.as_ptr()).iter_mut().enumerate() {
+                v.write(i as u32);
+            }
+            let slice = Allocator::assume_init_slice(slice);
+            assert_eq!((*slice.as_ptr()).iter().sum::<u32>(), 120);
+            allocator.free_slice(slice);
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        let slice = allocator.alloc_uninit_slice::<()>(16).unwrap();
+        assert_eq!(slice.len(), 16);
+        assert_eq!(crate::mock::pool_live(), 0);
+        unsafe { allocator.free_uninit_slice(slice) };
+
+        assert!(allocator.alloc_uninit_slice::<u64>(usize::MAX / 4).is_none());
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that low memory allocators never serve blocks above 4GiB. The
+    // addresses of the mock firmware depend on the host, so the requests
+    // might fail, but must never yield high memory.
+    #[test]
+    fn low_memory() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        let below = |ptr: *mut u8, size: usize| {
+            (ptr as usize as u64 + size as u64 - 1) <= LOW_MEMORY_LIMIT
+        };
+
+        unsafe {
+            let a = LowMemoryAllocator::new(Allocator::from_system_table(
+                st,
+                efi::LOADER_DATA,
+            ));
+            let ptr = a.alloc(layout);
+            if ptr.is_null() {
+                assert_eq!(
+                    a.allocator.last_error().map(|v| v.as_usize()),
+                    Some(efi::Status::OUT_OF_RESOURCES.as_usize()),
+                );
+            } else {
+                assert!(below(ptr, 64));
+                a.dealloc(ptr, layout);
+            }
+            assert_eq!(crate::mock::pool_live(), 0);
+
+            let pa = PageAllocator::low_memory(st, efi::LOADER_DATA);
+            let ptr = pa.alloc(layout);
+            if !ptr.is_null() {
+                assert!(below(ptr, 4096));
+                pa.dealloc(ptr, layout);
+            }
+            assert_eq!(crate::mock::pages_live(), 0);
+        }
+    }
+
+    // Verify that a low memory allocator rejects pool blocks above 4GiB as
+    // failure, without reporting the rejected block as allocated.
+    #[test]
+    fn low_memory_high() {
+        struct Events {
+            blocks: atomic::AtomicUsize,
+            failures: atomic::AtomicUsize,
+        }
+
+        impl crate::observe::AllocObserver for Events {
+            fn on_alloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+                self.blocks.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+
+            fn on_dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+                self.blocks.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+
+            fn on_failure(&self, _layout: core::alloc::Layout) {
+                self.failures.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        static EVENTS: Events = Events {
+            blocks: atomic::AtomicUsize::new(0),
+            failures: atomic::AtomicUsize::new(0),
+        };
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        let a = LowMemoryAllocator::new(
+            unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) }
+                .with_observer(&EVENTS),
+        );
+
+        crate::mock::pool_high(true);
+        assert!(unsafe { a.alloc(layout) }.is_null());
+        crate::mock::pool_high(false);
+
+        assert_eq!(
+            a.allocator.last_error(),
+            Some(efi::Status::OUT_OF_RESOURCES),
+        );
+        assert_eq!(EVENTS.blocks.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(EVENTS.failures.load(atomic::Ordering::Relaxed), 1);
+        #[cfg(feature = "stats")]
+        {
+            let stats = a.allocator.stats();
+            assert_eq!((stats.allocations, stats.deallocations), (0, 0));
+            assert_eq!(stats.failures, 1);
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Allocate blocks of other memory types from a single allocator, and
+    // via a derived allocator.
+    #[test]
+    fn memory_type() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let ptr = a.alloc_with_type(layout, efi::LOADER_CODE);
+            assert!(!ptr.is_null());
+            assert_eq!(crate::mock::pool_memory_type(), Some(efi::LOADER_CODE));
+            a.dealloc(ptr, layout);
+
+            let acpi = a.with_memory_type(efi::ACPI_RECLAIM_MEMORY);
+            let ptr = acpi.alloc(layout);
+            assert_eq!(
+                crate::mock::pool_memory_type(),
+                Some(efi::ACPI_RECLAIM_MEMORY),
+            );
+            acpi.dealloc(ptr, layout);
+
+            let ptr = a.alloc(layout);
+            assert_eq!(crate::mock::pool_memory_type(), Some(efi::LOADER_DATA));
+            a.dealloc(ptr, layout);
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that blocks are promoted to pages once they cross the
+    // threshold, shrink in place while page-backed, and move back to the
+    // pool below the threshold. Page-backed blocks count against the pool
+    // cap of the wrapped allocator like any other block.
+    #[test]
+    fn promote() {
+        static CAP: PoolCap = PoolCap::new(1 << 20);
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) }
+            .with_pool_cap(&CAP);
+        let a = PromotingAllocator::new(a).with_threshold(4 * PAGE_SIZE);
+        let layout = |size| {
+            core::alloc::Layout::from_size_align(size, 8).unwrap()
+        };
+
+        unsafe {
+            let p0 = a.alloc(layout(64));
+            core::ptr::write_bytes(p0, 0xaa, 64);
+            assert_eq!(crate::mock::pool_live(), 1);
+
+            let p1 = a.realloc(p0, layout(64), layout(8 * PAGE_SIZE));
+            assert!(!p1.is_null());
+            assert_eq!(*p1.add(63), 0xaa);
+            assert_eq!(crate::mock::pool_live(), 0);
+            assert_eq!(crate::mock::pages_live(), 8);
+            assert_eq!(CAP.used(), 8 * PAGE_SIZE);
+
+            let (l1, l2) = (layout(8 * PAGE_SIZE), layout(5 * PAGE_SIZE));
+            let p2 = a.realloc(p1, l1, l2);
+            assert_eq!(p2, p1);
+            assert_eq!(crate::mock::pages_live(), 5);
+            assert_eq!(CAP.used(), 5 * PAGE_SIZE);
+
+            let p3 = a.realloc(p2, l2, layout(128));
+            assert_eq!(*p3.add(63), 0xaa);
+            assert_eq!(crate::mock::pool_live(), 1);
+            assert_eq!(crate::mock::pages_live(), 0);
+            assert_eq!(CAP.used(), 128);
+
+            let p4 = a.realloc(p3, layout(128), layout(64));
+            assert_eq!(p4, p3);
+            assert_eq!(CAP.used(), 64);
+
+            a.dealloc(p4, layout(64));
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+        assert_eq!(CAP.used(), 0);
+        #[cfg(feature = "stats")]
+        assert_eq!(a.allocator.stats().bytes_in_use, 0);
+    }
+
+    // Verify that memory types are validated, and presets convert to their
+    // UEFI equivalents.
+    #[test]
+    fn mem_type() {
+        let memtype: efi::MemoryType = MemType::LOADER_DATA.into();
+        assert_eq!(memtype, efi::LOADER_DATA);
+        assert_eq!(MemType::new(efi::ACPI_MEMORY_NVS), Ok(MemType::ACPI_NVS));
+        assert!(MemType::new(efi::RESERVED_MEMORY_TYPE).is_ok());
+        assert!(MemType::new(0x8000_0001).is_ok());
+
+        for memtype in [
+            efi::CONVENTIONAL_MEMORY,
+            efi::MEMORY_MAPPED_IO,
+            efi::PERSISTENT_MEMORY,
+            0x10,
+            0x6fff_ffff,
+        ] {
+            assert_eq!(
+                MemType::new(memtype).map_err(|v| v.as_usize()),
+                Err(efi::Status::INVALID_PARAMETER.as_usize()),
+            );
+        }
+    }
+
+    // Verify that allocators created from an image handle use the data type
+    // of the image.
+    #[test]
+    fn image_handle() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        for memtype in [efi::LOADER_DATA, efi::BOOT_SERVICES_DATA] {
+            fw.set_image_data_type(memtype);
+            let h = fw.image_handle();
+            let a = unsafe { Allocator::from_image_handle(h, st) }.unwrap();
+
+            unsafe {
+                let ptr = a.alloc(layout);
+                assert_eq!(crate::mock::pool_memory_type(), Some(memtype));
+                a.dealloc(ptr, layout);
+            }
+        }
+
+        let r = unsafe {
+            Allocator::from_image_handle(core::ptr::null_mut(), st)
+        };
+        assert_eq!(
+            r.err().map(|v| v.as_usize()),
+            Some(efi::Status::UNSUPPORTED.as_usize()),
+        );
+    }
+
+    // Verify that clones serve allocations independently, and retain the
+    // configuration of the original.
+    #[test]
+    fn clone() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        crate::mock::pool_fail(Some(efi::Status::OUT_OF_RESOURCES));
+        assert!(unsafe { a.alloc(layout) }.is_null());
+        crate::mock::pool_fail(None);
+
+        let b = a.clone();
+        assert_eq!(
+            b.last_error().map(|v| v.as_usize()),
+            Some(efi::Status::OUT_OF_RESOURCES.as_usize()),
+        );
+
+        unsafe {
+            let ptr = b.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(crate::mock::pool_memory_type(), Some(efi::LOADER_DATA));
+            b.dealloc(ptr, layout);
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that temporary buffers up to `N` elements live on the stack,
+    // larger ones are allocated and released again, and that a failed
+    // fallback allocation skips the closure.
+    #[test]
+    fn stack_fallback() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+
+        let r = with_stack_fallback::<u64, _, 4>(&a, 4, |buf| {
+            assert_eq!(buf.len(), 4);
+            crate::mock::pool_live()
+        });
+        assert_eq!(r, Some(0));
+
+        let r = with_stack_fallback::<u64, _, 4>(&a, 5, |buf| {
+            assert_eq!(buf.len(), 5);
+            for (i, v) in buf.iter_mut().enumerate() {
+                *v = core::mem::MaybeUninit::new(i as u64);
+            }
+            crate::mock::pool_live()
+        });
+        assert_eq!(r, Some(1));
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        // Zero-sized elements never need an allocation.
+        let r = with_stack_fallback::<(), _, 4>(&a, 1000, |buf| buf.len());
+        assert_eq!(r, Some(1000));
+
+        crate::mock::pool_fail(Some(efi::Status::OUT_OF_RESOURCES));
+        let r = with_stack_fallback::<u64, _, 4>(&a, 5, |_| unreachable!());
+        assert_eq!(r, None::<()>);
+        crate::mock::pool_fail(None);
+    }
+
+    // Verify that an allocator with the AP check only serves requests on the
+    // BSP. Allocations on an AP fail with `ACCESS_DENIED`, deallocations on
+    // an AP panic.
+    #[test]
+    fn ap_check() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mp = fw.mp_services();
+        let a = unsafe {
+            Allocator::from_system_table(st, efi::LOADER_DATA).with_ap_check(mp)
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let ptr = unsafe { a.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(a.last_error(), None);
+
+        crate::mock::set_processor(1);
+        assert!(unsafe { a.alloc(layout) }.is_null());
+        assert_eq!(a.last_error(), Some(efi::Status::ACCESS_DENIED));
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        let ap = std::panic::AssertUnwindSafe(&a);
+        let r = std::panic::catch_unwind(|| unsafe {
+            ap.0.dealloc(ptr, layout)
+        });
+        assert!(r.is_err());
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        crate::mock::set_processor(0);
+        unsafe { a.dealloc(ptr, layout) };
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that the status of the firmware is recorded when an allocation
+    // fails, and cleared again by the next successful allocation.
+    #[test]
+    fn last_error() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        assert_eq!(a.last_error(), None);
+
+        crate::mock::pool_fail(Some(efi::Status::INVALID_PARAMETER));
+        assert!(unsafe { a.alloc(layout) }.is_null());
+        assert_eq!(a.last_error(), Some(efi::Status::INVALID_PARAMETER));
+
+        crate::mock::pool_fail(Some(efi::Status::OUT_OF_RESOURCES));
+        assert!(unsafe { a.alloc(layout) }.is_null());
+        assert_eq!(a.last_error(), Some(efi::Status::OUT_OF_RESOURCES));
+        crate::mock::pool_fail(None);
+
+        unsafe {
+            let ptr = a.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(a.last_error(), None);
+            a.dealloc(ptr, layout);
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that resizing blocks through the allocator trait retains their
+    // content, clears grown memory if requested, and releases all blocks.
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn resize() {
+        use core::alloc::Allocator as _;
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let layout = |size, align| {
+            core::alloc::Layout::from_size_align(size, align).unwrap()
+        };
+
+        for align in &[8, 64] {
+            let l0 = layout(16, *align);
+            let l1 = layout(256, *align);
+            let l2 = layout(8, *align);
+
+            unsafe {
+                let p0 = a.allocate(l0).unwrap().cast::<u8>();
+                core::ptr::write_bytes(p0.as_ptr(), 0xaa, 16);
+
+                let p1 = a.grow_zeroed(p0, l0, l1).unwrap().cast::<u8>();
+                let s = core::slice::from_raw_parts(p1.as_ptr(), 256);
+                assert!(s[..16].iter().all(|v| *v == 0xaa));
+                assert!(s[16..].iter().all(|v| *v == 0));
+
+                // Shrinking within the same alignment is done in place.
+                let p2 = a.shrink(p1, l1, l2).unwrap().cast::<u8>();
+                assert_eq!(p2, p1);
+                assert_eq!(*p2.as_ptr(), 0xaa);
+
+                a.deallocate(p2, l2);
+            }
+        }
+
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that the debug allocator keeps blocks aligned and leaves the
+    // redzones around them intact.
+    #[cfg(feature = "debug")]
+    #[test]
+    fn debug() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let a = DebugAllocator::new(a);
+
+        for align in &[1, 8, 64] {
+            let layout =
+                core::alloc::Layout::from_size_align(24, *align).unwrap();
+
+            unsafe {
+                let ptr = a.alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                assert_eq!(*ptr.sub(1), REDZONE_BYTE);
+                assert_eq!(*ptr.add(24), REDZONE_BYTE);
+
+                core::ptr::write_bytes(ptr, 0xff, 24);
+                a.dealloc(ptr, layout);
+            }
+        }
+
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that a write past the end of a block is caught on release.
+    #[cfg(feature = "debug")]
+    #[test]
+    #[should_panic(expected = "memory corruption behind block")]
+    fn debug_overflow() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let a = DebugAllocator::new(a);
+        let layout = core::alloc::Layout::from_size_align(24, 8).unwrap();
+
+        unsafe {
+            let ptr = a.alloc(layout);
+            core::ptr::write_bytes(ptr, 0xff, 25);
+            a.dealloc(ptr, layout);
+        }
+    }
+
+    // Verify that the tracking allocator reports exactly the blocks that
+    // were not released, including after growing its side table.
+    #[test]
+    fn tracking() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let a = TrackingAllocator::new(a);
+        let layout = |size| {
+            core::alloc::Layout::from_size_align(size, 8).unwrap()
+        };
+
+        let ptrs: Vec<*mut u8> = (1..=40)
+            .map(|i| unsafe { a.alloc(layout(i)) })
+            .collect();
+        assert_eq!(a.leaks().count(), 40);
+
+        for (i, ptr) in ptrs.iter().enumerate().skip(1) {
+            unsafe { a.dealloc(*ptr, layout(i + 1)) };
+        }
+        assert_eq!(
+            a.leaks().collect::<Vec<_>>(),
+            vec![AllocationRecord {
+                id: 0,
+                ptr: ptrs[0],
+                layout: layout(1),
+            }],
+        );
+
+        unsafe { a.dealloc(ptrs[0], layout(1)) };
+        a.assert_empty();
+
+        drop(a);
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that a rollback releases exactly the live blocks allocated after
+    // the checkpoint.
+    #[test]
+    fn rollback() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let a = TrackingAllocator::new(a);
+        let layout = core::alloc::Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let p0 = a.alloc(layout);
+            let checkpoint = a.checkpoint();
+            let ptrs: Vec<*mut u8> = (0..20).map(|_| a.alloc(layout)).collect();
+            a.dealloc(ptrs[3], layout);
+
+            a.rollback(checkpoint);
+            assert_eq!(
+                a.leaks().collect::<Vec<_>>(),
+                vec![AllocationRecord { id: 0, ptr: p0, layout }],
+            );
+
+            a.rollback(checkpoint);
+            assert_eq!(a.leaks().count(), 1);
+            a.dealloc(p0, layout);
+        }
+
+        drop(a);
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that allocations get increasing identifiers, which are not
+    // reused, and can be looked up by any address within their block.
+    #[test]
+    fn tracking_ids() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let a = TrackingAllocator::new(a);
+        let layout = core::alloc::Layout::from_size_align(16, 8).unwrap();
+
+        unsafe {
+            let p0 = a.alloc(layout);
+            let p1 = a.alloc(layout);
+            assert_eq!(a.id_of(p0), Some(0));
+            assert_eq!(a.id_of(p1.add(15)), Some(1));
+            assert_ne!(a.id_of(p1.add(16)), Some(1));
+
+            a.dealloc(p0, layout);
+            assert_eq!(a.id_of(p0), None);
+            let p2 = a.alloc(layout);
+            assert_eq!(a.id_of(p2), Some(2));
+
+            a.dealloc(p1, layout);
+            a.dealloc(p2, layout);
+        }
+        a.assert_empty();
+    }
+
+    // Verify that leaked blocks are reported by `assert_empty()`.
+    #[test]
+    #[should_panic(expected = "1 memory blocks leaked, including #0 at")]
+    fn tracking_leak() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let a = TrackingAllocator::new(a);
+        let layout = core::alloc::Layout::from_size_align(16, 8).unwrap();
+
+        unsafe { a.alloc(layout) };
+        a.assert_empty();
+    }
+
+    // Verify that strategies are parsed from their names, and that the
+    // selected strategy is applied to subsequent requests.
+    #[test]
+    fn strategy() {
+        assert_eq!(Strategy::parse(""), Some(Strategy::NONE));
+        assert_eq!(
+            Strategy::parse(" zero, poison,"),
+            Some(Strategy::ZERO | Strategy::POISON),
+        );
+        assert_eq!(Strategy::parse("zero,cache"), None);
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let ptr = a.alloc(layout);
+            a.dealloc(ptr, layout);
+            assert_eq!(crate::mock::set_mem_calls(), 0);
+
+            a.set_strategy(Strategy::ZERO);
+            let ptr = a.alloc(layout);
+            let s = core::slice::from_raw_parts(ptr, layout.size());
+            assert!(s.iter().all(|v| *v == 0));
+            assert_eq!(crate::mock::set_mem_calls(), 1);
+
+            a.set_strategy(Strategy::POISON);
+            assert_eq!(a.clone().strategy(), Strategy::POISON);
+            a.dealloc(ptr, layout);
+            assert_eq!(crate::mock::set_mem_calls(), 2);
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that a capped allocator fails requests beyond its cap, like
+    // an exhausted pool, and tracks its peak usage.
+    #[test]
+    fn pool_cap() {
+        static CAP: PoolCap = PoolCap::new(128);
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) }
+            .with_pool_cap(&CAP);
+        let b = a.clone();
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let p0 = a.alloc(layout);
+            let p1 = b.alloc(layout);
+            assert!(!p0.is_null() && !p1.is_null());
+            assert_eq!(CAP.used(), 128);
+
+            assert!(a.alloc(layout).is_null());
+            assert_eq!(a.last_error(), Some(efi::Status::OUT_OF_RESOURCES));
+            assert_eq!(crate::mock::pool_live(), 2);
+
+            // Blocks can be released through any allocator sharing the cap.
+            b.dealloc(p0, layout);
+            let p0 = a.alloc(layout);
+            assert!(!p0.is_null());
+
+            a.dealloc(p0, layout);
+            a.dealloc(p1, layout);
+        }
+        assert_eq!(CAP.used(), 0);
+        assert_eq!(CAP.peak(), 128);
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that releasing a block that was never counted against a cap
+    // through an allocator with the cap is caught.
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "pool cap released more than was charged")]
+    fn pool_cap_uncharged() {
+        static CAP: PoolCap = PoolCap::new(128);
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) }
+            .with_pool_cap(&CAP);
+        let b = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        unsafe { a.dealloc(b.alloc(layout), layout) };
+    }
+
+    // Verify that zeroed allocations are cleared via `SetMem()`, exactly once.
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn zeroed() {
+        use core::alloc::Allocator as _;
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe { Allocator::from_system_table(st, efi::LOADER_DATA) };
+        let layout = core::alloc::Layout::from_size_align(64, 64).unwrap();
+
+        let ptr = a.allocate_zeroed(layout).unwrap();
+        let s = unsafe { ptr.as_ref() };
+        assert!(s.iter().all(|v| *v == 0));
+        assert_eq!(crate::mock::set_mem_calls(), 1);
+        unsafe { a.deallocate(ptr.cast(), layout) };
+
+        // Blocks are not cleared twice if the strategy clears them already.
+        a.set_strategy(Strategy::ZERO);
+        let ptr = a.allocate_zeroed(layout).unwrap();
+        assert_eq!(crate::mock::set_mem_calls(), 2);
+        unsafe { a.deallocate(ptr.cast(), layout) };
+
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that the runtime allocator serves runtime-services data, stops
+    // using the boot-services once exited, and converts its system-table on
+    // the virtual address change.
+    #[test]
+    fn runtime() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let ra = std::boxed::Box::leak(std::boxed::Box::new(unsafe {
+            RuntimeAllocator::from_system_table(st)
+        }));
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        unsafe { ra.watch_events() }.unwrap();
+
+        let ptr = unsafe { ra.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(
+            crate::mock::pool_memory_type(),
+            Some(efi::RUNTIME_SERVICES_DATA),
+        );
+
+        assert_eq!(crate::mock::signal_exit_boot_services(), 1);
+        assert!(ra.is_exited());
+        assert!(unsafe { ra.alloc(layout) }.is_null());
+        unsafe { ra.dealloc(ptr, layout) };
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        let n = crate::mock::signal_events(
+            efi::EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE,
+        );
+        assert_eq!(n, 1);
+        assert_eq!(
+            ra.system_table() as usize,
+            st as usize + crate::mock::VIRTUAL_OFFSET,
+        );
     }
 }
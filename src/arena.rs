@@ -0,0 +1,191 @@
+//! Arena Allocations
+//!
+//! Many UEFI applications create lots of short-lived objects (e.g., device
+//! paths or strings), which are all released at the same time. Serving each
+//! of them from the pool allocator requires a round-trip into the firmware
+//! for every allocation and deallocation. This module provides an arena
+//! allocator for such objects, which grabs a block of pages once and serves
+//! all allocations from it.
+//!
+//! The `Arena` type is a bump allocator. Allocations are served by advancing
+//! an offset into its block, and deallocations are ignored. Memory is only
+//! reclaimed as a whole, via `reset()` or by dropping the arena.
+
+/// Page-backed Bump Allocator
+///
+/// This allocator serves memory from a single block of pages, which it
+/// allocates from the underlying `PageAllocator` on creation. Allocations
+/// never call into the firmware, and fail once the block is exhausted.
+/// Deallocations have no effect. All memory is reclaimed at once via
+/// `reset()`.
+///
+/// The block is released to the page allocator when the arena is dropped.
+pub struct Arena<'alloc> {
+    allocator: &'alloc crate::alloc::PageAllocator,
+    layout: core::alloc::Layout,
+    capacity: usize,
+    base: *mut u8,
+    offset: core::cell::Cell<usize>,
+}
+
+impl<'alloc> Arena<'alloc> {
+    /// Create Arena
+    ///
+    /// Create a new arena on top of the given page allocator, which can
+    /// serve at least `size` bytes of allocations (minus any alignment
+    /// padding). The size is rounded up to full pages. The block is
+    /// allocated right away. This yields `None` if the allocation fails or
+    /// `size` is 0.
+    pub fn new(
+        allocator: &'alloc crate::alloc::PageAllocator,
+        size: usize,
+    ) -> Option<Self> {
+        if size == 0 {
+            return None;
+        }
+
+        let layout = core::alloc::Layout::from_size_align(
+            size,
+            crate::alloc::PAGE_SIZE,
+        ).ok()?;
+        let base = unsafe { allocator.alloc(layout) };
+        if base.is_null() {
+            return None;
+        }
+
+        Some(Self {
+            allocator,
+            layout,
+            capacity: crate::alloc::PageAllocator::page_count(layout)
+                * crate::alloc::PAGE_SIZE,
+            base,
+            offset: core::cell::Cell::new(0),
+        })
+    }
+
+    /// Return the size of the arena in bytes
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Return the number of bytes used by allocations, including padding
+    pub fn used(&self) -> usize {
+        self.offset.get()
+    }
+
+    /// Allocate Memory from the Arena
+    ///
+    /// Serve a block satisfying the given layout from the arena. This returns
+    /// a null-pointer if the arena has insufficient space left. Zero-sized
+    /// requests are served like any other request.
+    ///
+    /// The returned block stays valid until the arena is reset or dropped.
+    pub fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        // The base is page-aligned, so aligning the offset is sufficient for
+        // alignments up to the page size. Higher alignments need the
+        // absolute address.
+        let start = self.base as usize + self.offset.get();
+        let pad = start.wrapping_neg() & (layout.align() - 1);
+
+        let end = self
+            .offset
+            .get()
+            .checked_add(pad)
+            .and_then(|v| v.checked_add(layout.size()));
+        match end {
+            Some(end) if end <= self.capacity => {
+                let ptr = unsafe { self.base.add(self.offset.get() + pad) };
+                self.offset.set(end);
+                ptr
+            }
+            _ => core::ptr::null_mut(),
+        }
+    }
+
+    /// Reset the Arena
+    ///
+    /// Reclaim all memory of the arena, so it can serve new allocations. All
+    /// blocks served by the arena before are invalidated. This requires a
+    /// mutable reference, hence no collection can still borrow the arena.
+    pub fn reset(&mut self) {
+        self.offset.set(0);
+    }
+}
+
+impl<'alloc> Drop for Arena<'alloc> {
+    fn drop(&mut self) {
+        unsafe { self.allocator.dealloc(self.base, self.layout) };
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl<'alloc> core::alloc::Allocator for Arena<'alloc> {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let ptr = Arena::alloc(self, layout);
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, layout.size())
+                        as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn deallocate(
+        &self,
+        _ptr: core::ptr::NonNull<u8>,
+        _layout: core::alloc::Layout,
+    ) {
+        // Memory is only reclaimed via `reset()`.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r_efi::efi;
+
+    // Verify that the arena serves aligned blocks until it is exhausted, and
+    // that a reset makes the whole block available again.
+    #[test]
+    fn bump() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let pa = unsafe {
+            crate::alloc::PageAllocator::from_system_table(
+                st,
+                efi::LOADER_DATA,
+            )
+        };
+        let layout = |size, align| {
+            core::alloc::Layout::from_size_align(size, align).unwrap()
+        };
+
+        let mut arena = Arena::new(&pa, 100).unwrap();
+        assert_eq!(arena.capacity(), 4096);
+        assert_eq!(crate::mock::pages_live(), 1);
+
+        let p0 = arena.alloc(layout(3, 1));
+        let p1 = arena.alloc(layout(8, 8));
+        assert_eq!(p1 as usize - p0 as usize, 8);
+        assert_eq!(arena.used(), 16);
+
+        assert!(!arena.alloc(layout(4080, 1)).is_null());
+        assert!(arena.alloc(layout(1, 1)).is_null());
+        assert!(!arena.alloc(layout(0, 1)).is_null());
+
+        arena.reset();
+        assert_eq!(arena.used(), 0);
+        assert_eq!(arena.alloc(layout(4096, 1)), p0);
+
+        drop(arena);
+        assert_eq!(crate::mock::pages_live(), 0);
+    }
+}
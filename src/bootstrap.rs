@@ -0,0 +1,235 @@
+//! Bootstrap Allocator
+//!
+//! This module provides a tiny fixed-capacity allocator that requires no
+//! setup at all. It serves allocations from a static buffer embedded in the
+//! allocator object itself, and can thus be used from the very first
+//! instruction of the UEFI entry-point, before the system-table was validated
+//! or any allocator was created. This is mostly useful for panic and logging
+//! infrastructure, which might need a few allocations during setup itself.
+//!
+//! The bootstrap allocator is a simple bump allocator. Memory is handed out
+//! linearly from the buffer, and can only be reused once it is released in
+//! reverse order of allocation. It is not meant as general purpose allocator,
+//! but only to bridge the time until a real allocator is available.
+//!
+//! A bootstrap allocator can be registered with a `global::Bridge` via
+//! `Bridge::with_bootstrap()`. The bridge then serves allocations from the
+//! bootstrap allocator as long as no allocator is attached. Once an allocator
+//! is attached, all new allocations are served by it, while blocks of the
+//! bootstrap allocator are still returned to it. Hence, the bootstrap
+//! allocator is drained as the early allocations are released.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! use r_efi_alloc::{bootstrap::Bootstrap, global::Bridge};
+//!
+//! static BOOTSTRAP: Bootstrap<[u8; 4096]> = Bootstrap::new();
+//!
+//! #[global_allocator]
+//! static GLOBAL_ALLOCATOR: Bridge = Bridge::new().with_bootstrap(&BOOTSTRAP);
+//! ```
+
+use core::sync::atomic;
+
+/// Bootstrap Allocator
+///
+/// This is a fixed-capacity bump allocator serving memory from a buffer
+/// embedded in the object. The buffer type `B` is a byte array of the desired
+/// capacity when the allocator is declared (e.g., `Bootstrap<[u8; 4096]>`).
+/// References to it can be coerced to the unsized `Bootstrap` type, which is
+/// independent of the capacity.
+///
+/// The allocator is lock-free and can be shared freely. It never calls into
+/// the firmware.
+pub struct Bootstrap<B: ?Sized = [u8]> {
+    offset: atomic::AtomicUsize,
+    live: atomic::AtomicUsize,
+    capacity: usize,
+    buffer: core::cell::UnsafeCell<B>,
+}
+
+// The buffer is only ever accessed through raw pointers to disjoint blocks,
+// which are handed out via atomic operations on `offset`.
+unsafe impl<B: ?Sized> Sync for Bootstrap<B> {}
+
+impl<const N: usize> Bootstrap<[u8; N]> {
+    /// Create Bootstrap Allocator
+    ///
+    /// Create a new bootstrap allocator with a capacity of `N` bytes. This is
+    /// a constant function, so the allocator can be used to initialize a
+    /// static variable.
+    pub const fn new() -> Self {
+        Self {
+            offset: atomic::AtomicUsize::new(0),
+            live: atomic::AtomicUsize::new(0),
+            capacity: N,
+            buffer: core::cell::UnsafeCell::new([0; N]),
+        }
+    }
+}
+
+impl<const N: usize> Default for Bootstrap<[u8; N]> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: ?Sized> Bootstrap<B> {
+    fn base(&self) -> usize {
+        self.buffer.get() as *mut u8 as usize
+    }
+
+    /// Return the capacity of the allocator in bytes
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Return the number of bytes currently in use
+    ///
+    /// This includes padding required to satisfy alignment requests, as well
+    /// as released blocks that cannot be reused, yet.
+    pub fn used(&self) -> usize {
+        self.offset.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Return the number of live allocations
+    pub fn live(&self) -> usize {
+        self.live.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Check whether all allocations were released
+    pub fn is_drained(&self) -> bool {
+        self.live() == 0
+    }
+
+    /// Check whether a pointer was allocated from this allocator
+    ///
+    /// Return true if `ptr` points into the buffer of this allocator. This
+    /// allows routing deallocations to the correct allocator if multiple
+    /// allocators are in use.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let base = self.base();
+        let addr = ptr as usize;
+
+        addr >= base && addr - base < self.capacity
+    }
+
+    /// Allocate Memory from the Bootstrap Buffer
+    ///
+    /// Allocate a block satisfying the given layout from the buffer of this
+    /// allocator. This returns a null-pointer if the buffer is exhausted.
+    /// Zero-sized requests are served like any other request.
+    pub fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let base = self.base();
+        let mut start = 0;
+
+        // Bump the offset atomically. We compute the aligned start of the
+        // block based on the absolute address, since the buffer itself is
+        // only byte-aligned.
+        let r = self.offset.fetch_update(
+            atomic::Ordering::Relaxed,
+            atomic::Ordering::Relaxed,
+            |offset| {
+                let addr = base.checked_add(offset)?;
+                let aligned = addr.checked_add(layout.align() - 1)?
+                    & !(layout.align() - 1);
+                let end = (aligned - base).checked_add(layout.size())?;
+
+                if end > self.capacity {
+                    None
+                } else {
+                    start = aligned - base;
+                    Some(end)
+                }
+            },
+        );
+
+        if r.is_err() {
+            return core::ptr::null_mut();
+        }
+
+        self.live.fetch_add(1, atomic::Ordering::Relaxed);
+        unsafe { (self.buffer.get() as *mut u8).add(start) }
+    }
+
+    /// Deallocate Memory to the Bootstrap Buffer
+    ///
+    /// Release a block previously allocated via `alloc()`. If it is the most
+    /// recent allocation, its space is reused for further allocations.
+    /// Otherwise, its space remains unusable.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The memory block must be the same as previously returned by a call to
+    /// `alloc()` on this allocator, and the layout must match. Every memory
+    /// block must be released exactly once.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        assert!(self.contains(ptr) || layout.size() == 0);
+
+        // Roll back the offset if this is the last block. If not, or if
+        // another allocation raced us, the space is simply not reused.
+        let start = ptr as usize - self.base();
+        let _ = self.offset.compare_exchange(
+            start + layout.size(),
+            start,
+            atomic::Ordering::Relaxed,
+            atomic::Ordering::Relaxed,
+        );
+
+        self.live.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
+unsafe impl<B: ?Sized> core::alloc::GlobalAlloc for Bootstrap<B> {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        Bootstrap::alloc(self, layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        Bootstrap::dealloc(self, ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify basic bump allocation, including alignment, exhaustion and
+    // reuse of the most recent block.
+    #[test]
+    fn bump() {
+        let b: Bootstrap<[u8; 256]> = Bootstrap::new();
+        let b: &Bootstrap = &b;
+
+        let l8 = core::alloc::Layout::from_size_align(8, 8).unwrap();
+        let l64 = core::alloc::Layout::from_size_align(64, 64).unwrap();
+
+        let p0 = b.alloc(l8);
+        assert!(!p0.is_null());
+        assert!(b.contains(p0));
+        assert_eq!(p0 as usize % 8, 0);
+
+        let p1 = b.alloc(l64);
+        assert!(!p1.is_null());
+        assert!(b.contains(p1));
+        assert_eq!(p1 as usize % 64, 0);
+        assert_eq!(b.live(), 2);
+
+        // Releasing the last block allows reusing its space.
+        let used = b.used();
+        unsafe { b.dealloc(p1, l64) };
+        assert!(b.used() < used);
+        assert_eq!(b.alloc(l64), p1);
+        unsafe { b.dealloc(p1, l64) };
+
+        // Requests beyond the capacity must fail.
+        let l = core::alloc::Layout::from_size_align(512, 8).unwrap();
+        assert!(b.alloc(l).is_null());
+
+        unsafe { b.dealloc(p0, l8) };
+        assert!(b.is_drained());
+        assert!(!b.contains(core::ptr::null()));
+    }
+}
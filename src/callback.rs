@@ -0,0 +1,241 @@
+//! Event-Callback Allocations
+//!
+//! UEFI restricts which boot-services can be called at raised task priority
+//! levels. Code running in event notification functions (usually at
+//! `TPL_CALLBACK` or `TPL_NOTIFY`) thus cannot rely on the pool allocator to
+//! serve memory requests. This module provides a bounded allocator for such
+//! code, which is pre-filled at `TPL_APPLICATION` and never calls into the
+//! firmware when serving or releasing memory.
+//!
+//! The `CallbackPool` type keeps a fixed number of equally sized blocks. It
+//! serves any request that fits into a block by handing out one of its
+//! pre-allocated blocks. Once all blocks are handed out, further requests
+//! fail. Released blocks are put back into the pool. The pool can be refilled
+//! from low TPL via `refill()`, in which case all missing blocks are
+//! allocated from the underlying allocator again.
+
+use core::sync::atomic;
+
+/// Bounded Allocator for Event Callbacks
+///
+/// This allocator keeps up to `N` pre-allocated blocks of a fixed layout,
+/// and serves allocations from them. Allocation and deallocation are
+/// lock-free and never call into the firmware, hence they can be used from
+/// any task priority level, including event notification functions.
+///
+/// The blocks are allocated from the underlying `Allocator` via `refill()`,
+/// which must be called at a task priority level that permits pool
+/// allocations (usually from the main application flow).
+pub struct CallbackPool<'alloc, const N: usize> {
+    allocator: &'alloc crate::alloc::Allocator,
+    block: core::alloc::Layout,
+    blocks: atomic::AtomicUsize,
+    slots: [atomic::AtomicPtr<u8>; N],
+}
+
+impl<'alloc, const N: usize> CallbackPool<'alloc, N> {
+    /// Create Callback Pool
+    ///
+    /// Create a new, empty callback pool on top of the given allocator. Each
+    /// block of the pool will satisfy the layout given as `block`, and thus
+    /// can serve any request with at most the same size and alignment.
+    ///
+    /// No memory is allocated until `refill()` is called.
+    ///
+    /// This function panics if the size of `block` is 0.
+    pub fn new(
+        allocator: &'alloc crate::alloc::Allocator,
+        block: core::alloc::Layout,
+    ) -> Self {
+        assert!(block.size() > 0);
+
+        Self {
+            allocator,
+            block,
+            blocks: atomic::AtomicUsize::new(0),
+            slots: core::array::from_fn(|_| {
+                atomic::AtomicPtr::new(core::ptr::null_mut())
+            }),
+        }
+    }
+
+    /// Refill the pool
+    ///
+    /// Allocate blocks from the underlying allocator until the pool owns `N`
+    /// blocks again (counting blocks that are currently handed out). This
+    /// calls into the pool allocator of the firmware and must thus only be
+    /// called at a task priority level that permits this.
+    ///
+    /// This returns the number of blocks that are available for allocation
+    /// after the operation. If the underlying allocator fails, the pool is
+    /// only partially refilled.
+    pub fn refill(&self) -> usize {
+        // Reserve a block in the counter before allocating it. This
+        // guarantees that there are never more than `N` blocks in
+        // circulation, which in turn guarantees that every block can be
+        // stored in a free slot.
+        while self
+            .blocks
+            .fetch_update(
+                atomic::Ordering::Relaxed,
+                atomic::Ordering::Relaxed,
+                |v| if v < N { Some(v + 1) } else { None },
+            )
+            .is_ok()
+        {
+            let ptr = unsafe { self.allocator.alloc(self.block) };
+            if ptr.is_null() {
+                self.blocks.fetch_sub(1, atomic::Ordering::Relaxed);
+                break;
+            }
+
+            self.put(ptr);
+        }
+
+        self.available()
+    }
+
+    /// Return the number of blocks available for allocation
+    pub fn available(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|v| !v.load(atomic::Ordering::Relaxed).is_null())
+            .count()
+    }
+
+    /// Allocate Memory from the Pool
+    ///
+    /// Hand out one of the pre-allocated blocks of the pool, if the given
+    /// layout fits into a block. This never calls into the firmware.
+    ///
+    /// This returns a null-pointer if the layout exceeds the block layout,
+    /// or if no block is available.
+    pub fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if layout.size() > self.block.size()
+            || layout.align() > self.block.align()
+        {
+            return core::ptr::null_mut();
+        }
+
+        // Take the first available block. Since every slot is atomically
+        // swapped with NULL, no block can be handed out twice. The Acquire
+        // pairs with the Release in `put()`.
+        for slot in self.slots.iter() {
+            let ptr =
+                slot.swap(core::ptr::null_mut(), atomic::Ordering::Acquire);
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+
+        core::ptr::null_mut()
+    }
+
+    /// Deallocate Memory to the Pool
+    ///
+    /// Put a block previously returned by `alloc()` back into the pool. This
+    /// never calls into the firmware.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The memory block must be the same as previously returned by a call to
+    /// `alloc()` on this pool. Every memory block must be released exactly
+    /// once.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, _layout: core::alloc::Layout) {
+        assert!(!ptr.is_null());
+
+        self.put(ptr);
+    }
+
+    fn put(&self, ptr: *mut u8) {
+        // Store the block in the first free slot. There are never more
+        // blocks in circulation than there are slots, so there is always a
+        // free slot. If we are preempted by an event callback, the slots
+        // might shuffle around, but a free slot remains, so we simply retry.
+        loop {
+            for slot in self.slots.iter() {
+                let r = slot.compare_exchange(
+                    core::ptr::null_mut(),
+                    ptr,
+                    atomic::Ordering::Release,
+                    atomic::Ordering::Relaxed,
+                );
+                if r.is_ok() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl<'alloc, const N: usize> Drop for CallbackPool<'alloc, N> {
+    fn drop(&mut self) {
+        // Return all blocks to the underlying allocator. Blocks that are
+        // still handed out are leaked, since we have no way to track them.
+        for slot in self.slots.iter_mut() {
+            let ptr =
+                core::mem::replace(slot.get_mut(), core::ptr::null_mut());
+            if !ptr.is_null() {
+                unsafe { self.allocator.dealloc(ptr, self.block) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify that the pool is refilled from the firmware, serves requests
+    // that fit a block until it is exhausted, and returns its blocks to the
+    // firmware when dropped.
+    #[test]
+    fn pool() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let block = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        let small = core::alloc::Layout::from_size_align(32, 8).unwrap();
+        let large = core::alloc::Layout::from_size_align(128, 8).unwrap();
+        let aligned = core::alloc::Layout::from_size_align(32, 64).unwrap();
+
+        let pool: CallbackPool<'_, 2> = CallbackPool::new(&a, block);
+        assert_eq!(pool.available(), 0);
+        assert!(pool.alloc(small).is_null());
+
+        crate::mock::pool_fail(Some(r_efi::efi::Status::OUT_OF_RESOURCES));
+        assert_eq!(pool.refill(), 0);
+        crate::mock::pool_fail(None);
+        assert_eq!(pool.refill(), 2);
+        assert_eq!(crate::mock::pool_live(), 2);
+
+        // Oversized and over-aligned requests are rejected.
+        assert!(pool.alloc(large).is_null());
+        assert!(pool.alloc(aligned).is_null());
+        assert_eq!(pool.available(), 2);
+
+        let p0 = pool.alloc(small);
+        let p1 = pool.alloc(block);
+        assert!(!p0.is_null() && !p1.is_null() && p0 != p1);
+        assert!(pool.alloc(small).is_null());
+        assert_eq!(pool.available(), 0);
+
+        // Blocks in circulation are counted, so a refill allocates nothing.
+        unsafe { pool.dealloc(p0, small) };
+        assert_eq!(pool.available(), 1);
+        assert_eq!(pool.refill(), 1);
+        assert_eq!(crate::mock::pool_live(), 2);
+
+        // Blocks still handed out are leaked on drop.
+        drop(pool);
+        assert_eq!(crate::mock::pool_live(), 1);
+        unsafe { a.dealloc(p1, block) };
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+}
@@ -0,0 +1,193 @@
+//! C Interface
+//!
+//! This module exports the pool allocator of this crate with a C ABI, so it
+//! can serve as allocation layer of UEFI projects that are not (fully)
+//! written in rust. It provides the same alignment handling as the `raw`
+//! module, which the UEFI pool allocator lacks.
+//!
+//! The interface uses a single, process-wide configuration. It must be set
+//! up via `r_efi_alloc_init()` before any other function is called, and the
+//! system-table passed to it must stay valid while any allocation is live.
+//! All functions report failures as NULL, and never unwind into the caller.
+//!
+//! To link this interface into a C project, build the crate as static
+//! library with the `capi` feature:
+//!
+//! ```sh
+//! cargo rustc --release --features capi --crate-type staticlib
+//! ```
+//!
+//! A matching C header can be generated via `cbindgen`, using the
+//! `cbindgen.toml` of this crate.
+
+use core::sync::atomic;
+use r_efi::efi;
+
+static SYSTEM_TABLE: atomic::AtomicPtr<efi::SystemTable> =
+    atomic::AtomicPtr::new(core::ptr::null_mut());
+static MEMORY_TYPE: atomic::AtomicU32 =
+    atomic::AtomicU32::new(efi::LOADER_DATA);
+
+// Return the layout for the given C request, or `None` if it is invalid or
+// zero-sized (the pool allocator cannot serve empty blocks).
+fn layout(size: usize, align: usize) -> Option<core::alloc::Layout> {
+    if size == 0 {
+        None
+    } else {
+        core::alloc::Layout::from_size_align(size, align).ok()
+    }
+}
+
+/// Initialize the Allocator
+///
+/// Set the system-table to allocate through, and the memory type to use for
+/// all allocations. This can be called again to switch to another
+/// system-table or memory type, but all blocks must be released through the
+/// same configuration they were allocated with.
+///
+/// Safety
+/// ------
+///
+/// `system_table` must be a valid system-table, and its boot-services must
+/// stay available while any allocation of this interface is live.
+#[no_mangle]
+pub unsafe extern "C" fn r_efi_alloc_init(
+    system_table: *mut efi::SystemTable,
+    memory_type: efi::MemoryType,
+) {
+    MEMORY_TYPE.store(memory_type, atomic::Ordering::Relaxed);
+    SYSTEM_TABLE.store(system_table, atomic::Ordering::Release);
+}
+
+/// Allocate Memory
+///
+/// Allocate a block of `size` bytes, aligned to `align`. The alignment must
+/// be a power of two. This returns NULL if the allocator is not
+/// initialized, the request is invalid or zero-sized, or the firmware
+/// cannot serve it.
+///
+/// Safety
+/// ------
+///
+/// The requirements of `r_efi_alloc_init()` apply.
+#[no_mangle]
+pub unsafe extern "C" fn r_efi_alloc_alloc(
+    size: usize,
+    align: usize,
+) -> *mut core::ffi::c_void {
+    let st = SYSTEM_TABLE.load(atomic::Ordering::Acquire);
+
+    match layout(size, align) {
+        Some(layout) if !st.is_null() => crate::raw::alloc(
+            st,
+            layout,
+            MEMORY_TYPE.load(atomic::Ordering::Relaxed),
+        ) as *mut _,
+        _ => core::ptr::null_mut(),
+    }
+}
+
+/// Release Memory
+///
+/// Release a block previously returned by `r_efi_alloc_alloc()` or
+/// `r_efi_alloc_realloc()`. Passing NULL is a no-op.
+///
+/// Safety
+/// ------
+///
+/// `size` and `align` must match the values the block was allocated with,
+/// and the block must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn r_efi_alloc_free(
+    ptr: *mut core::ffi::c_void,
+    size: usize,
+    align: usize,
+) {
+    let st = SYSTEM_TABLE.load(atomic::Ordering::Acquire);
+
+    if let Some(layout) = layout(size, align) {
+        if !ptr.is_null() && !st.is_null() {
+            crate::raw::dealloc(st, ptr as *mut u8, layout);
+        }
+    }
+}
+
+/// Resize Memory
+///
+/// Move a block of `old_size` bytes to a new block of `new_size` bytes with
+/// the same alignment, and return the new block. The content is preserved up
+/// to the smaller of both sizes. If `ptr` is NULL, this behaves like
+/// `r_efi_alloc_alloc()`. If `new_size` is 0, the block is released and NULL
+/// is returned. On failure, NULL is returned and the old block is left
+/// untouched.
+///
+/// Safety
+/// ------
+///
+/// The requirements of `r_efi_alloc_free()` apply to the old block.
+#[no_mangle]
+pub unsafe extern "C" fn r_efi_alloc_realloc(
+    ptr: *mut core::ffi::c_void,
+    old_size: usize,
+    align: usize,
+    new_size: usize,
+) -> *mut core::ffi::c_void {
+    if ptr.is_null() {
+        return r_efi_alloc_alloc(new_size, align);
+    }
+    if new_size == 0 {
+        r_efi_alloc_free(ptr, old_size, align);
+        return core::ptr::null_mut();
+    }
+
+    let new = r_efi_alloc_alloc(new_size, align);
+    if !new.is_null() {
+        core::ptr::copy_nonoverlapping(
+            ptr as *const u8,
+            new as *mut u8,
+            core::cmp::min(old_size, new_size),
+        );
+        r_efi_alloc_free(ptr, old_size, align);
+    }
+
+    new
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run a block through the C interface and verify alignment, content
+    // preservation and release of all memory.
+    #[test]
+    fn roundtrip() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+
+        unsafe {
+            r_efi_alloc_init(st, efi::BOOT_SERVICES_DATA);
+            assert!(r_efi_alloc_alloc(0, 8).is_null());
+            assert!(r_efi_alloc_alloc(16, 3).is_null());
+
+            let p = r_efi_alloc_alloc(16, 64) as *mut u8;
+            assert!(!p.is_null());
+            assert_eq!(p as usize % 64, 0);
+            assert_eq!(
+                crate::mock::pool_memory_type(),
+                Some(efi::BOOT_SERVICES_DATA),
+            );
+            core::ptr::write_bytes(p, 0xaa, 16);
+
+            let p = r_efi_alloc_realloc(p as *mut _, 16, 64, 256) as *mut u8;
+            assert!(!p.is_null());
+            assert_eq!(p as usize % 64, 0);
+            assert_eq!(*p.add(15), 0xaa);
+            assert_eq!(crate::mock::pool_live(), 1);
+
+            assert!(r_efi_alloc_realloc(p as *mut _, 256, 64, 0).is_null());
+            assert_eq!(crate::mock::pool_live(), 0);
+
+            r_efi_alloc_init(core::ptr::null_mut(), efi::LOADER_DATA);
+        }
+    }
+}
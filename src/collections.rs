@@ -0,0 +1,43 @@
+//! Collections
+//!
+//! This module re-exports the collections of the `alloc` crate of the rust
+//! standard library. It allows UEFI applications to use the collections of
+//! the standard library by depending on `r-efi` and `r-efi-alloc` only, with
+//! a single import path for everything related to memory allocations.
+//!
+//! Note that these types are served by the global allocator. Hence, a
+//! `global::Bridge` must be registered as `#[global_allocator]` and have an
+//! allocator attached, before any of these types can allocate memory.
+//!
+//! This module is only available if the `collections` feature is enabled.
+
+pub use liballoc::borrow::{Cow, ToOwned};
+pub use liballoc::boxed::Box;
+pub use liballoc::collections::{
+    BTreeMap, BTreeSet, BinaryHeap, LinkedList, VecDeque,
+};
+pub use liballoc::format;
+pub use liballoc::rc::Rc;
+pub use liballoc::string::{String, ToString};
+pub use liballoc::vec;
+pub use liballoc::vec::Vec;
+
+#[cfg(test)]
+mod tests {
+    // Verify that the re-exports are the collections and macros of the
+    // standard library, and are thus served by the global allocator like any
+    // other collection.
+    #[test]
+    fn reexports() {
+        let v: std::vec::Vec<u32> = crate::collections::vec![1, 2, 3];
+        let s: std::string::String =
+            crate::collections::format!("{}-{}", v[0], v.len());
+        assert_eq!(s, "1-3");
+
+        let mut m = crate::collections::BTreeMap::new();
+        m.insert(s.as_str(), crate::collections::Rc::<str>::from("x"));
+        let b: crate::collections::Box<[u32]> = v.into_boxed_slice();
+        assert_eq!(b.len(), m.len() + 2);
+        assert_eq!(crate::collections::ToString::to_string(&m["1-3"]), "x");
+    }
+}
@@ -0,0 +1,196 @@
+//! Configuration Reports
+//!
+//! This module describes the configuration this crate was compiled with. The
+//! enabled cargo features and the thresholds of the allocators affect how
+//! memory requests are served, so bug reports and crash dumps should state
+//! them. `Config::current()` captures this configuration, and its report
+//! format is a single line suitable to be pasted verbatim.
+//!
+//! The report starts with a format version, which is bumped whenever the
+//! meaning of an existing field changes. New fields might be appended
+//! without bumping the version.
+
+// Version of the report format produced by `Config`. Appending fields, or
+// new names to the feature list, keeps the version as is.
+const REPORT_VERSION: u32 = 1;
+
+/// Allocator Configuration
+///
+/// This describes the compile-time configuration of this crate. It is
+/// obtained via `Config::current()`, and can be formatted via its `Display`
+/// implementation or `to_report_string()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Config {
+    /// Version of this crate.
+    pub version: &'static str,
+    /// Whether the `allocator_api` feature is enabled.
+    pub allocator_api: bool,
+    /// Whether the `capi` feature is enabled.
+    pub capi: bool,
+    /// Whether the `check_tables` feature is enabled.
+    pub check_tables: bool,
+    /// Whether the `collections` feature is enabled.
+    pub collections: bool,
+    /// Whether the `current` feature is enabled.
+    pub current: bool,
+    /// Whether the `debug` feature is enabled.
+    pub debug: bool,
+    /// Whether the `native` feature is enabled.
+    pub native: bool,
+    /// Whether the `pool_quirks` feature is enabled.
+    pub pool_quirks: bool,
+    /// Whether the `stats` feature is enabled.
+    pub stats: bool,
+    /// Alignment guaranteed by the pool allocator, in bytes.
+    pub pool_alignment: usize,
+    /// Page size of the page allocator, in bytes.
+    pub page_size: usize,
+    /// Size of each redzone of `DebugAllocator`, in bytes. This is 0 if the
+    /// `debug` feature is not enabled.
+    pub redzone_size: usize,
+    /// Size limit of blocks served by a `Reserve`, in bytes.
+    pub reserve_limit: usize,
+    /// Default promotion threshold of `PromotingAllocator`, in bytes.
+    pub promote_threshold: usize,
+    /// Number of secondary allocators a chained bridge can hold.
+    pub secondary_slots: usize,
+}
+
+impl Config {
+    /// Return the Current Configuration
+    pub const fn current() -> Config {
+        Config {
+            version: env!("CARGO_PKG_VERSION"),
+            allocator_api: cfg!(feature = "allocator_api"),
+            capi: cfg!(feature = "capi"),
+            check_tables: cfg!(feature = "check_tables"),
+            collections: cfg!(feature = "collections"),
+            current: cfg!(feature = "current"),
+            debug: cfg!(feature = "debug"),
+            native: cfg!(feature = "native"),
+            pool_quirks: cfg!(feature = "pool_quirks"),
+            stats: cfg!(feature = "stats"),
+            pool_alignment: crate::raw::align::POOL_ALIGNMENT,
+            page_size: crate::alloc::PAGE_SIZE,
+            #[cfg(feature = "debug")]
+            redzone_size: crate::alloc::REDZONE_SIZE,
+            #[cfg(not(feature = "debug"))]
+            redzone_size: 0,
+            reserve_limit: crate::reserve::RESERVE_LIMIT,
+            promote_threshold: crate::alloc::PROMOTE_THRESHOLD,
+            secondary_slots: crate::global::SECONDARY_SLOTS,
+        }
+    }
+
+    /// Format Report into Pool Memory
+    ///
+    /// Write the report of this configuration into a new write buffer backed
+    /// by `allocator`. This yields `None` if the allocation fails. Use the
+    /// `Display` implementation to write the report without allocating.
+    pub fn to_report_string<'alloc>(
+        &self,
+        allocator: &'alloc crate::alloc::Allocator,
+    ) -> Option<crate::fmt::PoolWriteBuf<'alloc>> {
+        use core::fmt::Write;
+
+        let mut buf = crate::fmt::PoolWriteBuf::new(allocator);
+        write!(buf, "{}", self).ok()?;
+        Some(buf)
+    }
+}
+
+impl core::fmt::Display for Config {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "r-efi-alloc/{} v{} features=",
+            self.version, REPORT_VERSION,
+        )?;
+
+        let features = [
+            ("allocator_api", self.allocator_api),
+            ("capi", self.capi),
+            ("check_tables", self.check_tables),
+            ("collections", self.collections),
+            ("current", self.current),
+            ("debug", self.debug),
+            ("native", self.native),
+            ("pool_quirks", self.pool_quirks),
+            ("stats", self.stats),
+        ];
+        let mut first = true;
+        for (name, _) in features.iter().filter(|v| v.1) {
+            f.write_str(if first { "" } else { "," })?;
+            f.write_str(name)?;
+            first = false;
+        }
+        if first {
+            f.write_str("-")?;
+        }
+
+        write!(
+            f,
+            " pool_align={} page_size={} redzone={}",
+            self.pool_alignment, self.page_size, self.redzone_size,
+        )?;
+        write!(
+            f,
+            " reserve_limit={} promote={} secondary_slots={}",
+            self.reserve_limit, self.promote_threshold, self.secondary_slots,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify the report format, both via `Display` and in pool memory.
+    #[test]
+    fn report() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let config = Config {
+            version: "1.2.3",
+            allocator_api: true,
+            capi: false,
+            check_tables: false,
+            collections: false,
+            current: false,
+            debug: true,
+            native: false,
+            pool_quirks: false,
+            stats: false,
+            pool_alignment: 8,
+            page_size: 4096,
+            redzone_size: 16,
+            reserve_limit: 64,
+            promote_threshold: 65536,
+            secondary_slots: 3,
+        };
+
+        let buf = config.to_report_string(&a).unwrap();
+        assert_eq!(
+            buf.as_str(),
+            "r-efi-alloc/1.2.3 v1 features=allocator_api,debug \
+             pool_align=8 page_size=4096 redzone=16 \
+             reserve_limit=64 promote=65536 secondary_slots=3",
+        );
+        drop(buf);
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        let config = Config {
+            allocator_api: false,
+            debug: false,
+            ..config
+        };
+        assert!(format!("{}", config).contains(" features=- "));
+        assert_eq!(Config::current().version, env!("CARGO_PKG_VERSION"));
+    }
+}
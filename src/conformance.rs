@@ -0,0 +1,260 @@
+//! Allocator Conformance Tests
+//!
+//! This module provides a reusable test-suite for implementations of the
+//! `core::alloc::Allocator` trait. It exercises the behavioral contract of
+//! the trait, including alignment, zero-sized requests, resizing, and layout
+//! edge cases. The allocators of this crate are validated against it, and
+//! authors of custom backends can do the same by calling `run()` on their
+//! allocator.
+//!
+//! The test-suite panics on the first violation it detects, with a message
+//! describing the offending request. It does not rely on the standard
+//! library, so it can be run on the target as well as on the host.
+//!
+//! This module is only available with the `allocator_api` feature.
+
+use core::alloc::{Allocator, Layout};
+use core::ptr::NonNull;
+
+// Largest alignment exercised by the test-suite. This covers alignments
+// below, at, and above the guarantees of the UEFI pool allocator, as well as
+// page alignment.
+const MAX_ALIGN: usize = 8192;
+
+// Allocate a block and verify the returned slice satisfies the layout.
+fn allocate<A: Allocator + ?Sized>(a: &A, layout: Layout) -> NonNull<u8> {
+    let block = match a.allocate(layout) {
+        Ok(v) => v,
+        Err(_) => panic!("allocation failed for {:?}", layout),
+    };
+    let ptr = block.cast::<u8>();
+
+    assert!(
+        block.len() >= layout.size(),
+        "block of {} bytes returned for {:?}",
+        block.len(),
+        layout,
+    );
+    assert!(
+        ptr.as_ptr() as usize & (layout.align() - 1) == 0,
+        "misaligned block {:p} returned for {:?}",
+        ptr,
+        layout,
+    );
+
+    ptr
+}
+
+// Fill a block with a pattern derived from `seed`.
+unsafe fn fill(ptr: NonNull<u8>, len: usize, seed: u8) {
+    for i in 0..len {
+        *ptr.as_ptr().add(i) = seed.wrapping_add(i as u8);
+    }
+}
+
+// Verify a block still contains the pattern written by `fill()`.
+unsafe fn verify(ptr: NonNull<u8>, len: usize, seed: u8) {
+    for i in 0..len {
+        assert!(
+            *ptr.as_ptr().add(i) == seed.wrapping_add(i as u8),
+            "content of block {:p} lost at offset {}",
+            ptr,
+            i,
+        );
+    }
+}
+
+/// Verify Alignment Guarantees
+///
+/// Allocate blocks of various sizes for all alignments up to 8KiB, verify
+/// that they are properly aligned and usable, and release them again.
+pub fn alignment<A: Allocator + ?Sized>(a: &A) {
+    let mut align = 1;
+
+    while align <= MAX_ALIGN {
+        for size in [1, align - 1, align, align + 1, 3 * align] {
+            if size == 0 {
+                continue;
+            }
+
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = allocate(a, layout);
+
+            unsafe {
+                fill(ptr, size, align as u8);
+                verify(ptr, size, align as u8);
+                a.deallocate(ptr, layout);
+            }
+        }
+
+        align *= 2;
+    }
+}
+
+/// Verify Zero-Sized Requests
+///
+/// Allocate zero-sized blocks for various alignments, verify that they are
+/// properly aligned, and release them again. Zero-sized requests must not
+/// fail.
+pub fn zero_size<A: Allocator + ?Sized>(a: &A) {
+    let mut align = 1;
+
+    while align <= MAX_ALIGN {
+        let layout = Layout::from_size_align(0, align).unwrap();
+        let ptr = allocate(a, layout);
+
+        unsafe { a.deallocate(ptr, layout) };
+
+        align *= 2;
+    }
+}
+
+/// Verify Zeroed Allocations
+///
+/// Allocate zeroed blocks and verify their content is cleared. Blocks are
+/// dirtied and released first, so recycled memory is exercised as well.
+pub fn zeroed<A: Allocator + ?Sized>(a: &A) {
+    for align in [1, 16, 4096] {
+        for size in [1, 64, 4097] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+
+            let ptr = allocate(a, layout);
+            unsafe {
+                fill(ptr, size, 0xa5);
+                a.deallocate(ptr, layout);
+            }
+
+            let block = match a.allocate_zeroed(layout) {
+                Ok(v) => v,
+                Err(_) => panic!("zeroed allocation failed for {:?}", layout),
+            };
+            let ptr = block.cast::<u8>();
+
+            unsafe {
+                for i in 0..size {
+                    assert!(
+                        *ptr.as_ptr().add(i) == 0,
+                        "zeroed block {:p} is dirty at offset {}",
+                        ptr,
+                        i,
+                    );
+                }
+                a.deallocate(ptr, layout);
+            }
+        }
+    }
+}
+
+/// Verify Resizing
+///
+/// Grow and shrink blocks, with and without changing their alignment, and
+/// verify that their content is retained (and cleared for `grow_zeroed()`).
+/// This includes resizing from and to zero-sized blocks.
+pub fn resize<A: Allocator + ?Sized>(a: &A) {
+    let aligns = [(8, 8), (8, 64), (64, 64), (64, 8), (4096, 16)];
+
+    for (from, to) in aligns {
+        for (small, large) in [(0, 64), (1, 64), (24, 4096), (100, 101)] {
+            let l0 = Layout::from_size_align(small, from).unwrap();
+            let l1 = Layout::from_size_align(large, to).unwrap();
+            let l2 = Layout::from_size_align(small, from).unwrap();
+
+            unsafe {
+                let p0 = allocate(a, l0);
+                fill(p0, small, 0x11);
+
+                let p1 = match a.grow(p0, l0, l1) {
+                    Ok(v) => v.cast::<u8>(),
+                    Err(_) => panic!("growing {:?} to {:?} failed", l0, l1),
+                };
+                assert!(p1.as_ptr() as usize & (to - 1) == 0);
+                verify(p1, small, 0x11);
+                fill(p1, large, 0x22);
+
+                let p2 = match a.shrink(p1, l1, l2) {
+                    Ok(v) => v.cast::<u8>(),
+                    Err(_) => panic!("shrinking {:?} to {:?} failed", l1, l2),
+                };
+                assert!(p2.as_ptr() as usize & (from - 1) == 0);
+                verify(p2, small, 0x22);
+
+                let p3 = match a.grow_zeroed(p2, l2, l1) {
+                    Ok(v) => v.cast::<u8>(),
+                    Err(_) => panic!("growing {:?} to {:?} failed", l2, l1),
+                };
+                verify(p3, small, 0x22);
+                for i in small..large {
+                    assert!(
+                        *p3.as_ptr().add(i) == 0,
+                        "grown block {:p} is dirty at offset {}",
+                        p3,
+                        i,
+                    );
+                }
+
+                a.deallocate(p3, l1);
+            }
+        }
+    }
+}
+
+/// Verify Layout Edge Cases
+///
+/// Issue requests that cannot possibly be served, since they exceed the
+/// address-space when combined with their alignment. These must fail
+/// gracefully rather than wrap around or panic.
+pub fn edge_cases<A: Allocator + ?Sized>(a: &A) {
+    for align in [1, 8, 64, 4096] {
+        let size = isize::MAX as usize - (align - 1);
+        let layout = Layout::from_size_align(size, align).unwrap();
+
+        if let Ok(block) = a.allocate(layout) {
+            panic!("impossible request {:?} served at {:p}", layout, block);
+        }
+    }
+}
+
+/// Run the Conformance Test-Suite
+///
+/// Run all tests of this module on the allocator given as `a`. This panics
+/// on the first violation of the allocator contract. All memory allocated
+/// by the test-suite is released before this returns.
+pub fn run<A: Allocator + ?Sized>(a: &A) {
+    alignment(a);
+    zero_size(a);
+    zeroed(a);
+    resize(a);
+    edge_cases(a);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r_efi::efi;
+
+    // Validate the pool, page and promoting allocators of this crate against
+    // the conformance test-suite.
+    #[test]
+    fn backends() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+
+        let pool = unsafe {
+            crate::alloc::Allocator::from_system_table(st, efi::LOADER_DATA)
+        };
+        run(&pool);
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        let pages = unsafe {
+            crate::alloc::PageAllocator::from_system_table(st, efi::LOADER_DATA)
+        };
+        run(&pages);
+        assert_eq!(crate::mock::pages_live(), 0);
+
+        let promoting = crate::alloc::PromotingAllocator::new(pool)
+            .with_threshold(256);
+        run(&promoting);
+        assert_eq!(crate::mock::pool_live(), 0);
+        assert_eq!(crate::mock::pages_live(), 0);
+    }
+}
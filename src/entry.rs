@@ -0,0 +1,101 @@
+//! UEFI Entry-Point
+//!
+//! Every UEFI image that uses the global allocator needs the same setup code:
+//! a static `Bridge` marked as `global_allocator`, and an entry-point that
+//! creates an allocator from the system-table, attaches it to the bridge,
+//! runs the actual application, and detaches the allocator again. Getting any
+//! of this wrong usually results in allocations failing at runtime.
+//!
+//! This module provides the `entry!()` macro, which generates all of this.
+//! It declares the `#[global_allocator]` static `BRIDGE` and the `efi_main`
+//! entry-point, which forwards to `Bridge::run()`. Hence, the allocator uses
+//! the data type of the image as memory type, and is detached when the
+//! application function returns or unwinds.
+//!
+//! Optionally, the bridge can be configured by passing an expression that
+//! creates it as second argument.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! #![no_main]
+//! #![no_std]
+//!
+//! use r_efi::efi;
+//!
+//! r_efi_alloc::entry!(efi_run);
+//!
+//! fn efi_run(h: efi::Handle, st: *mut efi::SystemTable) -> efi::Status {
+//!     ...
+//! }
+//! ```
+
+// Re-export of the UEFI definitions, so the expansion of `entry!()` does not
+// depend on how the caller imports `r-efi`.
+#[doc(hidden)]
+pub use r_efi::efi;
+
+/// Generate UEFI Entry-Point
+///
+/// Declare the global allocator `BRIDGE` and the UEFI entry-point
+/// `efi_main`, which runs the function given as first argument with an
+/// attached allocator (see `global::Bridge::run()`). The function must have
+/// the signature `fn(efi::Handle, *mut efi::SystemTable) -> efi::Status`.
+///
+/// By default, the bridge is created via `global::Bridge::new()`. An
+/// expression creating a differently configured bridge can be passed as
+/// second argument. It must be a constant expression.
+///
+/// The macro can be used in any module, but only once per image, since it
+/// defines the `efi_main` symbol and only one global allocator can exist in
+/// a dependency graph.
+///
+/// Safety
+/// ------
+///
+/// The macro hides an `unsafe` call to `global::Bridge::run()`. The compiler
+/// cannot check its requirements, so by using the macro, the caller takes
+/// them over:
+///
+///  * The function must release all its global allocations before it
+///    returns or unwinds, since the allocator is detached afterwards. In
+///    particular, it must not leak allocations into static variables, or
+///    hand them to the firmware beyond its own lifetime.
+///
+///  * `efi_main` must only be invoked by the firmware, which passes the
+///    valid system-table of the image.
+///
+/// # Examples
+///
+/// ```no_run
+/// use r_efi::efi;
+///
+/// fn efi_run(_h: efi::Handle, _st: *mut efi::SystemTable) -> efi::Status {
+///     let v: Vec<u8> = Vec::with_capacity(64);
+///     drop(v);
+///     efi::Status::SUCCESS
+/// }
+///
+/// r_efi_alloc::entry!(efi_run, r_efi_alloc::global::Bridge::new());
+/// # fn main() {}
+/// ```
+#[macro_export]
+macro_rules! entry {
+    ($main:path $(,)?) => {
+        $crate::entry!($main, $crate::global::Bridge::new());
+    };
+    ($main:path, $bridge:expr $(,)?) => {
+        #[global_allocator]
+        static BRIDGE: $crate::global::Bridge = $bridge;
+
+        #[no_mangle]
+        pub extern "C" fn efi_main(
+            h: $crate::entry::efi::Handle,
+            st: *mut $crate::entry::efi::SystemTable,
+        ) -> $crate::entry::efi::Status {
+            // The system-table is passed in by the firmware, and the
+            // function is required to release its allocations.
+            unsafe { BRIDGE.run(h, st, $main) }
+        }
+    };
+}
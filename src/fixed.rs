@@ -0,0 +1,437 @@
+//! Fixed-Buffer Allocator
+//!
+//! Once the boot-services were exited, the UEFI pool allocator is gone, but
+//! code like panic handlers or logging might still need to allocate memory.
+//! This module provides an allocator that serves memory from a buffer
+//! provided by the caller, and thus never calls into the firmware.
+//!
+//! The `FixedAllocator` type manages its buffer with a first-fit free-list,
+//! sorted by address. Released blocks are merged with adjacent free blocks,
+//! so the buffer can be reused indefinitely, as long as fragmentation stays
+//! reasonable. It can be registered with a `global::Bridge` via
+//! `Bridge::with_fallback()`, in which case the bridge serves allocations
+//! from it once the boot-services were exited.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! use r_efi_alloc::{fixed::FixedAllocator, global::Bridge};
+//!
+//! static FALLBACK: FixedAllocator = FixedAllocator::new();
+//!
+//! #[global_allocator]
+//! static GLOBAL_ALLOCATOR: Bridge = Bridge::new().with_fallback(&FALLBACK);
+//!
+//! // Before exiting the boot-services, provide memory to the fallback
+//! // allocator. It must remain valid for the rest of the image lifetime.
+//! FALLBACK.set_buffer(buffer);
+//! ```
+
+use core::sync::atomic;
+
+// Header of a free block. It is stored at the start of every free block in
+// the buffer, linking all free blocks in order of their address. Its size
+// is the granularity of all blocks of the allocator.
+#[repr(C)]
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const UNIT: usize = core::mem::size_of::<FreeBlock>();
+
+// The block granularity must satisfy the alignment of the header, so every
+// block boundary can hold a header.
+const _: () = assert!(UNIT & (core::mem::align_of::<FreeBlock>() - 1) == 0);
+
+struct State {
+    free: *mut FreeBlock,
+}
+
+/// Fixed-Buffer Allocator
+///
+/// This allocator serves memory from a buffer provided via `set_buffer()`.
+/// Until a buffer is set, all allocations fail. Allocations never call into
+/// the firmware, and thus keep working after the boot-services were exited.
+///
+/// The free-list is protected by a spin-lock. If an allocation finds the
+/// lock taken (e.g., when re-entered from an interrupting callback), it fails
+/// rather than risk a dead-lock. A deallocation that finds the lock taken
+/// puts the block on a lock-free list of pending blocks instead, which are
+/// merged into the free-list by the next operation that takes the lock.
+/// `contains()` and `capacity()` never take the lock. Only `set_buffer()`
+/// and `available()` wait for the lock, and must thus not be called from
+/// callbacks that might interrupt the allocator.
+pub struct FixedAllocator {
+    lock: atomic::AtomicBool,
+    base: atomic::AtomicUsize,
+    len: atomic::AtomicUsize,
+    pending: atomic::AtomicPtr<FreeBlock>,
+    state: core::cell::UnsafeCell<State>,
+}
+
+// The state is only ever accessed with the lock held. The buffer location is
+// published via atomics, and pending blocks are handed over via an atomic
+// list.
+unsafe impl Sync for FixedAllocator {}
+
+impl FixedAllocator {
+    /// Create Fixed-Buffer Allocator
+    ///
+    /// Create a new allocator without a buffer. This is a constant function,
+    /// so the allocator can be used to initialize a static variable.
+    pub const fn new() -> FixedAllocator {
+        FixedAllocator {
+            lock: atomic::AtomicBool::new(false),
+            base: atomic::AtomicUsize::new(0),
+            len: atomic::AtomicUsize::new(0),
+            pending: atomic::AtomicPtr::new(core::ptr::null_mut()),
+            state: core::cell::UnsafeCell::new(State {
+                free: core::ptr::null_mut(),
+            }),
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.lock
+            .compare_exchange(
+                false,
+                true,
+                atomic::Ordering::Acquire,
+                atomic::Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    fn lock(&self) {
+        while !self.try_lock() {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.lock.store(false, atomic::Ordering::Release);
+    }
+
+    // Merge all pending blocks into the free-list. The caller must hold the
+    // lock.
+    unsafe fn drain(&self) {
+        let mut block =
+            self.pending.swap(core::ptr::null_mut(), atomic::Ordering::Acquire);
+
+        while !block.is_null() {
+            let next = (*block).next;
+            self.release(block as *mut u8, (*block).size);
+            block = next;
+        }
+    }
+
+    // Put the block at @ptr of @size bytes back on the free-list, and merge
+    // it with adjacent free blocks. The caller must hold the lock.
+    unsafe fn release(&self, ptr: *mut u8, size: usize) {
+        let addr = ptr as usize;
+        let state = &mut *self.state.get();
+        let mut link: *mut *mut FreeBlock = &mut state.free;
+        let mut prev: *mut FreeBlock = core::ptr::null_mut();
+
+        while !(*link).is_null() && (*link as usize) < addr {
+            prev = *link;
+            link = &mut (*prev).next;
+        }
+
+        // Merge with the following block, if adjacent.
+        let mut block = FreeBlock { size, next: *link };
+        if !block.next.is_null() && addr + size == block.next as usize {
+            block.size += (*block.next).size;
+            block.next = (*block.next).next;
+        }
+
+        // Merge with the preceding block, if adjacent. Otherwise, insert
+        // the block into the list.
+        if !prev.is_null() && prev as usize + (*prev).size == addr {
+            (*prev).size += block.size;
+            (*prev).next = block.next;
+        } else {
+            let b = ptr as *mut FreeBlock;
+            b.write(block);
+            *link = b;
+        }
+    }
+
+    // Return the size of the block backing a layout, or `None` if it
+    // overflows the address-space.
+    fn block_size(layout: core::alloc::Layout) -> Option<usize> {
+        let size = core::cmp::max(layout.size(), 1);
+        Some(size.checked_add(UNIT - 1)? & !(UNIT - 1))
+    }
+
+    /// Provide the Buffer
+    ///
+    /// Hand the buffer given as `buffer` to the allocator, which serves all
+    /// allocations from it. The buffer can only be set once. If a buffer was
+    /// set already, the passed buffer is returned as error.
+    ///
+    /// Parts of the buffer might be left unused to satisfy the alignment
+    /// requirements of the allocator.
+    pub fn set_buffer(
+        &self,
+        buffer: &'static mut [u8],
+    ) -> Result<(), &'static mut [u8]> {
+        self.lock();
+        let state = unsafe { &mut *self.state.get() };
+        if self.len.load(atomic::Ordering::Relaxed) > 0 {
+            self.unlock();
+            return Err(buffer);
+        }
+
+        let start = buffer.as_mut_ptr() as usize;
+        let base = start
+            .checked_add(UNIT - 1)
+            .map_or(usize::MAX, |v| v & !(UNIT - 1));
+        let len = buffer.len().saturating_sub(base - start) & !(UNIT - 1);
+
+        if len > 0 {
+            let block = base as *mut FreeBlock;
+            unsafe {
+                block.write(FreeBlock {
+                    size: len,
+                    next: core::ptr::null_mut(),
+                });
+            }
+            state.free = block;
+
+            // Publish the length last, so `contains()` sees a valid base
+            // once it sees a non-zero length.
+            self.base.store(base, atomic::Ordering::Relaxed);
+            self.len.store(len, atomic::Ordering::Release);
+        }
+
+        self.unlock();
+        Ok(())
+    }
+
+    /// Check whether a pointer was allocated from this allocator
+    ///
+    /// Return true if `ptr` points into the buffer of this allocator. This
+    /// allows routing deallocations to the correct allocator if multiple
+    /// allocators are in use. This never takes the lock of the allocator.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let len = self.len.load(atomic::Ordering::Acquire);
+        let base = self.base.load(atomic::Ordering::Relaxed);
+        let addr = ptr as usize;
+
+        len > 0 && addr >= base && addr - base < len
+    }
+
+    /// Return the size of the buffer
+    ///
+    /// Return the number of bytes of the buffer that are managed by this
+    /// allocator. This excludes parts of the buffer left unused for
+    /// alignment. Without a buffer, this is 0.
+    pub fn capacity(&self) -> usize {
+        self.len.load(atomic::Ordering::Acquire)
+    }
+
+    /// Return the number of free bytes
+    ///
+    /// Return the sum of the sizes of all free blocks. Due to fragmentation,
+    /// an allocation of this size might still fail.
+    pub fn available(&self) -> usize {
+        self.lock();
+        unsafe { self.drain() };
+        let mut block = unsafe { (*self.state.get()).free };
+        let mut r = 0;
+
+        while !block.is_null() {
+            unsafe {
+                r += (*block).size;
+                block = (*block).next;
+            }
+        }
+
+        self.unlock();
+        r
+    }
+
+    /// Allocate Memory from the Buffer
+    ///
+    /// Allocate a block satisfying the given layout from the buffer of this
+    /// allocator. This returns a null-pointer if no free block is large
+    /// enough, or if the allocator is locked by an interrupted operation.
+    /// Zero-sized requests are served like any other request.
+    pub fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let size = match Self::block_size(layout) {
+            Some(v) => v,
+            None => return core::ptr::null_mut(),
+        };
+        let align = core::cmp::max(layout.align(), UNIT);
+
+        if !self.try_lock() {
+            return core::ptr::null_mut();
+        }
+        unsafe { self.drain() };
+        let state = unsafe { &mut *self.state.get() };
+        let mut link: *mut *mut FreeBlock = &mut state.free;
+        let mut r = core::ptr::null_mut();
+
+        unsafe {
+            while !(*link).is_null() {
+                let block = *link;
+                let addr = block as usize;
+                let start = (addr + (align - 1)) & !(align - 1);
+                let pad = start - addr;
+
+                let end = pad.checked_add(size);
+                if matches!(end, Some(v) if v <= (*block).size) {
+                    // Both ends of the block are aligned to `UNIT`, so any
+                    // remainder in front of or behind the new block can hold
+                    // a header and stays on the free-list.
+                    let tail = (*block).size - pad - size;
+                    let mut next = (*block).next;
+
+                    if tail > 0 {
+                        let t = (start + size) as *mut FreeBlock;
+                        t.write(FreeBlock { size: tail, next });
+                        next = t;
+                    }
+                    if pad > 0 {
+                        (*block).size = pad;
+                        (*block).next = next;
+                    } else {
+                        *link = next;
+                    }
+
+                    r = start as *mut u8;
+                    break;
+                }
+
+                link = &mut (*block).next;
+            }
+        }
+
+        self.unlock();
+        r
+    }
+
+    /// Deallocate Memory to the Buffer
+    ///
+    /// Release a block previously allocated via `alloc()`. The block is put
+    /// back on the free-list and merged with adjacent free blocks. If the
+    /// allocator is locked by an interrupted operation, the block is queued
+    /// and merged by the next operation instead. Hence, this never waits.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The block must have been allocated via `alloc()` of this allocator
+    /// with the same layout, and must not be used afterwards.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        // The layout was already verified by `alloc()`.
+        let size = Self::block_size(layout).unwrap();
+
+        if !self.try_lock() {
+            // Push the block on the pending list. Blocks are at least `UNIT`
+            // bytes, so every block can hold a header.
+            let block = ptr as *mut FreeBlock;
+            let mut next = self.pending.load(atomic::Ordering::Relaxed);
+            loop {
+                block.write(FreeBlock { size, next });
+                match self.pending.compare_exchange_weak(
+                    next,
+                    block,
+                    atomic::Ordering::Release,
+                    atomic::Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(v) => next = v,
+                }
+            }
+        }
+
+        self.release(ptr, size);
+        self.drain();
+        self.unlock();
+    }
+}
+
+impl Default for FixedAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify that released blocks are merged and reused, and that the
+    // allocator serves nothing without a buffer.
+    #[test]
+    fn freelist() {
+        let fixed = FixedAllocator::new();
+        let layout = |size, align| {
+            core::alloc::Layout::from_size_align(size, align).unwrap()
+        };
+
+        assert!(fixed.alloc(layout(1, 1)).is_null());
+
+        #[repr(align(64))]
+        struct Buffer([u8; 256]);
+        let buffer = Box::leak(Box::new(Buffer([0; 256])));
+        assert!(fixed.set_buffer(&mut buffer.0[..]).is_ok());
+        assert!(fixed.set_buffer(&mut []).is_err());
+
+        unsafe {
+            let p0 = fixed.alloc(layout(64, 8));
+            let p1 = fixed.alloc(layout(64, 8));
+            let p2 = fixed.alloc(layout(64, 64));
+            assert!(!p0.is_null() && !p1.is_null() && !p2.is_null());
+            assert!(fixed.contains(p1));
+            assert_eq!(p2 as usize % 64, 0);
+            assert!(fixed.alloc(layout(128, 8)).is_null());
+
+            // Release the first two blocks in reverse, so both merges are
+            // exercised, then verify the merged space serves a large block.
+            fixed.dealloc(p1, layout(64, 8));
+            fixed.dealloc(p0, layout(64, 8));
+            let p3 = fixed.alloc(layout(128, 8));
+            assert_eq!(p3, p0);
+
+            fixed.dealloc(p3, layout(128, 8));
+            fixed.dealloc(p2, layout(64, 64));
+            assert_eq!(fixed.alloc(layout(256, 8)), p0);
+        }
+    }
+
+    // Verify that releases interrupting a locked allocator are deferred
+    // rather than waiting for the lock, and that lookups never wait.
+    #[test]
+    fn deferred() {
+        let fixed = FixedAllocator::new();
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        #[repr(align(64))]
+        struct Buffer([u8; 128]);
+        let buffer = Box::leak(Box::new(Buffer([0; 128])));
+        assert!(fixed.set_buffer(&mut buffer.0[..]).is_ok());
+
+        unsafe {
+            let p0 = fixed.alloc(layout);
+            let p1 = fixed.alloc(layout);
+            assert!(!p0.is_null() && !p1.is_null());
+
+            // Simulate a callback interrupting an operation of the allocator.
+            fixed.lock();
+            assert!(fixed.contains(p0));
+            assert_eq!(fixed.capacity(), 128);
+            assert!(fixed.alloc(layout).is_null());
+            fixed.dealloc(p0, layout);
+            fixed.dealloc(p1, layout);
+            fixed.unlock();
+
+            assert_eq!(fixed.available(), 128);
+            let large = core::alloc::Layout::from_size_align(128, 8).unwrap();
+            assert_eq!(fixed.alloc(large), p0);
+        }
+    }
+}
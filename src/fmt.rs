@@ -0,0 +1,433 @@
+//! Formatting Buffers
+//!
+//! This module provides string buffers that are backed by the allocators of
+//! this crate, rather than the global allocator of the rust standard library.
+//! They allow using the formatting machinery of `core::fmt` (e.g., the
+//! `write!()` macro) before a global allocator bridge is set up, or in
+//! environments that never set one up.
+//!
+//! The `PoolWriteBuf` type implements `core::fmt::Write` and collects the
+//! formatted output as UTF-8. Since UEFI consoles expect UCS-2 strings, the
+//! buffer can be converted into a NUL-terminated `Ucs2Buf`, which can be
+//! passed to `ConOut` directly.
+
+use r_efi::efi;
+
+// Minimum capacity of a `PoolWriteBuf` once it allocates. This avoids a
+// series of tiny reallocations when formatting short messages piece by piece.
+const WRITE_BUF_MIN_CAPACITY: usize = 64;
+
+// Number of UCS-2 characters (including the terminating NUL) that
+// `PoolWriteBuf::with_ucs2()` converts on the stack, rather than allocating
+// a buffer from the pool.
+const UCS2_STACK_LEN: usize = 128;
+
+// Replacement for characters that cannot be represented in UCS-2 (i.e., all
+// characters outside of the basic multilingual plane).
+const UCS2_REPLACEMENT: u16 = 0xfffd;
+
+fn ucs2_from_char(c: char) -> efi::Char16 {
+    if (c as u32) <= 0xffff {
+        c as u32 as efi::Char16
+    } else {
+        UCS2_REPLACEMENT
+    }
+}
+
+/// Allocator-backed Write Buffer
+///
+/// This is a growable UTF-8 string buffer that allocates its memory from a
+/// given `Allocator`. It implements `core::fmt::Write`, so it can be used as
+/// target of the `write!()` macro. If the allocator fails to grow the buffer,
+/// the write operation fails with `core::fmt::Error` and the buffer retains
+/// all previously written data.
+///
+/// The buffer is released to the allocator when dropped.
+pub struct PoolWriteBuf<'alloc> {
+    allocator: &'alloc crate::alloc::Allocator,
+    ptr: *mut u8,
+    len: usize,
+    capacity: usize,
+}
+
+/// Allocator-backed UCS-2 String
+///
+/// This is a NUL-terminated UCS-2 string allocated from a given `Allocator`.
+/// It is created via `PoolWriteBuf::to_ucs2()` and is suitable to be passed
+/// to UEFI interfaces that take `Char16` strings, like `ConOut`.
+///
+/// The string is released to the allocator when dropped.
+pub struct Ucs2Buf<'alloc> {
+    allocator: &'alloc crate::alloc::Allocator,
+    ptr: *mut efi::Char16,
+    len: usize,
+}
+
+impl<'alloc> PoolWriteBuf<'alloc> {
+    /// Create Write Buffer
+    ///
+    /// Create a new, empty write buffer on top of the given allocator. No
+    /// memory is allocated until data is written to the buffer.
+    pub fn new(allocator: &'alloc crate::alloc::Allocator) -> Self {
+        Self {
+            allocator,
+            ptr: core::ptr::null_mut(),
+            len: 0,
+            capacity: 0,
+        }
+    }
+
+    /// Return the length of the buffered string in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the buffered string
+    pub fn as_str(&self) -> &str {
+        if self.len == 0 {
+            ""
+        } else {
+            // The buffer only ever gets appended to via `write_str()`, hence
+            // it always contains valid UTF-8.
+            unsafe {
+                core::str::from_utf8_unchecked(core::slice::from_raw_parts(
+                    self.ptr, self.len,
+                ))
+            }
+        }
+    }
+
+    /// Return the capacity of the buffer in bytes
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Shrink the buffer to fit
+    ///
+    /// Reduce the capacity of the buffer to the length of the buffered
+    /// string, so a long-lived buffer does not keep the slack of its growth
+    /// allocated. The pool allocator can shrink blocks in place, but keeps
+    /// the memory behind them allocated. Hence, a slack of less than the
+    /// minimum capacity is shrunk in place, avoiding a copy. Larger slack is
+    /// released by moving the string into a new block of exactly its size.
+    /// An empty buffer releases its memory entirely.
+    ///
+    /// This is best-effort. If the new block cannot be allocated, the buffer
+    /// is left unchanged.
+    pub fn shrink_to_fit(&mut self) {
+        if self.capacity == self.len {
+            return;
+        }
+
+        let ptr = if self.len == 0 {
+            core::ptr::null_mut()
+        } else {
+            // The length never exceeds the capacity, which was a valid
+            // layout size, so this cannot fail.
+            let layout =
+                core::alloc::Layout::from_size_align(self.len, 1).unwrap();
+
+            let (ptr, old) = (self.ptr, self.layout());
+            if self.capacity - self.len < WRITE_BUF_MIN_CAPACITY
+                && unsafe { self.allocator.resize_in_place(ptr, old, layout) }
+            {
+                self.capacity = self.len;
+                return;
+            }
+
+            let ptr = unsafe { self.allocator.alloc(layout) };
+            if ptr.is_null() {
+                return;
+            }
+
+            unsafe { core::ptr::copy_nonoverlapping(self.ptr, ptr, self.len) };
+            ptr
+        };
+
+        unsafe { self.allocator.dealloc(self.ptr, self.layout()) };
+        self.ptr = ptr;
+        self.capacity = self.len;
+    }
+
+    /// Clear the buffer
+    ///
+    /// This truncates the buffered string to length 0, but retains the
+    /// allocated memory for further use.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Convert to UCS-2
+    ///
+    /// Allocate a NUL-terminated UCS-2 string from the same allocator as this
+    /// buffer, and fill it with the buffered string. Characters outside of
+    /// the basic multilingual plane cannot be represented in UCS-2 and are
+    /// replaced by `U+FFFD`.
+    ///
+    /// This yields `None` if the allocation fails.
+    pub fn to_ucs2(&self) -> Option<Ucs2Buf<'alloc>> {
+        let s = self.as_str();
+        let len = s.chars().count();
+        let layout = core::alloc::Layout::array::<efi::Char16>(
+            len.checked_add(1)?,
+        ).ok()?;
+
+        let ptr = unsafe { self.allocator.alloc(layout) } as *mut efi::Char16;
+        if ptr.is_null() {
+            return None;
+        }
+
+        for (i, c) in s.chars().chain(core::iter::once('\0')).enumerate() {
+            unsafe { core::ptr::write(ptr.add(i), ucs2_from_char(c)) };
+        }
+
+        Some(Ucs2Buf {
+            allocator: self.allocator,
+            ptr,
+            len,
+        })
+    }
+
+    /// Run Closure with UCS-2 String
+    ///
+    /// Convert the buffered string to a NUL-terminated UCS-2 string just like
+    /// `to_ucs2()` does, but only for the duration of the closure `f`. Short
+    /// strings are converted on the stack, so no allocation is performed.
+    /// Only long strings fall back to the allocator of this buffer.
+    ///
+    /// The slice passed to the closure includes the terminating NUL. This
+    /// yields `None` if the fallback allocation fails.
+    pub fn with_ucs2<R>(
+        &self,
+        f: impl FnOnce(&[efi::Char16]) -> R,
+    ) -> Option<R> {
+        let s = self.as_str();
+        let len = s.chars().count().checked_add(1)?;
+
+        crate::alloc::with_stack_fallback::<efi::Char16, R, UCS2_STACK_LEN>(
+            self.allocator,
+            len,
+            |buf| {
+                let chars = s.chars().chain(core::iter::once('\0'));
+                for (v, c) in buf.iter_mut().zip(chars) {
+                    *v = core::mem::MaybeUninit::new(ucs2_from_char(c));
+                }
+
+                // All `len` elements were initialized above.
+                f(unsafe {
+                    core::slice::from_raw_parts(
+                        buf.as_ptr() as *const efi::Char16,
+                        len,
+                    )
+                })
+            },
+        )
+    }
+
+    fn reserve(&mut self, additional: usize) -> Result<(), core::fmt::Error> {
+        let required =
+            self.len.checked_add(additional).ok_or(core::fmt::Error)?;
+        if required <= self.capacity {
+            return Ok(());
+        }
+
+        // Grow exponentially to avoid quadratic copying when formatting many
+        // small fragments.
+        let capacity = core::cmp::max(
+            core::cmp::max(required, WRITE_BUF_MIN_CAPACITY),
+            self.capacity.saturating_mul(2),
+        );
+        let layout = core::alloc::Layout::from_size_align(capacity, 1)
+            .map_err(|_| core::fmt::Error)?;
+
+        let ptr = unsafe { self.allocator.alloc(layout) };
+        if ptr.is_null() {
+            return Err(core::fmt::Error);
+        }
+
+        if !self.ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(self.ptr, ptr, self.len);
+                self.allocator.dealloc(self.ptr, self.layout());
+            }
+        }
+
+        self.ptr = ptr;
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    fn layout(&self) -> core::alloc::Layout {
+        // This layout was successfully created when allocating the buffer,
+        // so it cannot fail now.
+        core::alloc::Layout::from_size_align(self.capacity, 1).unwrap()
+    }
+}
+
+impl<'alloc> core::fmt::Write for PoolWriteBuf<'alloc> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.reserve(s.len())?;
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                s.as_ptr(),
+                self.ptr.add(self.len),
+                s.len(),
+            );
+        }
+        self.len += s.len();
+
+        Ok(())
+    }
+}
+
+impl<'alloc> Drop for PoolWriteBuf<'alloc> {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { self.allocator.dealloc(self.ptr, self.layout()) };
+        }
+    }
+}
+
+impl<'alloc> Ucs2Buf<'alloc> {
+    /// Return the length of the string in characters
+    ///
+    /// The terminating NUL-character is not included.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the string is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the string as slice, including the terminating NUL
+    pub fn as_slice_with_nul(&self) -> &[efi::Char16] {
+        unsafe { core::slice::from_raw_parts(self.ptr, self.len + 1) }
+    }
+
+    /// Return a pointer to the NUL-terminated string
+    ///
+    /// Many UEFI interfaces (like `output_string` of `ConOut`) take mutable
+    /// string pointers, even though they never modify the string. Hence, this
+    /// returns a mutable pointer. The caller must not modify the string
+    /// through it.
+    pub fn as_ptr(&self) -> *mut efi::Char16 {
+        self.ptr
+    }
+}
+
+impl<'alloc> Drop for Ucs2Buf<'alloc> {
+    fn drop(&mut self) {
+        // The layout was successfully computed when allocating the string.
+        let layout =
+            core::alloc::Layout::array::<efi::Char16>(self.len + 1).unwrap();
+
+        unsafe { self.allocator.dealloc(self.ptr as *mut u8, layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify that shrinking keeps small slack in place, moves the string into
+    // a block of its exact size otherwise, and that an empty buffer releases
+    // its memory.
+    #[test]
+    fn shrink() {
+        use core::fmt::Write;
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe {
+            crate::alloc::Allocator::from_system_table(st, efi::LOADER_DATA)
+        };
+
+        let mut buf = PoolWriteBuf::new(&a);
+        write!(buf, "{}", 12345).unwrap();
+        assert_eq!(buf.capacity(), WRITE_BUF_MIN_CAPACITY);
+
+        let ptr = buf.as_str().as_ptr();
+        buf.shrink_to_fit();
+        assert_eq!(buf.capacity(), 5);
+        assert_eq!(buf.as_str(), "12345");
+        assert_eq!(buf.as_str().as_ptr(), ptr);
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        write!(buf, "{:100}", 6).unwrap();
+        assert!(buf.capacity() >= 105);
+        buf.clear();
+        write!(buf, "12345").unwrap();
+        let ptr = buf.as_str().as_ptr();
+        buf.shrink_to_fit();
+        assert_eq!(buf.capacity(), 5);
+        assert_eq!(buf.as_str(), "12345");
+        assert_ne!(buf.as_str().as_ptr(), ptr);
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        buf.clear();
+        buf.shrink_to_fit();
+        assert_eq!(buf.capacity(), 0);
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        write!(buf, "x").unwrap();
+        assert_eq!(buf.as_str(), "x");
+    }
+
+    // Verify that UCS-2 conversions replace characters outside of the basic
+    // multilingual plane and append a NUL, and that `with_ucs2()` converts
+    // short strings on the stack and falls back to the pool for long ones.
+    #[test]
+    fn ucs2() {
+        use core::fmt::Write;
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let a = unsafe {
+            crate::alloc::Allocator::from_system_table(st, efi::LOADER_DATA)
+        };
+        let expected = [0x61, 0xe4, 0xfffd, 0x62, 0];
+
+        let mut buf = PoolWriteBuf::new(&a);
+        write!(buf, "a\u{e4}\u{1f600}b").unwrap();
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        let ucs2 = buf.to_ucs2().unwrap();
+        assert_eq!(ucs2.len(), 4);
+        assert_eq!(ucs2.as_slice_with_nul(), &expected[..]);
+        assert_eq!(crate::mock::pool_live(), 2);
+        drop(ucs2);
+
+        let r = buf.with_ucs2(|v| {
+            assert_eq!(v, &expected[..]);
+            crate::mock::pool_live()
+        });
+        assert_eq!(r, Some(1));
+
+        // A string that does not fit on the stack, including its NUL, is
+        // converted in pool memory.
+        buf.clear();
+        for _ in 0..UCS2_STACK_LEN {
+            buf.write_char('x').unwrap();
+        }
+        let r = buf.with_ucs2(|v| {
+            assert_eq!(v.len(), UCS2_STACK_LEN + 1);
+            assert_eq!(v[UCS2_STACK_LEN], 0);
+            crate::mock::pool_live()
+        });
+        assert_eq!(r, Some(2));
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        crate::mock::pool_fail(Some(efi::Status::OUT_OF_RESOURCES));
+        assert!(buf.with_ucs2(|_| unreachable!()).is_none());
+        assert!(buf.to_ucs2().is_none());
+        crate::mock::pool_fail(None);
+    }
+}
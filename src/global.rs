@@ -18,6 +18,62 @@
 //! to the implementor of the entry-point to set up the global state inherent
 //! to rust's global allocator.
 //!
+//! The `Bridge` type allows attaching and detaching allocators at runtime.
+//! `Bridge::with_attached()` limits an attachment to the runtime of a closure,
+//! so it cannot be leaked, and is released even if the closure unwinds.
+//! Drivers, which must keep their allocator attached beyond their entry-point,
+//! can use `Bridge::attach_static()`. Applications that never detach their
+//! allocator can use the simpler `GlobalAllocatorCell` type instead, which
+//! can be set exactly once.
+//!
+//! If an application ends up with multiple bridges (e.g., one provided by a
+//! runtime library and one declared locally), it can be hard to tell which
+//! of them is actually used as global allocator. Bridges can thus be named
+//! and registered in a global registry via `Bridge::register()`. The
+//! `dump_registry()` function then prints all registered bridges together
+//! with their state.
+//!
+//! If allocators are detached and re-attached at runtime (e.g., when a driver
+//! is reconnected), memory allocated under a previous attachment must not be
+//! released through the bridge, since it would be freed into the wrong
+//! allocator. Bridges created with `Bridge::with_generations()` tag every
+//! block with the attachment generation it was allocated under, and detect
+//! such stale blocks when they are released. Similarly, bridges created with
+//! `Bridge::with_chain()` accept secondary allocators via
+//! `Bridge::attach_secondary()`, which serve requests the primary attachment
+//! fails, and tag every block with the allocator that served it.
+//!
+//! Policy decisions about where requests are served (e.g., sending large
+//! requests to the page allocator) can be kept in a single function of the
+//! application. Bridges created with `Bridge::with_router()` pass the layout
+//! of every request to such a function, which selects one of the routes of
+//! `Route`.
+//!
+//! Library crates that need an allocator, but must not force their users to
+//! register a global allocator, can use `current()` with the `current`
+//! feature. It returns a handle to the bridge or cell the application
+//! installed via `Bridge::install()` or `GlobalAllocatorCell::install()`.
+//! Their entry-points can use `assert_ready!()` to verify a global allocator
+//! is ready, rather than failing obscurely on the first allocation.
+//!
+//! Once the boot-services are exited, no allocator of this crate can be used
+//! anymore. A bridge can be marked as exited via `Bridge::set_exited()`, or
+//! automatically via `Bridge::watch_exit_boot_services()`. Alternatively,
+//! `Bridge::exit_boot_services()` captures the memory map, marks the bridge and
+//! exits the boot-services in one go. Afterwards, the bridge stops calling into
+//! the attached allocator. Allocations fail (or are served by the fallback or
+//! bootstrap allocators), and deallocations are ignored. A fixed-buffer
+//! allocator can be registered via `Bridge::with_fallback()` to keep late
+//! allocations (e.g., of panic handlers) working. Similarly, a reserve can be
+//! registered via `Bridge::with_reserve()` to keep small allocations working
+//! when the firmware pool is exhausted.
+//!
+//! Event callbacks run at raised TPL and can interrupt the application at any
+//! time, including in the middle of an allocation. Bridges created with
+//! `Bridge::with_tpl_guard()` raise the TPL to `TPL_NOTIFY` around every call
+//! into the attached allocator, so callbacks can use the global allocator
+//! safely.
+//!
 //! # Examples
 //!
 //! The following UEFI application simply registers an allocator with its
@@ -59,6 +115,7 @@
 //! ```
 
 use core::sync::atomic;
+use r_efi::efi;
 
 /// Bridge for Global Allocators
 ///
@@ -81,10 +138,100 @@ use core::sync::atomic;
 /// To release it, the attachment object has to be dropped. Note that the
 /// caller must ensure that any global allocator is released before an
 /// allocator attachment is released.
+/// The attached allocator can be replaced atomically via the `swap()`
+/// operation of the attachment object.
 pub struct Bridge {
     attachment: atomic::AtomicPtr<crate::alloc::Allocator>,
+    bootstrap: Option<&'static crate::bootstrap::Bootstrap>,
+    fallback: Option<&'static crate::fixed::FixedAllocator>,
+    reserve: Option<&'static crate::reserve::Reserve>,
+    observer: Option<&'static (dyn crate::observe::AllocObserver + Sync)>,
+    oom_handler: Option<fn(core::alloc::Layout)>,
+    oom_hook: atomic::AtomicPtr<()>,
+    router: Option<Router>,
+    generations: bool,
+    generation: atomic::AtomicUsize,
+    chained: bool,
+    secondary: [atomic::AtomicPtr<crate::alloc::Allocator>; SECONDARY_SLOTS],
+    tpl_guard: bool,
+    exited: atomic::AtomicBool,
+    #[cfg(feature = "stats")]
+    stats: crate::stats::Stats,
+    name: Option<&'static str>,
+    registered: atomic::AtomicBool,
+    next: atomic::AtomicPtr<Bridge>,
+}
+
+/// Out-of-Memory Hook
+///
+/// Type of the hooks that can be set on a bridge via `Bridge::set_oom_hook()`.
+/// The hook is invoked with the layout of a failed allocation and the status
+/// the firmware returned for it, if known. If it returns `true`, the
+/// allocation is retried once.
+pub type OomHook = fn(core::alloc::Layout, Option<efi::Status>) -> bool;
+
+/// Allocation Route
+///
+/// This selects how a bridge created with `Bridge::with_router()` serves a
+/// request. It is returned by the router of the bridge for the layout of
+/// every request, and again for the layout of every released block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    /// Serve the request like a bridge without router does, that is, from
+    /// the attached pool allocator and its fallbacks.
+    Pool,
+    /// Serve the request via `AllocatePages()`, using the system-table and
+    /// memory-type of the attached allocator (see `alloc::PageAllocator`).
+    Pages,
+    /// Serve the request with a dangling, suitably aligned pointer, without
+    /// involving any allocator. This is only valid for zero-sized requests,
+    /// any other request routed here fails.
+    Dangling,
+    /// Fail the request without involving any allocator.
+    Fail,
 }
 
+/// Allocation Router
+///
+/// Type of the routers that can be set on a bridge via
+/// `Bridge::with_router()`. The router is invoked with the layout of a
+/// request and selects the route to serve it.
+pub type Router = fn(&core::alloc::Layout) -> Route;
+
+/// Number of Secondary Allocators
+///
+/// This is the number of secondary allocators that can be attached to a
+/// bridge created with `Bridge::with_chain()`.
+pub const SECONDARY_SLOTS: usize = 3usize;
+
+// Blocks of chained bridges carry the index of the allocator that served
+// them in the low bits of their tag, with the generation in the remaining
+// bits. Index 0 is the primary attachment, secondary slots start at 1.
+const TAG_SHIFT: u32 = 2u32;
+const TAG_INDEX_MASK: usize = (1usize << TAG_SHIFT) - 1;
+
+// Number of attempts of `Bridge::exit_boot_services()` to exit the
+// boot-services with a fresh memory map.
+const EXIT_RETRIES: usize = 4usize;
+
+// Head of the global bridge registry. This is an intrusive singly-linked
+// list through the `next` member of all registered bridges. Bridges can only
+// be added, never removed, hence it can be traversed without locking.
+static REGISTRY: atomic::AtomicPtr<Bridge> =
+    atomic::AtomicPtr::new(core::ptr::null_mut());
+
+// The global allocator installed via `Bridge::install()` or
+// `GlobalAllocatorCell::install()`. At most one of both is set, and neither
+// can be unset again.
+#[cfg(feature = "current")]
+static CURRENT_BRIDGE: atomic::AtomicPtr<Bridge> =
+    atomic::AtomicPtr::new(core::ptr::null_mut());
+#[cfg(feature = "current")]
+static CURRENT_CELL: atomic::AtomicPtr<GlobalAllocatorCell> =
+    atomic::AtomicPtr::new(core::ptr::null_mut());
+#[cfg(feature = "current")]
+static CURRENT_SET: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
 /// Bridge Attachment
 ///
 /// This type represents the attachment of an allocator to a bridge. It is
@@ -96,6 +243,57 @@ pub struct Attachment<'alloc, 'bridge> {
     bridge: &'bridge Bridge,
 }
 
+// Raised TPL of the boot-services, restored to its previous value when
+// dropped.
+struct TplGuard {
+    boot_services: *mut efi::BootServices,
+    tpl: efi::Tpl,
+}
+
+/// Secondary Bridge Attachment
+///
+/// This type represents the attachment of a secondary allocator to a bridge.
+/// It is returned by the `attach_secondary()` operation of a bridge.
+/// Dropping it detaches the allocator from its slot.
+pub struct SecondaryAttachment<'alloc, 'bridge> {
+    allocator: &'alloc mut crate::alloc::Allocator,
+    bridge: &'bridge Bridge,
+    slot: usize,
+}
+
+/// Static Bridge Attachment
+///
+/// This type represents the attachment of a static allocator to a static
+/// bridge. It is returned by the `attach_static()` operation of a bridge.
+/// Unlike `Attachment`, it is not tied to any stack frame, and can thus
+/// outlive the entry-point of the image (e.g., for drivers, which keep
+/// serving protocol calls after their entry-point returned).
+///
+/// Dropping the attachment detaches the allocator, just like `Attachment`.
+/// To keep the allocator attached for the lifetime of the image, use
+/// `leak()`. To detach it explicitly (e.g., from an unload handler) and
+/// regain access to the allocator, use `detach()`.
+pub struct StaticAttachment {
+    allocator: *mut crate::alloc::Allocator,
+    bridge: &'static Bridge,
+}
+
+/// Set-Once Global Allocator
+///
+/// This is a simpler alternative to `Bridge` for applications that never
+/// detach their allocator. The cell is created empty at compile-time, and
+/// can be set exactly once at runtime via `set()`. Once set, the allocator
+/// stays in place for the rest of the lifetime of the application. There is
+/// no attachment object and no way to detach the allocator again, hence
+/// there is no race between global allocations and a detach operation.
+///
+/// Just like the bridge, the cell implements the `GlobalAlloc` interface and
+/// can thus be marked as `global_allocator`. Any allocation request before
+/// the cell is set will yield an allocation error.
+pub struct GlobalAllocatorCell {
+    allocator: atomic::AtomicPtr<crate::alloc::Allocator>,
+}
+
 impl Bridge {
     /// Create Bridge
     ///
@@ -118,7 +316,630 @@ impl Bridge {
     pub const fn new() -> Bridge {
         Bridge {
             attachment: atomic::AtomicPtr::new(core::ptr::null_mut()),
+            bootstrap: None,
+            fallback: None,
+            reserve: None,
+            observer: None,
+            oom_handler: None,
+            oom_hook: atomic::AtomicPtr::new(core::ptr::null_mut()),
+            router: None,
+            generations: false,
+            generation: atomic::AtomicUsize::new(0),
+            chained: false,
+            secondary: [
+                atomic::AtomicPtr::new(core::ptr::null_mut()),
+                atomic::AtomicPtr::new(core::ptr::null_mut()),
+                atomic::AtomicPtr::new(core::ptr::null_mut()),
+            ],
+            tpl_guard: false,
+            exited: atomic::AtomicBool::new(false),
+            #[cfg(feature = "stats")]
+            stats: crate::stats::Stats::new(),
+            name: None,
+            registered: atomic::AtomicBool::new(false),
+            next: atomic::AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Register a fallback allocator
+    ///
+    /// Register the fixed-buffer allocator given as @fallback with this
+    /// bridge. Once the bridge is marked as exited, allocations are served by
+    /// the fallback allocator, rather than failing. Blocks of the fallback
+    /// allocator are returned to it when released.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable. The fallback allocator must be provided with a
+    /// buffer at runtime. See the `fixed` module for details.
+    pub const fn with_fallback(
+        self,
+        fallback: &'static crate::fixed::FixedAllocator,
+    ) -> Bridge {
+        Bridge {
+            fallback: Some(fallback),
+            ..self
+        }
+    }
+
+    /// Register a reserve allocator
+    ///
+    /// Register the reserve given as @reserve with this bridge. Allocations
+    /// smaller than `reserve::RESERVE_LIMIT` bytes are served by the reserve
+    /// if they cannot be served otherwise (e.g., because the firmware pool
+    /// is exhausted). Blocks of the reserve are returned to it when
+    /// released. See the `reserve` module for details.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable.
+    pub const fn with_reserve(
+        self,
+        reserve: &'static crate::reserve::Reserve,
+    ) -> Bridge {
+        Bridge {
+            reserve: Some(reserve),
+            ..self
+        }
+    }
+
+    /// Attach an observer
+    ///
+    /// Attach the observer given as @observer to this bridge. It is notified
+    /// about every allocation, deallocation and allocation failure through
+    /// this bridge, regardless of which allocator serves it. See the
+    /// `observe` module for details.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable. Hence, a fully configured bridge requires no
+    /// runtime registration.
+    pub const fn with_observer(
+        self,
+        observer: &'static (dyn crate::observe::AllocObserver + Sync),
+    ) -> Bridge {
+        Bridge {
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    /// Register an out-of-memory handler
+    ///
+    /// Register the function given as @handler with this bridge. It is
+    /// invoked with the requested layout whenever an allocation through this
+    /// bridge fails, before the failure is returned to the caller. This can
+    /// be used to log the failure, or to release caches.
+    ///
+    /// The handler is invoked on the allocation path and must not allocate
+    /// memory through this bridge.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable.
+    pub const fn with_oom_handler(
+        self,
+        handler: fn(core::alloc::Layout),
+    ) -> Bridge {
+        Bridge {
+            oom_handler: Some(handler),
+            ..self
+        }
+    }
+
+    /// Set the out-of-memory hook
+    ///
+    /// Set the function given as @hook as out-of-memory hook of this bridge,
+    /// or clear it if `None` is passed, and return the previous hook. Unlike
+    /// the handler of `with_oom_handler()`, the hook can be changed at any
+    /// time, and is told the status the firmware returned for the failed
+    /// request (via `Allocator::last_error()` of the attached allocator).
+    ///
+    /// The hook is invoked whenever an allocation through this bridge fails,
+    /// before the observer and out-of-memory handler are notified. Failures
+    /// of `try_alloc_optional()` are silent and do not invoke the hook. The
+    /// hook can log the failure (e.g., via `ConOut`), and it can try to
+    /// reclaim memory. If it returns `true`, the allocation is retried once,
+    /// and only reported as failure if the retry fails as well.
+    ///
+    /// The hook is invoked on the allocation path and must not allocate
+    /// memory through this bridge.
+    pub fn set_oom_hook(&self, hook: Option<OomHook>) -> Option<OomHook> {
+        let v = match hook {
+            Some(hook) => hook as *mut (),
+            None => core::ptr::null_mut(),
+        };
+        let v = self.oom_hook.swap(v, atomic::Ordering::AcqRel);
+
+        // Only `set_oom_hook()` stores into the slot, so any non-NULL value
+        // is a valid hook.
+        if v.is_null() {
+            None
+        } else {
+            Some(unsafe { core::mem::transmute::<*mut (), OomHook>(v) })
+        }
+    }
+
+    /// Enable generation checks
+    ///
+    /// Make this bridge tag every memory block with the generation of the
+    /// attachment it was allocated under. The generation is advanced every
+    /// time an allocator is detached. When a block is released, its tag is
+    /// compared to the current generation, and a mismatch causes a panic
+    /// with the address of the block. This catches blocks that outlive the
+    /// attachment they were allocated under, which would otherwise be freed
+    /// into a different allocator.
+    ///
+    /// The tag is stored in front of every block, which costs at least one
+    /// word of memory per allocation. Blocks served by a bootstrap allocator
+    /// are tagged as well, but never checked.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable.
+    pub const fn with_generations(self) -> Bridge {
+        Bridge {
+            generations: true,
+            ..self
+        }
+    }
+
+    /// Enable the allocator chain
+    ///
+    /// Make this bridge accept secondary allocators via `attach_secondary()`.
+    /// If the primary attachment cannot serve a request (e.g., since it is a
+    /// constrained allocator that is exhausted), the secondary allocators are
+    /// tried in order of their slots. This allows layering allocators, as is
+    /// common in bootloaders.
+    ///
+    /// To release every block to the allocator that served it, blocks are
+    /// tagged like with `with_generations()`. Both can be combined, in which
+    /// case they share the tag.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable.
+    pub const fn with_chain(self) -> Bridge {
+        Bridge {
+            chained: true,
+            ..self
+        }
+    }
+
+    /// Route allocations
+    ///
+    /// Register the function given as @router with this bridge. It is
+    /// invoked with the layout of every request, and selects the route that
+    /// serves it (see `Route`). This keeps policy decisions, like serving
+    /// large requests from pages, or failing requests of a certain size, in
+    /// one place under the control of the application.
+    ///
+    /// Blocks are released to the route selected by the router for their
+    /// layout. Hence, the router must select the same route for the same
+    /// layout every time. Blocks of `Route::Pages` are neither tagged (see
+    /// `with_generations()` and `with_chain()`), nor served by the reserve,
+    /// fallback or bootstrap allocators. Without an attachment, or once the
+    /// boot-services were exited, requests routed to pages fail.
+    ///
+    /// The router is invoked on the allocation path and must not allocate
+    /// memory through this bridge.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable.
+    pub const fn with_router(self, router: Router) -> Bridge {
+        Bridge {
+            router: Some(router),
+            ..self
+        }
+    }
+
+    /// Guard allocations against event callbacks
+    ///
+    /// Make this bridge raise the TPL to `TPL_NOTIFY` around every call into
+    /// the attached allocator. Event callbacks on the same CPU (e.g., timer
+    /// callbacks) thus cannot interrupt an allocation in progress, and can
+    /// safely use the global allocator themselves.
+    ///
+    /// The boot-services must not be used for allocations above
+    /// `TPL_NOTIFY`. Hence, allocations requested above `TPL_NOTIFY` fail,
+    /// and blocks of the firmware released above `TPL_NOTIFY` are leaked,
+    /// rather than calling into the firmware. Leaked blocks are reported to
+    /// the observer via `AllocObserver::on_leak()` and, with the `stats`
+    /// feature, counted in the statistics of the bridge. Blocks of the
+    /// reserve, fallback and bootstrap allocators are released regardless of
+    /// the TPL. Use the `callback` module to allocate at higher TPLs.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable.
+    pub const fn with_tpl_guard(self) -> Bridge {
+        Bridge {
+            tpl_guard: true,
+            ..self
+        }
+    }
+
+    /// Name the bridge
+    ///
+    /// Assign the name given as @name to this bridge. The name is purely
+    /// diagnostic and is shown by `dump_registry()`.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable.
+    pub const fn with_name(self, name: &'static str) -> Bridge {
+        Bridge {
+            name: Some(name),
+            ..self
+        }
+    }
+
+    /// Return the name of the bridge, if any
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Check whether an allocator is attached
+    pub fn is_attached(&self) -> bool {
+        !self.attachment.load(atomic::Ordering::Relaxed).is_null()
+    }
+
+    /// Assert the bridge can serve allocations
+    ///
+    /// Panic with a descriptive message if the bridge cannot serve any
+    /// allocations. That is, if no allocator is attached and no bootstrap
+    /// allocator is registered, or if the boot-services were exited and no
+    /// fallback allocator is registered.
+    ///
+    /// Without this check, using an unprepared bridge shows up as a failed
+    /// allocation deep inside some dependency. This is meant to be called
+    /// at the top of entry-points, usually via `assert_ready!()`.
+    #[track_caller]
+    pub fn assert_ready(&self) {
+        let name = self.name.unwrap_or("<unnamed>");
+
+        if self.is_exited() {
+            assert!(
+                self.fallback.is_some(),
+                "global allocator bridge '{}' is used after \
+                 ExitBootServices() without a fallback allocator",
+                name,
+            );
+        } else {
+            assert!(
+                self.is_attached() || self.bootstrap.is_some(),
+                "global allocator bridge '{}' has no allocator attached, \
+                 attach one (e.g., via `Bridge::run()`) before first use",
+                name,
+            );
+        }
+    }
+
+    /// Mark boot-services as exited
+    ///
+    /// Mark this bridge as exited. From then on, the bridge never calls into
+    /// the attached allocator again, since the boot-services it relies on are
+    /// no longer available. Allocations are served by the fallback allocator
+    /// or the bootstrap allocator, if any, or fail. Deallocations of blocks
+    /// of these allocators are still served, while all other deallocations
+    /// are ignored, since the memory is owned by the operating system now.
+    ///
+    /// This cannot be undone. The attached allocator stays attached, and can
+    /// be detached as usual.
+    pub fn set_exited(&self) {
+        self.exited.store(true, atomic::Ordering::Release);
+    }
+
+    /// Check whether the bridge was marked as exited
+    pub fn is_exited(&self) -> bool {
+        self.exited.load(atomic::Ordering::Acquire)
+    }
+
+    /// Exit the Boot-Services
+    ///
+    /// Capture the memory map with the attached allocator, mark this bridge
+    /// as exited via `set_exited()`, and call `ExitBootServices()` with the
+    /// key of the map. If the map changed in the meantime (e.g., since an
+    /// event callback allocated memory), the firmware rejects the key with
+    /// `INVALID_PARAMETER`, and the map is captured again. This is retried a
+    /// few times, before the status is returned.
+    ///
+    /// On success, the final memory map is returned. Its buffer is never
+    /// released, but handed over to the operating system. If no allocator is
+    /// attached, this fails with `NOT_READY`. The bridge is marked exited
+    /// right before `ExitBootServices()` is called for the first time. If
+    /// capturing the map fails before that, the bridge is left untouched.
+    /// Once `ExitBootServices()` was called, the bridge stays exited even if
+    /// the operation fails, since the firmware might have torn down parts of
+    /// the boot-services already.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The caller must guarantee that it is safe to exit the boot-services
+    /// of the attached allocator, and that the allocator stays attached until
+    /// the returned map is dropped.
+    pub unsafe fn exit_boot_services(
+        &self,
+        image_handle: efi::Handle,
+    ) -> Result<crate::memmap::MemoryMap<'_>, efi::Status> {
+        let allocator = self.attachment.load(atomic::Ordering::Acquire);
+        if allocator.is_null() {
+            return Err(efi::Status::NOT_READY);
+        }
+        let allocator = &*allocator;
+        let bs = (*allocator.system_table()).boot_services;
+
+        let mut r = efi::Status::INVALID_PARAMETER;
+        for _ in 0..EXIT_RETRIES {
+            // Only memory allocation services may be called after a failed
+            // `ExitBootServices()`, which includes `GetMemoryMap()`.
+            let mut map = crate::memmap::MemoryMap::capture(allocator)?;
+
+            self.set_exited();
+            r = ((*bs).exit_boot_services)(image_handle, map.key());
+            if r == efi::Status::SUCCESS {
+                map.set_exited();
+                return Ok(map);
+            } else if r != efi::Status::INVALID_PARAMETER {
+                break;
+            }
+        }
+
+        Err(r)
+    }
+
+    /// Mark bridge as exited on ExitBootServices
+    ///
+    /// Create an event that is signalled when the boot-services are exited,
+    /// and which marks this bridge as exited via `set_exited()`. This uses
+    /// `EVT_SIGNAL_EXIT_BOOT_SERVICES`, so the bridge is marked before the
+    /// boot-services are torn down.
+    ///
+    /// This returns the created event. It can be closed via `CloseEvent()`
+    /// to stop watching.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The caller must guarantee that it is safe to call `CreateEvent()` of
+    /// the boot-services of the passed system-table.
+    pub unsafe fn watch_exit_boot_services(
+        &'static self,
+        system_table: *mut efi::SystemTable,
+    ) -> Result<efi::Event, efi::Status> {
+        let mut event: efi::Event = core::ptr::null_mut();
+
+        let r = ((*(*system_table).boot_services).create_event)(
+            efi::EVT_SIGNAL_EXIT_BOOT_SERVICES,
+            efi::TPL_NOTIFY,
+            Some(exit_boot_services_notify),
+            self as *const Bridge as *mut core::ffi::c_void,
+            &mut event,
+        );
+
+        if r.is_error() {
+            Err(r)
+        } else {
+            Ok(event)
+        }
+    }
+
+    /// Query Allocation Statistics
+    ///
+    /// Return a snapshot of the allocation counters of this bridge. These
+    /// cover all allocations through the bridge, regardless of which
+    /// allocator served them. This is only available with the `stats`
+    /// feature.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::Snapshot {
+        self.stats.snapshot()
+    }
+
+    /// Register the bridge
+    ///
+    /// Add this bridge to the global bridge registry, so it is listed by
+    /// `registry()` and `dump_registry()`. Bridges cannot be removed from
+    /// the registry again, hence this requires a static bridge.
+    ///
+    /// This returns `false` if the bridge was already registered, in which
+    /// case this call has no effect.
+    pub fn register(&'static self) -> bool {
+        if self.registered.swap(true, atomic::Ordering::Relaxed) {
+            return false;
+        }
+
+        // Push the bridge to the front of the registry. The Release pairs
+        // with the Acquire in `registry()`, so the link to the next bridge is
+        // visible to anyone traversing the list.
+        let this = self as *const Bridge as *mut Bridge;
+        let mut head = REGISTRY.load(atomic::Ordering::Relaxed);
+        loop {
+            self.next.store(head, atomic::Ordering::Relaxed);
+            match REGISTRY.compare_exchange_weak(
+                head,
+                this,
+                atomic::Ordering::Release,
+                atomic::Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(v) => head = v,
+            }
+        }
+    }
+
+    /// Install as current allocator
+    ///
+    /// Install this bridge as the allocator returned by `current()`, so
+    /// library crates can allocate from it without relying on the global
+    /// allocator of the application. Only a single bridge or cell can be
+    /// installed, and it cannot be uninstalled again.
+    ///
+    /// This returns `false` if a bridge or cell was installed already, in
+    /// which case this call has no effect. This is only available with the
+    /// `current` feature.
+    #[cfg(feature = "current")]
+    pub fn install(&'static self) -> bool {
+        if CURRENT_SET.swap(true, atomic::Ordering::Relaxed) {
+            return false;
+        }
+
+        let this = self as *const Bridge as *mut Bridge;
+        CURRENT_BRIDGE.store(this, atomic::Ordering::Release);
+        true
+    }
+
+    /// Register a bootstrap allocator
+    ///
+    /// Register the bootstrap allocator given as @bootstrap with this bridge.
+    /// As long as no allocator is attached to the bridge, allocations are
+    /// served by the bootstrap allocator, rather than failing. Once an
+    /// allocator is attached, it serves all new allocations, but blocks of
+    /// the bootstrap allocator are still returned to it when released.
+    ///
+    /// This is a constant function, meant to be used when initializing the
+    /// static bridge variable. See the `bootstrap` module for details.
+    pub const fn with_bootstrap(
+        self,
+        bootstrap: &'static crate::bootstrap::Bootstrap,
+    ) -> Bridge {
+        Bridge {
+            bootstrap: Some(bootstrap),
+            ..self
+        }
+    }
+
+    // Serve an allocation like `GlobalAlloc::alloc()`, but without reporting
+    // failures. Successful allocations are recorded in the statistics and
+    // passed to the observer, so they stay balanced with the deallocation
+    // path. Failures invoke neither the out-of-memory hook, nor the observer
+    // or out-of-memory handler, but are left to the caller.
+    unsafe fn alloc_unreported(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let ptr = self.alloc_guarded(layout);
+
+        if !ptr.is_null() {
+            #[cfg(feature = "stats")]
+            self.stats.record_alloc(layout.size());
+            if let Some(observer) = self.observer {
+                observer.on_alloc(ptr, layout);
+            }
+        }
+
+        ptr
+    }
+
+    unsafe fn raw_critical<R>(
+        &self,
+        ptr: *mut crate::alloc::Allocator,
+        f: impl FnOnce() -> R,
+    ) -> R {
+        // Run @f with the TPL raised to `TPL_NOTIFY`, using the boot-services
+        // of the allocator @ptr. Event callbacks on this CPU thus cannot
+        // interrupt @f, and never observe a half-completed transition of the
+        // bridge state. The previous TPL is restored afterwards, even if @f
+        // panics.
+        //
+        // If the caller already runs above `TPL_NOTIFY`, the TPL must not be
+        // lowered. Callbacks that could use the bridge are blocked anyway,
+        // so @f is run without a guard.
+        //
+        // Once the boot-services were exited, the firmware must not be called
+        // anymore. No callbacks run at that point, so @f is run directly.
+        if self.is_exited() {
+            return f();
+        }
+
+        let _tpl = TplGuard::try_raise((*ptr).system_table(), efi::TPL_NOTIFY);
+        f()
+    }
+
+    // Serve an allocation along its route, through the TPL guard and, if
+    // enabled, with a tagged block. This is the allocation path without any
+    // of the hooks.
+    unsafe fn alloc_guarded(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let route = self.route(&layout);
+        match route {
+            Route::Dangling => return layout.align() as *mut u8,
+            Route::Fail => return core::ptr::null_mut(),
+            Route::Pool | Route::Pages => {}
+        }
+
+        match self.raw_tpl_guard() {
+            Err(()) => core::ptr::null_mut(),
+            Ok(_tpl) if route == Route::Pages => self.alloc_pages(layout),
+            Ok(_tpl) if self.generations || self.chained => {
+                self.alloc_tagged(layout)
+            }
+            Ok(_tpl) => self.alloc_backend(layout).0,
+        }
+    }
+
+    // Return the route of a block of @layout. Without a router, everything
+    // is served from the pool. Zero-sized blocks are the only ones that can
+    // be served with a dangling pointer, any other block routed there fails.
+    fn route(&self, layout: &core::alloc::Layout) -> Route {
+        match self.router.map(|v| v(layout)) {
+            None => Route::Pool,
+            Some(Route::Dangling) if layout.size() > 0 => Route::Fail,
+            Some(v) => v,
+        }
+    }
+
+    // Return a page allocator for the system-table and memory-type of the
+    // attached allocator. This yields `None` if nothing is attached, or once
+    // the boot-services were exited.
+    unsafe fn page_allocator(&self) -> Option<crate::alloc::PageAllocator> {
+        let allocator = self.attachment.load(atomic::Ordering::Acquire);
+        if allocator.is_null() || self.is_exited() {
+            return None;
+        }
+
+        Some(crate::alloc::PageAllocator::from_system_table(
+            (*allocator).system_table(),
+            (*allocator).memory_type(),
+        ))
+    }
+
+    // Serve an allocation of `Route::Pages`.
+    unsafe fn alloc_pages(&self, layout: core::alloc::Layout) -> *mut u8 {
+        match self.page_allocator() {
+            Some(pages) => pages.alloc(layout),
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    // Release a block of `Route::Pages`. Once the boot-services were exited,
+    // the block is ignored, like any other block of the firmware.
+    unsafe fn dealloc_pages(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        if let Some(pages) = self.page_allocator() {
+            pages.dealloc(ptr, layout);
+        }
+    }
+
+    // Invoke the out-of-memory hook, if any, for a failed allocation of
+    // @layout. This returns whether the hook asked for a retry.
+    fn raw_oom_hook(&self, layout: core::alloc::Layout) -> bool {
+        let v = self.oom_hook.load(atomic::Ordering::Acquire);
+        if v.is_null() {
+            return false;
+        }
+
+        let hook = unsafe { core::mem::transmute::<*mut (), OomHook>(v) };
+        let allocator = self.attachment.load(atomic::Ordering::Acquire);
+        let status = unsafe { allocator.as_ref() }.and_then(|v| v.last_error());
+        hook(layout, status)
+    }
+
+    unsafe fn raw_tpl_guard(&self) -> Result<Option<TplGuard>, ()> {
+        // Raise the TPL to `TPL_NOTIFY` for a call into the attached
+        // allocator, if enabled via `with_tpl_guard()`. This fails if the
+        // caller runs above `TPL_NOTIFY`, in which case the firmware must not
+        // be called. Without an attachment, or once the boot-services were
+        // exited, there is no firmware to call, and no guard is needed.
+        if !self.tpl_guard || self.is_exited() {
+            return Ok(None);
+        }
+
+        let allocator = self.attachment.load(atomic::Ordering::Acquire);
+        if allocator.is_null() {
+            return Ok(None);
         }
+
+        TplGuard::try_raise((*allocator).system_table(), efi::TPL_NOTIFY)
+            .map(Some)
+            .ok_or(())
     }
 
     unsafe fn raw_attach(&self, ptr: *mut crate::alloc::Allocator) -> Option<()> {
@@ -135,12 +956,14 @@ impl Bridge {
         // This interface is unsafe since the caller must guarantee to detach
         // the bridge before it is destroyed. There are no runtime guarantees
         // given by this interface, it is all left to the caller.
-        let p = self.attachment.compare_exchange(
-            core::ptr::null_mut(),
-            ptr,
-            atomic::Ordering::Release,
-            atomic::Ordering::Relaxed,
-        );
+        let p = self.raw_critical(ptr, || {
+            self.attachment.compare_exchange(
+                core::ptr::null_mut(),
+                ptr,
+                atomic::Ordering::Release,
+                atomic::Ordering::Relaxed,
+            )
+        });
 
         if p.is_ok() {
             Some(())
@@ -157,15 +980,210 @@ impl Bridge {
         // We use compare_exchange() to replace the old attachment with NULL.
         // If it was not NULL, we panic. No ordering guarantees are required,
         // since there is no dependent state.
-        let p = self.attachment.compare_exchange(
-            ptr,
-            core::ptr::null_mut(),
-            atomic::Ordering::Relaxed,
-            atomic::Ordering::Relaxed,
-        );
+        //
+        // Advance the generation, so blocks allocated under this attachment
+        // are detected as stale if they are released later on. This happens
+        // before any new attachment is published, so new blocks are always
+        // tagged with the new generation. Both steps happen at raised TPL,
+        // so no callback can allocate in between.
+        self.raw_critical(ptr, || {
+            let p = self.attachment.compare_exchange(
+                ptr,
+                core::ptr::null_mut(),
+                atomic::Ordering::Relaxed,
+                atomic::Ordering::Relaxed,
+            );
+            assert!(p.is_ok());
+
+            self.generation.fetch_add(1, atomic::Ordering::Relaxed);
+        })
+    }
+
+    unsafe fn raw_swap(
+        &self,
+        old: *mut crate::alloc::Allocator,
+        new: *mut crate::alloc::Allocator,
+    ) {
+        // Replace the attachment @old with @new in a single step, so there is
+        // no window in which allocations find the bridge detached. The
+        // Release pairs with the Acquire in the GlobalAlloc trait, just like
+        // in `raw_attach()`. The caller must guarantee @old is the current
+        // attachment, otherwise this panics.
+        //
+        // The generation is not advanced, since blocks of the old allocator
+        // are meant to be released through the new one.
+        let p = self.raw_critical(new, || {
+            self.attachment.compare_exchange(
+                old,
+                new,
+                atomic::Ordering::Release,
+                atomic::Ordering::Relaxed,
+            )
+        });
         assert!(p.is_ok());
     }
 
+    // Return the layout of a block tagged with its generation and allocator
+    // index, as well as the offset of the user block in it. The tag is placed
+    // in the word right in front of the user block.
+    fn tagged_layout(
+        layout: core::alloc::Layout,
+    ) -> Option<(core::alloc::Layout, usize)> {
+        core::alloc::Layout::new::<usize>().extend(layout).ok()
+    }
+
+    // Serve an allocation from the attached allocator, or the bootstrap
+    // allocator if nothing is attached. Once the boot-services were exited,
+    // the fallback allocator is preferred over the bootstrap allocator. This
+    // returns the block and the index of the allocator that served it (see
+    // `TAG_SHIFT`).
+    unsafe fn alloc_backend(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> (*mut u8, usize) {
+        let allocator = if self.is_exited() {
+            if let Some(fallback) = self.fallback {
+                let ptr = fallback.alloc(layout);
+                if !ptr.is_null() {
+                    return (ptr, 0);
+                }
+            }
+            core::ptr::null_mut()
+        } else {
+            self.attachment.load(atomic::Ordering::Acquire)
+        };
+
+        let ptr = if allocator.is_null() {
+            match self.bootstrap {
+                Some(bootstrap) => bootstrap.alloc(layout),
+                None => core::ptr::null_mut(),
+            }
+        } else {
+            (&*allocator).alloc(layout)
+        };
+
+        // Requests that failed are passed down the chain of secondary
+        // allocators, if any.
+        if ptr.is_null() && self.chained && !self.is_exited() {
+            for (i, slot) in self.secondary.iter().enumerate() {
+                let allocator = slot.load(atomic::Ordering::Acquire);
+                if !allocator.is_null() {
+                    let ptr = (&*allocator).alloc(layout);
+                    if !ptr.is_null() {
+                        return (ptr, i + 1);
+                    }
+                }
+            }
+        }
+
+        // Small requests that failed are served from the reserve, if any.
+        match self.reserve {
+            Some(reserve) if ptr.is_null() => (reserve.alloc(layout), 0),
+            _ => (ptr, 0),
+        }
+    }
+
+    // Release a block to the allocator that served it. Blocks are routed to
+    // the reserve, fallback and bootstrap allocators based on their address,
+    // and to secondary allocators based on the index given as @index. Once
+    // the boot-services were exited, all other blocks are ignored.
+    unsafe fn dealloc_backend(
+        &self,
+        ptr: *mut u8,
+        layout: core::alloc::Layout,
+        index: usize,
+    ) {
+        if let Some(reserve) = self.reserve {
+            if reserve.contains(ptr) {
+                return reserve.dealloc(ptr, layout);
+            }
+        }
+
+        if let Some(fallback) = self.fallback {
+            if fallback.contains(ptr) {
+                return fallback.dealloc(ptr, layout);
+            }
+        }
+
+        if let Some(bootstrap) = self.bootstrap {
+            if bootstrap.contains(ptr) {
+                return bootstrap.dealloc(ptr, layout);
+            }
+        }
+
+        if self.is_exited() {
+            return;
+        }
+
+        let allocator = match index {
+            0 => self.attachment.load(atomic::Ordering::Acquire),
+            i => self.secondary[i - 1].load(atomic::Ordering::Acquire),
+        };
+
+        assert!(!allocator.is_null());
+
+        (&*allocator).dealloc(ptr, layout)
+    }
+
+    // Check whether @ptr points into a block of the reserve, fallback or
+    // bootstrap allocator. These serve memory without calling into the
+    // firmware.
+    fn is_local(&self, ptr: *const u8) -> bool {
+        let reserve = match self.reserve {
+            Some(reserve) => reserve.contains(ptr),
+            None => false,
+        };
+        let fallback = match self.fallback {
+            Some(fallback) => fallback.contains(ptr),
+            None => false,
+        };
+        let bootstrap = match self.bootstrap {
+            Some(bootstrap) => bootstrap.contains(ptr),
+            None => false,
+        };
+        reserve || fallback || bootstrap
+    }
+
+    unsafe fn alloc_tagged(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let (tagged, offset) = match Self::tagged_layout(layout) {
+            Some(v) => v,
+            None => return core::ptr::null_mut(),
+        };
+
+        let (base, index) = self.alloc_backend(tagged);
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = base.add(offset);
+        let generation = self.generation.load(atomic::Ordering::Relaxed);
+        core::ptr::write(
+            (ptr as *mut usize).offset(-1),
+            (generation << TAG_SHIFT) | index,
+        );
+        ptr
+    }
+
+    unsafe fn dealloc_tagged(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        // The layout was already verified by `alloc_tagged()`.
+        let (tagged, offset) = Self::tagged_layout(layout).unwrap();
+        let base = ptr.sub(offset);
+
+        // Blocks of the bootstrap, fallback and reserve allocators are not
+        // tied to an attachment, so their tag carries no meaning.
+        let tag = core::ptr::read((ptr as *mut usize).offset(-1));
+        if self.generations && !self.is_local(base) {
+            let generation = self.generation.load(atomic::Ordering::Relaxed);
+            assert!(
+                tag >> TAG_SHIFT == generation & (usize::MAX >> TAG_SHIFT),
+                "stale memory block {:p} released across re-attach",
+                ptr,
+            );
+        }
+
+        self.dealloc_backend(base, tagged, tag & TAG_INDEX_MASK)
+    }
+
     /// Attach an allocator
     ///
     /// This attaches the allocator given as @allocator to the bridge. If there
@@ -177,10 +1195,17 @@ impl Bridge {
     /// this bridge (via rust's `GlobalAlloc` trait) will be served by this
     /// allocator.
     ///
-    /// This is an unsafe interface. It is the caller's responsibility to
-    /// guarantee that the attachment survives all outstanding allocations.
-    /// That is, any allocated memory must be released before detaching the
-    /// allocator.
+    /// Attaching, detaching and swapping allocators raises the TPL to
+    /// `TPL_NOTIFY` while the bridge is updated, so event callbacks never
+    /// observe a half-completed transition. Hence, these operations must not
+    /// be used above `TPL_NOTIFY`.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// It is the caller's responsibility to guarantee that the attachment
+    /// survives all outstanding allocations. That is, any allocated memory
+    /// must be released before detaching the allocator.
     pub unsafe fn attach<'alloc, 'bridge>(
         &'bridge self,
         allocator: &'alloc mut crate::alloc::Allocator,
@@ -193,6 +1218,391 @@ impl Bridge {
             }),
         }
     }
+
+    /// Attach a secondary allocator
+    ///
+    /// This attaches the allocator given as @allocator to the first free
+    /// secondary slot of the bridge. Requests that the primary attachment
+    /// cannot serve are passed to the secondary allocators in order of their
+    /// slots, and blocks are released to the allocator that served them. This
+    /// yields `None` if the bridge was not created with `with_chain()`, or
+    /// if all `SECONDARY_SLOTS` slots are taken. Dropping the returned
+    /// attachment detaches the allocator again.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `attach()` apply. That is, all
+    /// memory served by the secondary allocator must be released before it
+    /// is detached.
+    pub unsafe fn attach_secondary<'alloc, 'bridge>(
+        &'bridge self,
+        allocator: &'alloc mut crate::alloc::Allocator,
+    ) -> Option<SecondaryAttachment<'alloc, 'bridge>> {
+        if !self.chained {
+            return None;
+        }
+
+        // Publish the allocator with Release semantics, like
+        // `raw_attach()` does for the primary attachment. The TPL is raised
+        // for the update, so event callbacks never interleave with it.
+        let ptr = allocator as *mut crate::alloc::Allocator;
+        let slot = self.raw_critical(ptr, || {
+            self.secondary.iter().position(|v| {
+                v.compare_exchange(
+                    core::ptr::null_mut(),
+                    ptr,
+                    atomic::Ordering::Release,
+                    atomic::Ordering::Relaxed,
+                )
+                .is_ok()
+            })
+        })?;
+
+        Some(SecondaryAttachment {
+            allocator,
+            bridge: self,
+            slot,
+        })
+    }
+
+    /// Run Closure with Attached Allocator
+    ///
+    /// This attaches the allocator given as @allocator to the bridge, like
+    /// `attach()`, invokes @f, and detaches the allocator again before it
+    /// returns the result of @f. Unlike with `attach()`, the attachment is
+    /// never exposed to the caller, so it cannot be leaked or forgotten. If
+    /// @f unwinds, the allocator is detached while unwinding, so no dangling
+    /// allocator is ever left attached to the bridge. (Without unwinding, a
+    /// panic aborts the image, and the bridge is never used again.)
+    ///
+    /// If there is an allocator attached already, @f is not invoked and
+    /// `None` is returned.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// It is the caller's responsibility to guarantee that all memory
+    /// allocated through the bridge while @f runs is released before @f
+    /// returns.
+    pub unsafe fn with_attached<R>(
+        &self,
+        allocator: &mut crate::alloc::Allocator,
+        f: impl FnOnce() -> R,
+    ) -> Option<R> {
+        let attachment = self.attach(allocator)?;
+        let r = f();
+
+        drop(attachment);
+        Some(r)
+    }
+
+    /// Attach a static allocator
+    ///
+    /// This attaches the allocator given as @allocator to the bridge, just
+    /// like `attach()`. However, both the bridge and the allocator must be
+    /// static, and the allocator is borrowed exclusively for the rest of the
+    /// lifetime of the image. Hence, the attachment can be kept beyond the
+    /// entry-point, and there is no requirement to release allocations
+    /// before the entry-point returns.
+    ///
+    /// If there is an allocator attached already, this yields back the
+    /// passed allocator as error.
+    ///
+    /// A mutable static reference can be obtained from a `static mut` or a
+    /// leaked allocation, for instance. The caller must still guarantee that
+    /// the system-table of the allocator stays valid for as long as it is
+    /// attached.
+    pub fn attach_static(
+        &'static self,
+        allocator: &'static mut crate::alloc::Allocator,
+    ) -> Result<StaticAttachment, &'static mut crate::alloc::Allocator> {
+        match unsafe { self.raw_attach(allocator) } {
+            None => Err(allocator),
+            Some(()) => Ok(StaticAttachment {
+                allocator,
+                bridge: self,
+            }),
+        }
+    }
+
+    /// Allocate optional memory
+    ///
+    /// This allocates memory through the bridge, just like the `GlobalAlloc`
+    /// trait does, but reports failure as `None` rather than a null-pointer.
+    /// This is meant for allocations that are truly optional (e.g., caches),
+    /// where the caller can silently do without the memory. Unlike the
+    /// collections of the standard library, the caller never routes such a
+    /// failure into `handle_alloc_error()`.
+    ///
+    /// Failures are silent. Neither the out-of-memory handler nor the
+    /// failure notification of the observer are invoked for them.
+    ///
+    /// If no allocator is attached to the bridge, this yields `None`.
+    /// Zero-sized requests are served with a dangling, suitably aligned
+    /// pointer without involving the allocator.
+    ///
+    /// Any non-zero-sized allocation returned by this function must be
+    /// released via `GlobalAlloc::dealloc()` on this bridge, with the same
+    /// layout as passed here. Dangling pointers of zero-sized requests must
+    /// not be passed to the bridge.
+    pub fn try_alloc_optional(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Option<core::ptr::NonNull<[u8]>> {
+        let size = layout.size();
+
+        let ptr = if size > 0 {
+            unsafe { self.alloc_unreported(layout) }
+        } else {
+            layout.align() as *mut u8
+        };
+
+        core::ptr::NonNull::new(
+            core::ptr::slice_from_raw_parts_mut(ptr, size),
+        )
+    }
+
+    /// Run Entry-Point with Attached Allocator
+    ///
+    /// Create an allocator for the image given as @handle (see
+    /// `Allocator::from_image_handle()`), and invoke @main with @handle and
+    /// @st while it is attached (see `with_attached()`). The allocator is
+    /// detached again once @main returns or unwinds, and the status code of
+    /// @main is returned. If the `LoadedImage` protocol of the image cannot
+    /// be queried, `LOADER_DATA` is used as memory type.
+    ///
+    /// If an allocator is attached already, @main is not invoked and
+    /// `ALREADY_STARTED` is returned. This is what the `entry!()` macro uses
+    /// to implement the UEFI entry-point.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same requirements as for `attach()` apply, so all global
+    /// allocations must be released before @main returns. Furthermore, @st
+    /// must be the valid system-table of the image.
+    pub unsafe fn run(
+        &self,
+        handle: efi::Handle,
+        st: *mut efi::SystemTable,
+        main: fn(efi::Handle, *mut efi::SystemTable) -> efi::Status,
+    ) -> efi::Status {
+        let mut allocator =
+            match crate::alloc::Allocator::from_image_handle(handle, st) {
+                Ok(v) => v,
+                Err(_) => crate::alloc::Allocator::from_system_table(
+                    st,
+                    efi::LOADER_DATA,
+                ),
+            };
+
+        self.with_attached(&mut allocator, || main(handle, st))
+            .unwrap_or(efi::Status::ALREADY_STARTED)
+    }
+}
+
+// Notification function of the event created by `watch_exit_boot_services()`.
+// The context is the static bridge to mark as exited.
+extern "efiapi" fn exit_boot_services_notify(
+    _event: efi::Event,
+    context: *mut core::ffi::c_void,
+) {
+    let bridge = unsafe { &*(context as *const Bridge) };
+
+    bridge.set_exited();
+}
+
+/// Iterate Registered Bridges
+///
+/// Return an iterator over all bridges registered via `Bridge::register()`,
+/// most recently registered first.
+pub fn registry() -> impl Iterator<Item = &'static Bridge> {
+    let head = REGISTRY.load(atomic::Ordering::Acquire);
+
+    // Registered bridges are static and never unlinked, so any pointer in
+    // the list is valid forever.
+    core::iter::successors(unsafe { head.as_ref() }, |v| unsafe {
+        v.next.load(atomic::Ordering::Acquire).as_ref()
+    })
+}
+
+/// Return the Current Allocator
+///
+/// Return a handle to the bridge or cell installed via `Bridge::install()`
+/// or `GlobalAllocatorCell::install()`. This yields `None` if nothing was
+/// installed, or if no allocator is attached to the installed bridge or set
+/// in the installed cell.
+///
+/// This is meant for library crates that need an allocator, but must not
+/// require their users to register a global allocator. The handle stays
+/// usable if the allocator is detached later on, but allocations fail then.
+/// This is only available with the `current` feature.
+#[cfg(feature = "current")]
+pub fn current() -> Option<Current> {
+    let bridge = unsafe {
+        CURRENT_BRIDGE.load(atomic::Ordering::Acquire).as_ref()
+    };
+    let cell = unsafe { CURRENT_CELL.load(atomic::Ordering::Acquire).as_ref() };
+
+    // Installed bridges and cells are static, so any pointer is valid
+    // forever.
+    let global: &'static (dyn core::alloc::GlobalAlloc + Sync) =
+        match (bridge, cell) {
+            (Some(bridge), _) if bridge.is_attached() => bridge,
+            (_, Some(cell)) if cell.get().is_some() => cell,
+            _ => return None,
+        };
+
+    Some(Current { global })
+}
+
+/// Assert the Current Allocator can serve allocations
+///
+/// Panic with a descriptive message if `current()` would yield `None`, or
+/// if the installed bridge cannot serve allocations (see
+/// `Bridge::assert_ready()`). This is meant for library crates, which do
+/// not know the bridge of the application, and is usually called via
+/// `assert_ready!()`. This is only available with the `current` feature.
+#[cfg(feature = "current")]
+#[track_caller]
+pub fn assert_current_ready() {
+    let bridge = unsafe {
+        CURRENT_BRIDGE.load(atomic::Ordering::Acquire).as_ref()
+    };
+    let cell = unsafe { CURRENT_CELL.load(atomic::Ordering::Acquire).as_ref() };
+
+    match (bridge, cell) {
+        (Some(bridge), _) => bridge.assert_ready(),
+        (_, Some(cell)) => assert!(
+            cell.get().is_some(),
+            "global allocator cell is installed, but not set",
+        ),
+        _ => panic!(
+            "no global allocator installed, the application must install \
+             its bridge via `Bridge::install()`",
+        ),
+    }
+}
+
+/// Assert a Global Allocator is Ready
+///
+/// Verify that a global allocator can serve allocations, and panic with a
+/// descriptive message otherwise. With an argument, this checks the given
+/// bridge via `Bridge::assert_ready()`. Without arguments, this checks the
+/// allocator installed via `Bridge::install()` or
+/// `GlobalAllocatorCell::install()` via `assert_current_ready()`, which
+/// requires the `current` feature.
+///
+/// This is opt-in, and meant to be used at the top of library entry-points,
+/// so a missing allocator is reported right away rather than as a failed
+/// allocation inside some dependency.
+#[macro_export]
+macro_rules! assert_ready {
+    () => {
+        $crate::global::assert_current_ready()
+    };
+    ($bridge:expr $(,)?) => {
+        $crate::global::Bridge::assert_ready(&$bridge)
+    };
+}
+
+// Make the macro available under the path of this module as well.
+pub use crate::assert_ready;
+
+#[cfg(feature = "current")]
+impl Current {
+    /// Allocate memory through the current allocator
+    ///
+    /// This behaves like `GlobalAlloc::alloc()` of the installed bridge or
+    /// cell.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `GlobalAlloc::alloc()` apply.
+    pub unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.global.alloc(layout)
+    }
+
+    /// Deallocate memory through the current allocator
+    ///
+    /// This behaves like `GlobalAlloc::dealloc()` of the installed bridge or
+    /// cell.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `GlobalAlloc::dealloc()` apply.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        self.global.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(all(feature = "allocator_api", feature = "current"))]
+unsafe impl core::alloc::Allocator for Current {
+    fn allocate(
+        &self,
+        layout: core::alloc::Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let size = layout.size();
+
+        let ptr = if size > 0 {
+            unsafe { Current::alloc(self, layout) }
+        } else {
+            layout.dangling().as_ptr() as *mut _
+        };
+
+        if ptr.is_null() {
+            Err(core::alloc::AllocError)
+        } else {
+            Ok(
+                core::ptr::NonNull::new(
+                    core::ptr::slice_from_raw_parts(ptr, size) as *mut _,
+                ).unwrap(),
+            )
+        }
+    }
+
+    unsafe fn deallocate(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        layout: core::alloc::Layout,
+    ) {
+        if layout.size() != 0 {
+            Current::dealloc(self, ptr.as_ptr(), layout)
+        }
+    }
+}
+
+/// Dump the Bridge Registry
+///
+/// Write a human-readable description of all registered bridges to @w, one
+/// line per bridge. For each bridge, its name, its address, whether an
+/// allocator is attached, and whether a bootstrap allocator is registered
+/// are shown. This is meant for diagnostics, in case global allocations fail
+/// unexpectedly.
+pub fn dump_registry(w: &mut dyn core::fmt::Write) -> core::fmt::Result {
+    for bridge in registry() {
+        writeln!(
+            w,
+            "bridge '{}' at {:p}: {}{}",
+            bridge.name().unwrap_or("<unnamed>"),
+            bridge,
+            if bridge.is_attached() {
+                "attached"
+            } else {
+                "detached"
+            },
+            if bridge.bootstrap.is_some() {
+                ", with bootstrap"
+            } else {
+                ""
+            },
+        )?;
+    }
+
+    Ok(())
 }
 
 impl<'alloc, 'bridge> Drop for Attachment<'alloc, 'bridge> {
@@ -203,12 +1613,240 @@ impl<'alloc, 'bridge> Drop for Attachment<'alloc, 'bridge> {
     }
 }
 
+impl TplGuard {
+    // Raise the TPL of the boot-services of @system_table to @tpl, unless
+    // the current TPL exceeds @tpl. In that case, the TPL is left unchanged
+    // and `None` is returned. The caller must guarantee the boot-services
+    // can be called. Firmware provides no way to query the TPL, so this
+    // raises it to `TPL_HIGH_LEVEL` and then lowers it to @tpl, if allowed.
+    unsafe fn try_raise(
+        system_table: *mut efi::SystemTable,
+        tpl: efi::Tpl,
+    ) -> Option<TplGuard> {
+        let boot_services = (*system_table).boot_services;
+        let current = ((*boot_services).raise_tpl)(efi::TPL_HIGH_LEVEL);
+        if current > tpl {
+            ((*boot_services).restore_tpl)(current);
+            return None;
+        }
+
+        ((*boot_services).restore_tpl)(tpl);
+        Some(TplGuard {
+            boot_services,
+            tpl: current,
+        })
+    }
+}
+
+impl Drop for TplGuard {
+    fn drop(&mut self) {
+        unsafe { ((*self.boot_services).restore_tpl)(self.tpl) };
+    }
+}
+
+impl<'alloc, 'bridge> Drop for SecondaryAttachment<'alloc, 'bridge> {
+    fn drop(&mut self) {
+        let ptr = &mut *self.allocator as *mut crate::alloc::Allocator;
+        let slot = &self.bridge.secondary[self.slot];
+        let p = unsafe {
+            self.bridge.raw_critical(ptr, || {
+                slot.compare_exchange(
+                    ptr,
+                    core::ptr::null_mut(),
+                    atomic::Ordering::Relaxed,
+                    atomic::Ordering::Relaxed,
+                )
+            })
+        };
+        assert!(p.is_ok());
+    }
+}
+
+/// Current Global Allocator
+///
+/// This is a handle to the global allocator installed via `Bridge::install()`
+/// or `GlobalAllocatorCell::install()`, as returned by `current()`. It
+/// forwards all requests to the installed bridge or cell, with the same
+/// semantics as global allocations. With the `allocator_api` feature, it
+/// implements the `core::alloc::Allocator` trait.
+///
+/// This type is only available with the `current` feature.
+#[cfg(feature = "current")]
+#[derive(Clone, Copy)]
+pub struct Current {
+    global: &'static (dyn core::alloc::GlobalAlloc + Sync),
+}
+
+impl<'alloc, 'bridge> Attachment<'alloc, 'bridge> {
+    /// Swap the attached allocator
+    ///
+    /// Replace the allocator of this attachment with the allocator given as
+    /// @allocator, and return the previously attached allocator. The bridge
+    /// is switched over atomically, so concurrent global allocations are
+    /// served by either allocator, but never fail because the bridge is
+    /// detached in between.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// Blocks allocated before the swap are released through the new
+    /// allocator. The caller must guarantee both
+    /// allocators can release blocks of each other. This is the case for
+    /// any two `Allocator` objects on the same system-table, since
+    /// `FreePool()` does not depend on the memory type.
+    pub unsafe fn swap(
+        &mut self,
+        allocator: &'alloc mut crate::alloc::Allocator,
+    ) -> &'alloc mut crate::alloc::Allocator {
+        self.bridge.raw_swap(&mut *self.allocator, allocator);
+        core::mem::replace(&mut self.allocator, allocator)
+    }
+}
+
+impl StaticAttachment {
+    /// Swap the attached allocator
+    ///
+    /// Replace the allocator of this attachment with the allocator given as
+    /// @allocator, and return the previously attached allocator. See
+    /// `Attachment::swap()` for details.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The same safety requirements as for `Attachment::swap()` apply.
+    pub unsafe fn swap(
+        &mut self,
+        allocator: &'static mut crate::alloc::Allocator,
+    ) -> &'static mut crate::alloc::Allocator {
+        self.bridge.raw_swap(self.allocator, allocator);
+        &mut *core::mem::replace(&mut self.allocator, allocator)
+    }
+
+    /// Leak the attachment
+    ///
+    /// Consume the attachment without detaching the allocator. The allocator
+    /// stays attached to the bridge for the rest of the lifetime of the
+    /// image.
+    pub fn leak(self) {
+        core::mem::forget(self);
+    }
+
+    /// Detach the allocator
+    ///
+    /// Detach the allocator from the bridge and return it to the caller.
+    /// This is meant to be used in unload handlers of drivers, once all
+    /// global allocations were released.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// It is the caller's responsibility to guarantee that all memory
+    /// allocated through the bridge while the allocator was attached has been
+    /// released.
+    pub unsafe fn detach(self) -> &'static mut crate::alloc::Allocator {
+        let this = core::mem::ManuallyDrop::new(self);
+        let allocator = this.allocator;
+
+        this.bridge.raw_detach(allocator);
+        &mut *allocator
+    }
+}
+
+impl Drop for StaticAttachment {
+    fn drop(&mut self) {
+        unsafe {
+            self.bridge.raw_detach(self.allocator);
+        }
+    }
+}
+
+impl GlobalAllocatorCell {
+    /// Create Empty Cell
+    ///
+    /// Create a new cell with no allocator set. This is a constant function,
+    /// so the cell can be used to initialize a static variable annotated as
+    /// `#[global_allocator]`.
+    pub const fn new() -> GlobalAllocatorCell {
+        GlobalAllocatorCell {
+            allocator: atomic::AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Set the allocator
+    ///
+    /// Set the allocator given as @allocator as the allocator of this cell.
+    /// This only succeeds on the first call. Any further call yields back the
+    /// passed allocator as error, and leaves the cell unchanged.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The caller must guarantee that the allocator remains usable (i.e., its
+    /// system-table and boot-services remain valid) for as long as global
+    /// allocations are performed.
+    pub unsafe fn set(
+        &self,
+        allocator: &'static crate::alloc::Allocator,
+    ) -> Result<(), &'static crate::alloc::Allocator> {
+        // We use Release semantics, so any stores to the allocator are
+        // visible once the cell is set. This pairs with the Acquire in the
+        // `GlobalAlloc` implementation below. On error, no ordering is
+        // required, since nothing was published.
+        self.allocator
+            .compare_exchange(
+                core::ptr::null_mut(),
+                allocator as *const _ as *mut _,
+                atomic::Ordering::Release,
+                atomic::Ordering::Relaxed,
+            )
+            .map(|_| ())
+            .map_err(|_| allocator)
+    }
+
+    /// Install as current allocator
+    ///
+    /// Install this cell as the allocator returned by `current()`. See
+    /// `Bridge::install()` for details. This is only available with the
+    /// `current` feature.
+    #[cfg(feature = "current")]
+    pub fn install(&'static self) -> bool {
+        if CURRENT_SET.swap(true, atomic::Ordering::Relaxed) {
+            return false;
+        }
+
+        let this = self as *const GlobalAllocatorCell as *mut _;
+        CURRENT_CELL.store(this, atomic::Ordering::Release);
+        true
+    }
+
+    /// Get the allocator
+    ///
+    /// Return the allocator of this cell, or `None` if it was not set, yet.
+    pub fn get(&self) -> Option<&'static crate::alloc::Allocator> {
+        let allocator = self.allocator.load(atomic::Ordering::Acquire);
+
+        // Only `set()` stores into the cell, and it requires a `'static`
+        // reference. Hence, any non-NULL value is valid forever.
+        unsafe { allocator.as_ref() }
+    }
+}
+
+impl Default for GlobalAllocatorCell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // This implements GlobalAlloc for our bridge. This trait is used by the rust
 // ecosystem to serve global memory allocations. For this to work, you must
 // have a bridge as static variable annotated as `#[global_allocator]`.
 //
 // We simply forward all allocation requests to the attached allocator. If the
-// allocator is NULL, we fail the allocations.
+// allocator is NULL, we fall back to the bootstrap allocator, if any, or fail
+// the allocations. Deallocations are routed to the bootstrap allocator based
+// on the address of the block. The observer and out-of-memory handler of the
+// bridge, if any, are invoked for all requests. Failed requests are retried
+// once, if the out-of-memory hook asks for it.
 //
 // Note that the bridge interface must guarantee that an attachment survives
 // all allocations. That is, you must drop/deallocate all memory before
@@ -216,20 +1854,1063 @@ impl<'alloc, 'bridge> Drop for Attachment<'alloc, 'bridge> {
 // details.
 unsafe impl core::alloc::GlobalAlloc for Bridge {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let allocator = self.attachment.load(atomic::Ordering::Acquire);
+        let mut ptr = self.alloc_unreported(layout);
+        if ptr.is_null() && self.raw_oom_hook(layout) {
+            ptr = self.alloc_unreported(layout);
+        }
 
-        if allocator.is_null() {
-            return core::ptr::null_mut();
+        if ptr.is_null() {
+            #[cfg(feature = "stats")]
+            self.stats.record_failure();
+            if let Some(observer) = self.observer {
+                observer.on_failure(layout);
+            }
+            if let Some(handler) = self.oom_handler {
+                handler(layout);
+            }
         }
 
-        (&*allocator).alloc(layout)
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let allocator = self.attachment.load(atomic::Ordering::Acquire);
+        // Blocks of the firmware released above `TPL_NOTIFY` are leaked, and
+        // thus still accounted as live. They are reported as leaks instead,
+        // so they do not go unnoticed. All other blocks never touch the
+        // firmware, and are released regardless of the TPL.
+        let route = self.route(&layout);
+        let _tpl = if route == Route::Dangling || self.is_local(ptr) {
+            None
+        } else {
+            match self.raw_tpl_guard() {
+                Ok(v) => v,
+                Err(()) => {
+                    #[cfg(feature = "stats")]
+                    self.stats.record_leak();
+                    if let Some(observer) = self.observer {
+                        observer.on_leak(ptr, layout);
+                    }
+                    return;
+                }
+            }
+        };
 
-        assert!(!allocator.is_null());
+        #[cfg(feature = "stats")]
+        self.stats.record_dealloc(layout.size());
+        if let Some(observer) = self.observer {
+            observer.on_dealloc(ptr, layout);
+        }
 
-        (&*allocator).dealloc(ptr, layout)
+        match route {
+            Route::Dangling | Route::Fail => {}
+            Route::Pages => self.dealloc_pages(ptr, layout),
+            Route::Pool if self.generations || self.chained => {
+                self.dealloc_tagged(ptr, layout)
+            }
+            Route::Pool => self.dealloc_backend(ptr, layout, 0),
+        }
+    }
+}
+
+// This implements GlobalAlloc for the set-once cell. Unlike the bridge, the
+// allocator can never be unset again, so any memory allocated through the
+// cell can be released at any time.
+unsafe impl core::alloc::GlobalAlloc for GlobalAllocatorCell {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        match self.get() {
+            None => core::ptr::null_mut(),
+            Some(allocator) => allocator.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        // Blocks can only have been allocated once the cell was set, and a
+        // set cell cannot be cleared again.
+        match self.get() {
+            None => panic!("deallocation through an unset allocator cell"),
+            Some(allocator) => allocator.dealloc(ptr, layout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify that closures run with the allocator attached, and that it is
+    // detached afterwards, even if the closure panics.
+    #[test]
+    fn with_attached() {
+        static BRIDGE: Bridge = Bridge::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(st, efi::LOADER_DATA)
+        };
+        let mut other = allocator.clone();
+
+        let r = unsafe {
+            BRIDGE.with_attached(&mut allocator, || {
+                assert!(BRIDGE.is_attached());
+                BRIDGE.with_attached(&mut other, || ())
+            })
+        };
+        assert_eq!(r, Some(None));
+        assert!(!BRIDGE.is_attached());
+
+        let mut allocator = std::panic::AssertUnwindSafe(allocator);
+        let r = std::panic::catch_unwind(move || unsafe {
+            BRIDGE.with_attached(&mut allocator.0, || panic!("closure failed"))
+        });
+        assert!(r.is_err());
+        assert!(!BRIDGE.is_attached());
+    }
+
+    // Verify that the entry-point runs with an attached allocator of the
+    // data type of the image, and that it is detached again on return and
+    // on panic.
+    #[test]
+    fn run() {
+        static BRIDGE: Bridge = Bridge::new();
+
+        fn main(_h: efi::Handle, _st: *mut efi::SystemTable) -> efi::Status {
+            assert!(BRIDGE.is_attached());
+
+            let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+            unsafe {
+                let ptr = core::alloc::GlobalAlloc::alloc(&BRIDGE, layout);
+                assert!(!ptr.is_null());
+                assert_eq!(
+                    crate::mock::pool_memory_type(),
+                    Some(efi::BOOT_SERVICES_DATA),
+                );
+                core::alloc::GlobalAlloc::dealloc(&BRIDGE, ptr, layout);
+            }
+            efi::Status::ABORTED
+        }
+
+        fn fail(_h: efi::Handle, _st: *mut efi::SystemTable) -> efi::Status {
+            panic!("entry-point failed");
+        }
+
+        let mut fw = crate::mock::Firmware::new();
+        fw.set_image_data_type(efi::BOOT_SERVICES_DATA);
+        let (h, st) = (fw.image_handle(), fw.system_table());
+
+        let r = unsafe { BRIDGE.run(h, st, main) };
+        assert_eq!(r.as_usize(), efi::Status::ABORTED.as_usize());
+        assert!(!BRIDGE.is_attached());
+
+        let r = std::panic::catch_unwind(|| unsafe { BRIDGE.run(h, st, fail) });
+        assert!(r.is_err());
+        assert!(!BRIDGE.is_attached());
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that registered bridges show up in the registry exactly once,
+    // and that their state is reflected in the dump.
+    #[test]
+    fn registry_dump() {
+        static BRIDGE: Bridge = Bridge::new().with_name("test-registry");
+
+        assert!(BRIDGE.register());
+        assert!(!BRIDGE.register());
+
+        let n = registry()
+            .filter(|v| core::ptr::eq(*v, &BRIDGE))
+            .count();
+        assert_eq!(n, 1);
+
+        let mut fw = crate::mock::Firmware::new();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+
+        let mut s = std::string::String::new();
+        dump_registry(&mut s).unwrap();
+        assert!(s.contains("bridge 'test-registry' at "));
+        assert!(s.contains(": detached\n"));
+
+        let attachment = unsafe { BRIDGE.attach(&mut allocator) };
+        assert!(BRIDGE.is_attached());
+        s.clear();
+        dump_registry(&mut s).unwrap();
+        assert!(s.contains(": attached\n"));
+
+        drop(attachment);
+        assert!(!BRIDGE.is_attached());
+    }
+
+    // Verify that observers and out-of-memory handlers can be configured at
+    // compile-time, and are invoked for allocations through the bridge.
+    #[test]
+    fn const_hooks() {
+        use core::alloc::GlobalAlloc;
+
+        struct Counter(atomic::AtomicUsize, atomic::AtomicUsize);
+
+        impl crate::observe::AllocObserver for Counter {
+            fn on_alloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+                self.0.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+
+            fn on_dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+                self.0.fetch_sub(1, atomic::Ordering::Relaxed);
+            }
+
+            fn on_failure(&self, _layout: core::alloc::Layout) {
+                self.1.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        fn oom(_layout: core::alloc::Layout) {
+            OOM.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        static COUNTER: Counter = Counter(
+            atomic::AtomicUsize::new(0),
+            atomic::AtomicUsize::new(0),
+        );
+        static OOM: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        static BRIDGE: Bridge = Bridge::new()
+            .with_observer(&COUNTER)
+            .with_oom_handler(oom);
+
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        // Without an attached allocator, the request fails and both the
+        // observer and the handler are notified.
+        assert!(unsafe { BRIDGE.alloc(layout) }.is_null());
+        assert_eq!(COUNTER.1.load(atomic::Ordering::Relaxed), 1);
+        assert_eq!(OOM.load(atomic::Ordering::Relaxed), 1);
+
+        // Optional allocations fail silently.
+        assert!(BRIDGE.try_alloc_optional(layout).is_none());
+        assert_eq!(COUNTER.1.load(atomic::Ordering::Relaxed), 1);
+        assert_eq!(OOM.load(atomic::Ordering::Relaxed), 1);
+
+        let mut fw = crate::mock::Firmware::new();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let attachment = unsafe { BRIDGE.attach(&mut allocator) };
+
+        let ptr = unsafe { BRIDGE.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(COUNTER.0.load(atomic::Ordering::Relaxed), 1);
+        unsafe { BRIDGE.dealloc(ptr, layout) };
+        assert_eq!(COUNTER.0.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(OOM.load(atomic::Ordering::Relaxed), 1);
+
+        drop(attachment);
+    }
+
+    // Verify that the out-of-memory hook sees the firmware status of failed
+    // allocations, and that they are retried if the hook reclaimed memory.
+    #[test]
+    fn oom_hook() {
+        use core::alloc::GlobalAlloc;
+
+        fn reclaim(
+            _layout: core::alloc::Layout,
+            status: Option<r_efi::efi::Status>,
+        ) -> bool {
+            assert_eq!(status, Some(r_efi::efi::Status::OUT_OF_RESOURCES));
+            crate::mock::pool_fail(None);
+            true
+        }
+
+        fn give_up(
+            _layout: core::alloc::Layout,
+            _status: Option<r_efi::efi::Status>,
+        ) -> bool {
+            GIVE_UP.fetch_add(1, atomic::Ordering::Relaxed);
+            false
+        }
+
+        static GIVE_UP: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+        static BRIDGE: Bridge = Bridge::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        let _attachment = unsafe { BRIDGE.attach(&mut allocator) };
+
+        assert!(BRIDGE.set_oom_hook(Some(reclaim)).is_none());
+        crate::mock::pool_fail(Some(r_efi::efi::Status::OUT_OF_RESOURCES));
+        let ptr = unsafe { BRIDGE.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { BRIDGE.dealloc(ptr, layout) };
+
+        assert!(BRIDGE.set_oom_hook(Some(give_up)).is_some());
+        crate::mock::pool_fail(Some(r_efi::efi::Status::OUT_OF_RESOURCES));
+        assert!(unsafe { BRIDGE.alloc(layout) }.is_null());
+        assert_eq!(GIVE_UP.load(atomic::Ordering::Relaxed), 1);
+
+        // Optional allocations never invoke the hook.
+        assert!(BRIDGE.try_alloc_optional(layout).is_none());
+        assert_eq!(GIVE_UP.load(atomic::Ordering::Relaxed), 1);
+        crate::mock::pool_fail(None);
+
+        assert!(BRIDGE.set_oom_hook(None).is_some());
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that static allocators can be attached beyond the scope of the
+    // caller, and detached again explicitly.
+    #[test]
+    fn attach_static() {
+        use core::alloc::GlobalAlloc;
+
+        static BRIDGE: Bridge = Bridge::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let allocator = std::boxed::Box::leak(std::boxed::Box::new(unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        }));
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let attachment = BRIDGE.attach_static(allocator).ok().unwrap();
+        assert!(BRIDGE.is_attached());
+
+        let ptr = unsafe { BRIDGE.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { BRIDGE.dealloc(ptr, layout) };
+
+        let allocator = unsafe { attachment.detach() };
+        assert!(!BRIDGE.is_attached());
+
+        // A leaked attachment stays in place.
+        let attachment = BRIDGE.attach_static(allocator).ok().unwrap();
+        attachment.leak();
+        assert!(BRIDGE.is_attached());
+    }
+
+    // Verify that swapping the allocator of an attachment redirects new
+    // allocations, and that old blocks are released through the new one.
+    #[test]
+    fn swap() {
+        use core::alloc::GlobalAlloc;
+
+        static BRIDGE: Bridge = Bridge::new().with_generations();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let (mut a0, mut a1) = unsafe {
+            (
+                crate::alloc::Allocator::from_system_table(
+                    st,
+                    r_efi::efi::LOADER_DATA,
+                ),
+                crate::alloc::Allocator::from_system_table(
+                    st,
+                    r_efi::efi::BOOT_SERVICES_DATA,
+                ),
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let mut attachment = unsafe { BRIDGE.attach(&mut a0) }.unwrap();
+        let p0 = unsafe { BRIDGE.alloc(layout) };
+
+        let old = unsafe { attachment.swap(&mut a1) };
+        assert_eq!(old.last_error(), None);
+        let p1 = unsafe { BRIDGE.alloc(layout) };
+        assert_eq!(
+            crate::mock::pool_memory_type(),
+            Some(r_efi::efi::BOOT_SERVICES_DATA),
+        );
+
+        unsafe { BRIDGE.dealloc(p0, layout) };
+        unsafe { BRIDGE.dealloc(p1, layout) };
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        // Releasing blocks of the old allocator leaves the new one usable.
+        let p2 = unsafe { BRIDGE.alloc(layout) };
+        assert!(!p2.is_null());
+        unsafe { BRIDGE.dealloc(p2, layout) };
+
+        drop(attachment);
+        assert!(!BRIDGE.is_attached());
+    }
+
+    // Verify that attach, swap and detach each raise the TPL for the update
+    // of the bridge, and restore it afterwards. The same applies to the
+    // secondary slots.
+    #[test]
+    fn tpl() {
+        static BRIDGE: Bridge = Bridge::new().with_chain();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let (mut a0, mut a1) = unsafe {
+            (
+                crate::alloc::Allocator::from_system_table(
+                    st,
+                    r_efi::efi::LOADER_DATA,
+                ),
+                crate::alloc::Allocator::from_system_table(
+                    st,
+                    r_efi::efi::BOOT_SERVICES_DATA,
+                ),
+            )
+        };
+
+        let mut attachment = unsafe { BRIDGE.attach(&mut a0) }.unwrap();
+        assert_eq!(crate::mock::tpl_raises(), 1);
+        assert_eq!(crate::mock::tpl(), r_efi::efi::TPL_APPLICATION);
+
+        unsafe { attachment.swap(&mut a1) };
+        assert_eq!(crate::mock::tpl_raises(), 2);
+        assert_eq!(crate::mock::tpl(), r_efi::efi::TPL_APPLICATION);
+
+        drop(attachment);
+        assert_eq!(crate::mock::tpl_raises(), 3);
+        assert_eq!(crate::mock::tpl(), r_efi::efi::TPL_APPLICATION);
+
+        let secondary = unsafe { BRIDGE.attach_secondary(&mut a0) }.unwrap();
+        assert_eq!(crate::mock::tpl_raises(), 4);
+        assert_eq!(crate::mock::tpl(), r_efi::efi::TPL_APPLICATION);
+
+        drop(secondary);
+        assert_eq!(crate::mock::tpl_raises(), 5);
+        assert_eq!(crate::mock::tpl(), r_efi::efi::TPL_APPLICATION);
+    }
+
+    // Verify that attach and detach work above `TPL_NOTIFY`, without ever
+    // lowering the TPL of the caller.
+    #[test]
+    fn tpl_high() {
+        static BRIDGE: Bridge = Bridge::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+
+        unsafe {
+            let bs = (*st).boot_services;
+            let tpl = ((*bs).raise_tpl)(r_efi::efi::TPL_HIGH_LEVEL);
+
+            let attachment = BRIDGE.attach(&mut allocator).unwrap();
+            assert!(BRIDGE.is_attached());
+            assert_eq!(crate::mock::tpl(), r_efi::efi::TPL_HIGH_LEVEL);
+
+            drop(attachment);
+            assert!(!BRIDGE.is_attached());
+            assert_eq!(crate::mock::tpl(), r_efi::efi::TPL_HIGH_LEVEL);
+
+            ((*bs).restore_tpl)(tpl);
+        }
+
+        assert_eq!(crate::mock::tpl_inversions(), 0);
+    }
+
+    // Verify that a guarded bridge calls into its allocator at `TPL_NOTIFY`,
+    // and refuses to call into it above `TPL_NOTIFY`. Blocks of the firmware
+    // released above `TPL_NOTIFY` are reported as leaks.
+    #[test]
+    fn tpl_guard() {
+        use core::alloc::GlobalAlloc;
+
+        struct Leaks(atomic::AtomicUsize);
+
+        impl crate::observe::AllocObserver for Leaks {
+            fn on_leak(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+                self.0.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        static LEAKS: Leaks = Leaks(atomic::AtomicUsize::new(0));
+        static RESERVE: crate::reserve::Reserve<[crate::reserve::Slot; 1]> =
+            crate::reserve::Reserve::new();
+        static BRIDGE: Bridge = Bridge::new()
+            .with_reserve(&RESERVE)
+            .with_observer(&LEAKS)
+            .with_tpl_guard();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let attachment = unsafe { BRIDGE.attach(&mut allocator) };
+        let p0 = unsafe { BRIDGE.alloc(layout) };
+        assert!(!p0.is_null());
+        assert_eq!(crate::mock::pool_tpl(), r_efi::efi::TPL_NOTIFY);
+        assert_eq!(crate::mock::tpl(), r_efi::efi::TPL_APPLICATION);
+        let p1 = unsafe { BRIDGE.alloc(layout) };
+        assert!(!p1.is_null());
+
+        crate::mock::pool_fail(Some(r_efi::efi::Status::OUT_OF_RESOURCES));
+        let small = core::alloc::Layout::from_size_align(16, 8).unwrap();
+        let p2 = unsafe { BRIDGE.alloc(small) };
+        assert!(RESERVE.contains(p2));
+        crate::mock::pool_fail(None);
+
+        // Run like an event callback at `TPL_HIGH_LEVEL`, where the pool
+        // must not be used.
+        unsafe {
+            let bs = (*st).boot_services;
+            let tpl = ((*bs).raise_tpl)(r_efi::efi::TPL_HIGH_LEVEL);
+            assert!(BRIDGE.alloc(layout).is_null());
+            BRIDGE.dealloc(p0, layout);
+            assert_eq!(crate::mock::pool_live(), 2);
+            assert_eq!(LEAKS.0.load(atomic::Ordering::Relaxed), 1);
+            BRIDGE.dealloc(p2, small);
+            assert_eq!(RESERVE.used(), 0);
+            assert_eq!(LEAKS.0.load(atomic::Ordering::Relaxed), 1);
+            ((*bs).restore_tpl)(tpl);
+        }
+
+        unsafe { BRIDGE.dealloc(p1, layout) };
+        assert_eq!(crate::mock::pool_tpl(), r_efi::efi::TPL_NOTIFY);
+        assert_eq!(crate::mock::pool_live(), 1);
+        drop(attachment);
+
+        // The leaked block is still allocated in the firmware, so reclaim it
+        // directly to keep the fake firmware balanced.
+        unsafe { allocator.dealloc(p0, layout) };
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that a bridge stops calling into its allocator once the
+    // boot-services were exited, but still serves its bootstrap allocator.
+    #[test]
+    fn exit_boot_services() {
+        use core::alloc::GlobalAlloc;
+
+        static BOOTSTRAP: crate::bootstrap::Bootstrap<[u8; 256]> =
+            crate::bootstrap::Bootstrap::new();
+        static BRIDGE: Bridge = Bridge::new().with_bootstrap(&BOOTSTRAP);
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        unsafe { BRIDGE.watch_exit_boot_services(st) }.unwrap();
+        let _attachment = unsafe { BRIDGE.attach(&mut allocator) };
+
+        let p0 = unsafe { BRIDGE.alloc(layout) };
+        assert!(!BOOTSTRAP.contains(p0));
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        assert_eq!(crate::mock::signal_exit_boot_services(), 1);
+        assert!(BRIDGE.is_exited());
+
+        // Allocations fall back to the bootstrap allocator, and pool blocks
+        // are no longer released.
+        let p1 = unsafe { BRIDGE.alloc(layout) };
+        assert!(BOOTSTRAP.contains(p1));
+        unsafe { BRIDGE.dealloc(p1, layout) };
+        unsafe { BRIDGE.dealloc(p0, layout) };
+        assert!(BOOTSTRAP.is_drained());
+        assert_eq!(crate::mock::pool_live(), 1);
+    }
+
+    // Verify that `current()` forwards to the installed bridge as long as an
+    // allocator is attached, and that only one bridge can be installed.
+    #[cfg(feature = "current")]
+    #[test]
+    fn current_bridge() {
+        static BRIDGE: Bridge = Bridge::new();
+        static CELL: GlobalAllocatorCell = GlobalAllocatorCell::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        assert!(current().is_none());
+        assert!(BRIDGE.install());
+        assert!(!CELL.install());
+        assert!(current().is_none());
+        assert!(std::panic::catch_unwind(|| crate::assert_ready!()).is_err());
+
+        let attachment = unsafe { BRIDGE.attach(&mut allocator) };
+        crate::assert_ready!();
+        let c = current().unwrap();
+        unsafe {
+            let ptr = c.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(crate::mock::pool_live(), 1);
+            c.dealloc(ptr, layout);
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        drop(attachment);
+        assert!(current().is_none());
+    }
+
+    // Verify that `assert_ready!()` accepts attached bridges and bridges
+    // with a bootstrap allocator, and rejects exited bridges.
+    #[test]
+    fn ready() {
+        static BOOTSTRAP: crate::bootstrap::Bootstrap<[u8; 256]> =
+            crate::bootstrap::Bootstrap::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let bridge = Bridge::new().with_name("ready");
+        let ready = |b: &Bridge| {
+            let b = std::panic::AssertUnwindSafe(b);
+            std::panic::catch_unwind(|| assert_ready!(b.0)).is_ok()
+        };
+
+        assert!(!ready(&bridge));
+        {
+            let _attachment = unsafe { bridge.attach(&mut allocator) };
+            assert!(ready(&bridge));
+        }
+
+        let bootstrap = Bridge::new().with_bootstrap(&BOOTSTRAP);
+        assert!(ready(&bootstrap));
+        bootstrap.set_exited();
+        assert!(!ready(&bootstrap));
+    }
+
+    // Verify that an exited bridge serves allocations from its fallback
+    // allocator, and that those blocks survive a detach.
+    #[test]
+    fn fallback() {
+        use core::alloc::GlobalAlloc;
+
+        static FALLBACK: crate::fixed::FixedAllocator =
+            crate::fixed::FixedAllocator::new();
+        static BRIDGE: Bridge =
+            Bridge::new().with_fallback(&FALLBACK).with_generations();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let buffer = Box::leak(Box::new([0u8; 512]));
+        assert!(FALLBACK.set_buffer(&mut buffer[..]).is_ok());
+
+        let attachment = unsafe { BRIDGE.attach(&mut allocator) };
+        let p0 = unsafe { BRIDGE.alloc(layout) };
+        assert!(!FALLBACK.contains(p0));
+
+        BRIDGE.set_exited();
+        let p1 = unsafe { BRIDGE.alloc(layout) };
+        assert!(FALLBACK.contains(p1));
+
+        drop(attachment);
+        unsafe { BRIDGE.dealloc(p1, layout) };
+        assert_eq!(crate::mock::pool_live(), 1);
+        assert!(FALLBACK.contains(unsafe { BRIDGE.alloc(layout) }));
+    }
+
+    // Verify that small allocations are served from the reserve once the
+    // pool is exhausted, and that large ones still fail.
+    #[test]
+    fn reserve() {
+        use core::alloc::GlobalAlloc;
+
+        static RESERVE: crate::reserve::Reserve<[crate::reserve::Slot; 4]> =
+            crate::reserve::Reserve::new();
+        static BRIDGE: Bridge = Bridge::new().with_reserve(&RESERVE);
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let small = core::alloc::Layout::from_size_align(16, 8).unwrap();
+        let large = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let _attachment = unsafe { BRIDGE.attach(&mut allocator) };
+        let p0 = unsafe { BRIDGE.alloc(small) };
+        assert!(!RESERVE.contains(p0));
+
+        crate::mock::pool_fail(Some(r_efi::efi::Status::OUT_OF_RESOURCES));
+        let p1 = unsafe { BRIDGE.alloc(small) };
+        assert!(RESERVE.contains(p1));
+        assert!(unsafe { BRIDGE.alloc(large) }.is_null());
+        crate::mock::pool_fail(None);
+
+        unsafe {
+            BRIDGE.dealloc(p1, small);
+            BRIDGE.dealloc(p0, small);
+        }
+        assert_eq!(RESERVE.used(), 0);
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Exit the boot-services of the mock, and verify that a stale map key
+    // is retried and the bridge stops using its allocator, but only once
+    // `ExitBootServices()` was called.
+    #[test]
+    fn exit() {
+        use core::alloc::GlobalAlloc;
+
+        static BRIDGE: Bridge = Bridge::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        let handle = core::ptr::null_mut();
+
+        assert_eq!(
+            unsafe { BRIDGE.exit_boot_services(handle) }
+                .err()
+                .map(|v| v.as_usize()),
+            Some(efi::Status::NOT_READY.as_usize()),
+        );
+
+        let _attachment = unsafe { BRIDGE.attach(&mut allocator) };
+
+        // A map that cannot be captured leaves the bridge usable.
+        crate::mock::pool_fail(Some(efi::Status::OUT_OF_RESOURCES));
+        assert_eq!(
+            unsafe { BRIDGE.exit_boot_services(handle) }
+                .err()
+                .map(|v| v.as_usize()),
+            Some(efi::Status::OUT_OF_RESOURCES.as_usize()),
+        );
+        crate::mock::pool_fail(None);
+        assert!(!BRIDGE.is_exited());
+        let p = unsafe { BRIDGE.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { BRIDGE.dealloc(p, layout) };
+
+        crate::mock::exit_stale(2);
+        let map = unsafe { BRIDGE.exit_boot_services(handle) }.unwrap();
+        assert_eq!(map.len(), 3);
+        assert!(BRIDGE.is_exited());
+        assert!(unsafe { BRIDGE.alloc(layout) }.is_null());
+
+        // The final map is handed over, the stale ones were released.
+        drop(map);
+        assert_eq!(crate::mock::pool_live(), 1);
+    }
+
+    // Verify that blocks are tagged with their generation, and that a block
+    // released across a re-attach is detected.
+    #[test]
+    #[should_panic(expected = "released across re-attach")]
+    fn generations() {
+        use core::alloc::GlobalAlloc;
+
+        static BRIDGE: Bridge = Bridge::new().with_generations();
+
+        let mut fw = crate::mock::Firmware::new();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 64).unwrap();
+
+        let attachment = unsafe { BRIDGE.attach(&mut allocator) };
+        let p0 = unsafe { BRIDGE.alloc(layout) };
+        assert!(!p0.is_null());
+        assert_eq!(p0 as usize % 64, 0);
+        unsafe { BRIDGE.dealloc(p0, layout) };
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        let p1 = unsafe { BRIDGE.alloc(layout) };
+        drop(attachment);
+
+        let _attachment = unsafe { BRIDGE.attach(&mut allocator) };
+        unsafe { BRIDGE.dealloc(p1, layout) };
+    }
+
+    // Verify that optional allocations yield `None` when detached and when
+    // the firmware is out of memory, without invoking the out-of-memory hook,
+    // the out-of-memory handler, or the failure notification of the observer.
+    #[test]
+    fn alloc_optional() {
+        use core::alloc::GlobalAlloc;
+
+        struct Failures(atomic::AtomicUsize);
+
+        impl crate::observe::AllocObserver for Failures {
+            fn on_alloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {}
+
+            fn on_dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {}
+
+            fn on_failure(&self, _layout: core::alloc::Layout) {
+                self.0.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        fn oom(_layout: core::alloc::Layout) {
+            CALLS.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        fn hook(
+            _layout: core::alloc::Layout,
+            _status: Option<r_efi::efi::Status>,
+        ) -> bool {
+            CALLS.fetch_add(1, atomic::Ordering::Relaxed);
+            true
+        }
+
+        static FAILURES: Failures = Failures(atomic::AtomicUsize::new(0));
+        static CALLS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+        static BRIDGE: Bridge = Bridge::new()
+            .with_observer(&FAILURES)
+            .with_oom_handler(oom);
+
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        assert!(BRIDGE.set_oom_hook(Some(hook)).is_none());
+
+        assert!(BRIDGE.try_alloc_optional(layout).is_none());
+
+        let mut fw = crate::mock::Firmware::new();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let attachment = unsafe { BRIDGE.attach(&mut allocator) };
+
+        crate::mock::pool_fail(Some(r_efi::efi::Status::OUT_OF_RESOURCES));
+        assert!(BRIDGE.try_alloc_optional(layout).is_none());
+        crate::mock::pool_fail(None);
+
+        let block = BRIDGE.try_alloc_optional(layout).unwrap();
+        assert_eq!(block.len(), 64);
+        unsafe { BRIDGE.dealloc(block.as_ptr() as *mut u8, layout) };
+
+        // Zero-sized requests never reach the allocator.
+        let zst = core::alloc::Layout::from_size_align(0, 16).unwrap();
+        let block = BRIDGE.try_alloc_optional(zst).unwrap();
+        assert_eq!(block.as_ptr() as *mut u8 as usize, 16);
+        assert_eq!(block.len(), 0);
+
+        assert_eq!(FAILURES.0.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(CALLS.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        drop(attachment);
+        assert!(BRIDGE.set_oom_hook(None).is_some());
+    }
+
+    // Verify that a cell fails allocations until it is set, can be set only
+    // once, and then serves allocations from its allocator.
+    #[test]
+    fn cell() {
+        use core::alloc::GlobalAlloc;
+
+        static CELL: GlobalAllocatorCell = GlobalAllocatorCell::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let (a0, a1) = unsafe {
+            (
+                crate::alloc::Allocator::from_system_table(
+                    st,
+                    r_efi::efi::LOADER_DATA,
+                ),
+                crate::alloc::Allocator::from_system_table(
+                    st,
+                    r_efi::efi::BOOT_SERVICES_DATA,
+                ),
+            )
+        };
+        let a0: &'static _ = Box::leak(Box::new(a0));
+        let a1: &'static _ = Box::leak(Box::new(a1));
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        assert!(CELL.get().is_none());
+        assert!(unsafe { CELL.alloc(layout) }.is_null());
+
+        assert!(unsafe { CELL.set(a0) }.is_ok());
+        let r = unsafe { CELL.set(a1) };
+        assert!(core::ptr::eq(r.unwrap_err(), a1));
+        assert!(core::ptr::eq(CELL.get().unwrap(), a0));
+
+        unsafe {
+            let ptr = CELL.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(
+                crate::mock::pool_memory_type(),
+                Some(r_efi::efi::LOADER_DATA),
+            );
+            CELL.dealloc(ptr, layout);
+        }
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Exhaust the primary allocator of a chained bridge, and verify the
+    // secondary allocator serves the request and gets the block back.
+    #[test]
+    fn chain() {
+        use core::alloc::GlobalAlloc;
+
+        extern "efiapi" fn exhausted(
+            _: r_efi::efi::MemoryType,
+            _: usize,
+            _: *mut *mut core::ffi::c_void,
+        ) -> r_efi::efi::Status {
+            r_efi::efi::Status::OUT_OF_RESOURCES
+        }
+
+        static BRIDGE: Bridge = Bridge::new().with_chain();
+
+        let mut fw0 = crate::mock::Firmware::new();
+        let mut fw1 = crate::mock::Firmware::new();
+        fw0.patch_boot_services(|bs| unsafe {
+            (*bs).allocate_pool = exhausted;
+        });
+        let st0 = fw0.system_table();
+        let mut primary = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st0,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let mut secondary = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw1.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let mut spare = secondary.clone();
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let unchained = Bridge::new();
+        assert!(unsafe { unchained.attach_secondary(&mut spare) }.is_none());
+
+        let _attachment = unsafe { BRIDGE.attach(&mut primary) };
+        let p = unsafe { BRIDGE.alloc(layout) };
+        assert!(p.is_null());
+
+        {
+            let _secondary = unsafe { BRIDGE.attach_secondary(&mut secondary) };
+            let p = unsafe { BRIDGE.alloc(layout) };
+            assert!(!p.is_null());
+            assert_eq!(crate::mock::pool_live(), 1);
+
+            unsafe { BRIDGE.dealloc(p, layout) };
+            assert_eq!(crate::mock::pool_live(), 0);
+        }
+
+        // The slot is free again after the secondary attachment is dropped.
+        assert!(unsafe { BRIDGE.attach_secondary(&mut spare) }.is_some());
+    }
+
+    // Verify that a router sends requests to pages, the pool, a dangling
+    // pointer or a failure, and that blocks are released along the same
+    // route.
+    #[test]
+    fn router() {
+        use core::alloc::GlobalAlloc;
+
+        fn route(layout: &core::alloc::Layout) -> Route {
+            match layout.size() {
+                0 => Route::Dangling,
+                1..=4095 => Route::Pool,
+                4096 => Route::Fail,
+                _ => Route::Pages,
+            }
+        }
+
+        static BRIDGE: Bridge = Bridge::new()
+            .with_router(route)
+            .with_generations();
+
+        let mut fw = crate::mock::Firmware::new();
+        let mut allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                fw.system_table(),
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let small = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        let fail = core::alloc::Layout::from_size_align(4096, 8).unwrap();
+        let large = core::alloc::Layout::from_size_align(8192, 8).unwrap();
+        let zst = core::alloc::Layout::from_size_align(0, 16).unwrap();
+
+        // Pages need the system-table of an attachment.
+        assert!(unsafe { BRIDGE.alloc(large) }.is_null());
+
+        let _attachment = unsafe { BRIDGE.attach(&mut allocator) };
+
+        let p = unsafe { BRIDGE.alloc(small) };
+        assert!(!p.is_null());
+        assert_eq!(crate::mock::pool_live(), 1);
+        assert_eq!(crate::mock::pages_live(), 0);
+        unsafe { BRIDGE.dealloc(p, small) };
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        let p = unsafe { BRIDGE.alloc(large) };
+        assert!(!p.is_null());
+        assert_eq!(p as usize % crate::alloc::PAGE_SIZE, 0);
+        assert_eq!(crate::mock::pool_live(), 0);
+        assert_eq!(crate::mock::pages_live(), 2);
+        unsafe { BRIDGE.dealloc(p, large) };
+        assert_eq!(crate::mock::pages_live(), 0);
+
+        assert!(unsafe { BRIDGE.alloc(fail) }.is_null());
+
+        let block = BRIDGE.try_alloc_optional(zst).unwrap();
+        assert_eq!(block.as_ptr() as *mut u8 as usize, 16);
+        let p = unsafe { BRIDGE.alloc(zst) };
+        assert_eq!(p as usize, 16);
+        unsafe { BRIDGE.dealloc(p, zst) };
+
+        assert_eq!(crate::mock::pool_live(), 0);
+        assert_eq!(crate::mock::pages_live(), 0);
     }
 }
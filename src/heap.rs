@@ -0,0 +1,175 @@
+//! Embedded-Style Heap
+//!
+//! Allocators of the embedded ecosystem commonly follow the same pattern: a
+//! heap is placed in a static variable, registered as global allocator, and
+//! initialized with a memory region early on. This module provides the
+//! `Heap` type, which follows this pattern, so existing `no_std` code can be
+//! ported to UEFI without restructuring its setup.
+//!
+//! The heap is backed by a `fixed::FixedAllocator`. It can be initialized
+//! with any memory region via `Heap::init()`, or with pages allocated from
+//! the firmware via `Heap::init_from_pages()`. Since the heap never calls
+//! into the firmware after initialization, it keeps working after the
+//! boot-services were exited.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! use r_efi_alloc::heap::Heap;
+//!
+//! #[global_allocator]
+//! static HEAP: Heap = Heap::empty();
+//!
+//! // Initialize the heap with 16 pages of loader data, before the global
+//! // allocator is used.
+//! unsafe { HEAP.init_from_pages(st, efi::LOADER_DATA, 16) }.unwrap();
+//! ```
+
+use r_efi::efi;
+
+/// Embedded-Style Heap
+///
+/// This is a heap with the interface common to allocators of the embedded
+/// ecosystem. It is created empty, and serves no allocations until it is
+/// initialized with a memory region. It implements `GlobalAlloc`, so it can
+/// be registered as global allocator.
+pub struct Heap {
+    fixed: crate::fixed::FixedAllocator,
+}
+
+impl Heap {
+    /// Create Empty Heap
+    ///
+    /// Create a new heap without any memory. This is a constant function, so
+    /// the heap can be used to initialize a static variable.
+    pub const fn empty() -> Heap {
+        Heap {
+            fixed: crate::fixed::FixedAllocator::new(),
+        }
+    }
+
+    /// Initialize the Heap
+    ///
+    /// Hand the memory region of `size` bytes at `start_addr` to the heap,
+    /// which serves all allocations from it. This panics if the heap was
+    /// initialized before.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The memory region must be valid for reads and writes, and must not be
+    /// used by anything else for the rest of the lifetime of the heap.
+    pub unsafe fn init(&self, start_addr: usize, size: usize) {
+        let region =
+            core::slice::from_raw_parts_mut(start_addr as *mut u8, size);
+
+        assert!(
+            self.fixed.set_buffer(region).is_ok(),
+            "heap initialized more than once",
+        );
+    }
+
+    /// Initialize the Heap with Firmware Pages
+    ///
+    /// Allocate `pages` pages of the given memory type from the firmware and
+    /// initialize the heap with them, like `init()` does. The pages are never
+    /// released. If the allocation fails, the status code of the firmware is
+    /// returned and the heap stays empty.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// It must be safe for this function to call `allocate_pages` and
+    /// `free_pages` of the boot-services provided via the system-table. The
+    /// heap must not have been initialized before.
+    pub unsafe fn init_from_pages(
+        &self,
+        system_table: *mut efi::SystemTable,
+        memory_type: efi::MemoryType,
+        pages: usize,
+    ) -> Result<(), efi::Status> {
+        let pages = crate::raw::alloc_pages(
+            (*system_table).boot_services,
+            crate::alloc::AllocateType::AnyPages,
+            memory_type,
+            pages,
+        )?;
+
+        self.init(pages.as_ptr() as usize, pages.size());
+        Ok(())
+    }
+
+    /// Return the number of bytes in use
+    pub fn used(&self) -> usize {
+        self.fixed.capacity() - self.fixed.available()
+    }
+
+    /// Return the number of free bytes
+    pub fn free(&self) -> usize {
+        self.fixed.available()
+    }
+}
+
+unsafe impl core::alloc::GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        self.fixed.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        self.fixed.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::GlobalAlloc;
+
+    // Initialize heaps from a region and from firmware pages, and verify
+    // they serve allocations and account for them.
+    #[test]
+    fn init() {
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let heap = Heap::empty();
+        unsafe {
+            assert!(heap.alloc(layout).is_null());
+
+            let region = Box::leak(vec![0u64; 64].into_boxed_slice());
+            heap.init(region.as_mut_ptr() as usize, 512);
+            assert_eq!(heap.free(), 512);
+
+            let ptr = heap.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(heap.used(), 64);
+            heap.dealloc(ptr, layout);
+            assert_eq!(heap.used(), 0);
+        }
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let heap = Heap::empty();
+        unsafe {
+            heap.init_from_pages(st, efi::LOADER_DATA, 2).unwrap();
+            assert_eq!(crate::mock::pages_live(), 2);
+            assert_eq!(heap.free(), 8192);
+
+            let ptr = heap.alloc(layout);
+            assert!(!ptr.is_null());
+            heap.dealloc(ptr, layout);
+        }
+    }
+
+    // Verify that a heap cannot be initialized twice.
+    #[test]
+    #[should_panic(expected = "heap initialized more than once")]
+    fn init_twice() {
+        let heap = Heap::empty();
+        let region = Box::leak(vec![0u64; 16].into_boxed_slice());
+
+        unsafe {
+            heap.init(region.as_mut_ptr() as usize, 64);
+            heap.init(region.as_mut_ptr() as usize + 64, 64);
+        }
+    }
+}
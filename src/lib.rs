@@ -8,6 +8,24 @@
 //! UEFI memory allocators to the rust standard library. Lastly, `alloc`
 //! implements the unstable `core::alloc::Allocator` trait which likely
 //! will take the role of the main rust memory allocators in the future.
+//!
+//! Additionally, a set of auxiliary modules builds on these allocators: `fmt`
+//! provides string buffers for formatting without a global allocator, `arena`
+//! provides a bump allocator on top of the page allocator, `fixed` provides an
+//! allocator on a caller-provided buffer for use after `ExitBootServices()`,
+//! `heap` wraps it in the heap interface common to the embedded ecosystem,
+//! `reserve` keeps a few small blocks available under memory exhaustion,
+//! `bootstrap` provides an allocator that needs no setup at all, `memmap`
+//! captures the UEFI memory map, `callback` provides a bounded allocator that
+//! is safe to use from UEFI event callbacks, and `observe` allows hooking into
+//! the allocation paths. With the `allocator_api` feature, `conformance`
+//! provides a test-suite to validate allocator implementations. With the
+//! `stats` feature, `stats` provides allocation counters, and with the `capi`
+//! feature, `capi` exports the allocator to C. The `entry` module provides a
+//! macro that generates the UEFI entry-point and wires up the global allocator.
+//! Lastly, `config` describes the configuration of this crate for bug reports,
+//! and `stdshim` provides the stable interface the rust standard library relies
+//! on.
 
 // The `core::alloc::Allocator` trait is still unstable and hidden behind the
 // `allocator_api` feature. Make sure to enable it, so we can implement this
@@ -23,6 +41,50 @@
 // during tests, so we can run them on the host.
 #![cfg_attr(not(test), no_std)]
 
+// UEFI only specifies 32-bit and 64-bit platforms. The alignment helpers of
+// the `raw` module rely on this, so refuse to build for anything else.
+#[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
+compile_error!("r-efi-alloc only supports 32-bit and 64-bit targets");
+
+// The `native` feature selects code that only compiles for UEFI targets. Catch
+// accidental use on foreign targets early, rather than failing to link.
+#[cfg(all(feature = "native", not(target_os = "uefi")))]
+compile_error!("the `native` feature of r-efi-alloc requires a UEFI target");
+
+// All calls into the firmware go through `extern "efiapi"` function pointers.
+// Name that calling convention here, so a toolchain without it fails to build
+// this crate with an error pointing right at it, rather than deep inside the
+// table definitions of r-efi. It is stable since Rust 1.68.
+const _: Option<extern "efiapi" fn()> = None;
+
+// The `collections` module re-exports types of the `alloc` crate. Since this
+// crate has an `alloc` module of its own, import it under a different name.
+#[cfg(feature = "collections")]
+extern crate alloc as liballoc;
+
 pub mod alloc;
+pub mod arena;
+pub mod bootstrap;
+pub mod callback;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod config;
+#[cfg(feature = "collections")]
+pub mod collections;
+#[cfg(feature = "allocator_api")]
+pub mod conformance;
+pub mod entry;
+pub mod fixed;
+pub mod fmt;
 pub mod global;
+pub mod heap;
+pub mod memmap;
+pub mod observe;
 pub mod raw;
+pub mod reserve;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod stdshim;
+
+#[cfg(test)]
+mod mock;
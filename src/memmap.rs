@@ -0,0 +1,406 @@
+//! Memory Map
+//!
+//! Every bootloader needs the UEFI memory map, at the latest to exit the
+//! boot-services. Retrieving it is surprisingly fiddly: the required buffer
+//! size is only known after a failed call, allocating the buffer can itself
+//! grow the map, and the size of each descriptor is reported by the firmware
+//! rather than fixed by the specification.
+//!
+//! This module provides the `MemoryMap` type, which captures the memory map
+//! into a buffer allocated through an `alloc::Allocator`, and provides an
+//! iterator over its descriptors, as well as the map key needed to call
+//! `ExitBootServices()`.
+//!
+//! Furthermore, `probe_pool()` diffs the memory map around a sweep of pool
+//! allocations, to measure how much memory the firmware pool consumes per
+//! block on a given platform.
+
+use r_efi::efi;
+
+// Number of descriptors to leave room for when allocating the buffer, since
+// the allocation of the buffer itself might split a free region of the map.
+const MAP_SLACK: usize = 4usize;
+
+/// Memory Map
+///
+/// This owns a copy of the UEFI memory map, as returned by `GetMemoryMap()`,
+/// in a buffer allocated from an `alloc::Allocator`. The buffer is released
+/// when the object is dropped.
+///
+/// The map is a snapshot. Any allocation or deallocation of memory after it
+/// was captured might change the memory map of the firmware, in which case
+/// `key()` no longer matches the current map.
+///
+/// If the map was used to exit the boot-services (see
+/// `global::Bridge::exit_boot_services()`), its buffer is not released on
+/// drop, but remains owned by the operating system.
+pub struct MemoryMap<'alloc> {
+    allocator: &'alloc crate::alloc::Allocator,
+    exited: bool,
+    buffer: *mut u8,
+    layout: core::alloc::Layout,
+    size: usize,
+    key: usize,
+    descriptor_size: usize,
+    descriptor_version: u32,
+}
+
+/// Memory Map Iterator
+///
+/// This iterates over the descriptors of a `MemoryMap`. Descriptors are
+/// returned by value, since the firmware does not guarantee their alignment
+/// within the map.
+pub struct Iter<'map> {
+    buffer: *const u8,
+    stride: usize,
+    index: usize,
+    len: usize,
+    _map: core::marker::PhantomData<&'map [u8]>,
+}
+
+/// Pool Probe
+///
+/// This describes the outcome of `probe_pool()`: the number of pages the
+/// memory map grew by, while `count` pool blocks of `size` bytes each were
+/// live.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PoolProbe {
+    /// Size of each probed block in bytes.
+    pub size: usize,
+    /// Number of probed blocks.
+    pub count: usize,
+    /// Number of pages the memory type of the allocator grew by.
+    pub pages: u64,
+}
+
+impl<'alloc> MemoryMap<'alloc> {
+    /// Capture the Memory Map
+    ///
+    /// Retrieve the current memory map via `GetMemoryMap()` of the
+    /// boot-services of `allocator`, and store it in a buffer allocated from
+    /// `allocator`. If the buffer turns out too small (since the map grew in
+    /// the meantime), the request is retried with a larger buffer.
+    ///
+    /// If the buffer cannot be allocated, or if `GetMemoryMap()` fails with
+    /// anything but `BUFFER_TOO_SMALL`, the status code is returned.
+    pub fn capture(
+        allocator: &'alloc crate::alloc::Allocator,
+    ) -> Result<MemoryMap<'alloc>, efi::Status> {
+        let bs = unsafe { (*allocator.system_table()).boot_services };
+        let mut map = MemoryMap {
+            allocator,
+            exited: false,
+            buffer: core::ptr::null_mut(),
+            layout: core::alloc::Layout::new::<efi::MemoryDescriptor>(),
+            size: 0,
+            key: 0,
+            descriptor_size: 0,
+            descriptor_version: 0,
+        };
+
+        loop {
+            let mut size = if map.buffer.is_null() {
+                0
+            } else {
+                map.layout.size()
+            };
+            let r = unsafe {
+                ((*bs).get_memory_map)(
+                    &mut size,
+                    map.buffer as *mut efi::MemoryDescriptor,
+                    &mut map.key,
+                    &mut map.descriptor_size,
+                    &mut map.descriptor_version,
+                )
+            };
+
+            if r == efi::Status::SUCCESS {
+                map.size = size;
+                return Ok(map);
+            } else if r != efi::Status::BUFFER_TOO_SMALL {
+                return Err(r);
+            }
+
+            // Release the old buffer before allocating a larger one, and
+            // leave some room for the map to grow due to the allocation.
+            map.release();
+            map.layout = match map
+                .descriptor_size
+                .checked_mul(MAP_SLACK)
+                .and_then(|v| v.checked_add(size))
+                .and_then(|v| {
+                    core::alloc::Layout::from_size_align(v, 8).ok()
+                }) {
+                Some(v) => v,
+                None => return Err(efi::Status::OUT_OF_RESOURCES),
+            };
+            map.buffer = unsafe { allocator.alloc(map.layout) };
+            if map.buffer.is_null() {
+                return Err(allocator
+                    .last_error()
+                    .unwrap_or(efi::Status::OUT_OF_RESOURCES));
+            }
+        }
+    }
+
+    // Mark the map as handed over to the operating system. Its buffer is
+    // never released, since the boot-services are gone.
+    pub(crate) fn set_exited(&mut self) {
+        self.exited = true;
+    }
+
+    // Release the buffer of the map, if any.
+    fn release(&mut self) {
+        if !self.buffer.is_null() && !self.exited {
+            unsafe { self.allocator.dealloc(self.buffer, self.layout) };
+            self.buffer = core::ptr::null_mut();
+        }
+    }
+
+    /// Return the map key
+    ///
+    /// Return the key identifying this version of the memory map, as needed
+    /// by `ExitBootServices()`.
+    pub fn key(&self) -> usize {
+        self.key
+    }
+
+    /// Return the size of a descriptor in bytes
+    pub fn descriptor_size(&self) -> usize {
+        self.descriptor_size
+    }
+
+    /// Return the version of the descriptors
+    pub fn descriptor_version(&self) -> u32 {
+        self.descriptor_version
+    }
+
+    /// Return the number of descriptors
+    pub fn len(&self) -> usize {
+        self.size.checked_div(self.descriptor_size).unwrap_or(0)
+    }
+
+    /// Check whether the map has no descriptors
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the descriptor at `index`, if any
+    pub fn get(&self, index: usize) -> Option<efi::MemoryDescriptor> {
+        self.iter().nth(index)
+    }
+
+    /// Iterate over the descriptors of the map
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            buffer: self.buffer,
+            stride: self.descriptor_size,
+            index: 0,
+            len: self.len(),
+            _map: core::marker::PhantomData,
+        }
+    }
+}
+
+impl PoolProbe {
+    /// Return the footprint of a block
+    ///
+    /// Return the number of bytes the firmware consumed per probed block,
+    /// including its own bookkeeping and any rounding of the block size.
+    pub fn footprint(&self) -> usize {
+        let pages = self.pages as usize;
+        let bytes = pages.saturating_mul(crate::alloc::PAGE_SIZE);
+
+        bytes.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Return the overhead of a block
+    ///
+    /// Return the number of bytes the firmware consumed per probed block on
+    /// top of the requested size.
+    pub fn overhead(&self) -> usize {
+        self.footprint().saturating_sub(self.size)
+    }
+}
+
+// Sum up the pages of all regions of the given memory type.
+fn pages_of(map: &MemoryMap<'_>, memory_type: efi::MemoryType) -> u64 {
+    map.iter()
+        .filter(|v| v.r#type == memory_type)
+        .map(|v| v.number_of_pages)
+        .sum()
+}
+
+/// Probe the Pool Granularity
+///
+/// Allocate `count` pool blocks of `size` bytes each via `allocator`, and
+/// diff the memory map captured before and after, to measure how much
+/// memory the firmware pool consumes per block. All blocks are released
+/// again before this returns. Layers that carve small objects out of larger
+/// pool chunks can use this to tune their chunk size for a platform.
+///
+/// The measurement is approximate. The pool of the firmware grows in whole
+/// pages, and the buffer of the second memory map is part of the diff.
+/// Hence, `count` should be chosen large enough for the blocks to span many
+/// pages. The probe should run early, before the pool becomes fragmented,
+/// since the firmware reuses free pool memory before it grows the pool.
+///
+/// If `size` or `count` is 0, `INVALID_PARAMETER` is returned. If any
+/// allocation or capture fails, its status code is returned.
+pub fn probe_pool(
+    allocator: &crate::alloc::Allocator,
+    size: usize,
+    count: usize,
+) -> Result<PoolProbe, efi::Status> {
+    let layout = match core::alloc::Layout::from_size_align(size, 8) {
+        Ok(v) if size > 0 && count > 0 => v,
+        _ => return Err(efi::Status::INVALID_PARAMETER),
+    };
+    let table_layout = core::alloc::Layout::array::<*mut u8>(count)
+        .map_err(|_| efi::Status::INVALID_PARAMETER)?;
+    let memory_type = allocator.memory_type();
+    let oom = || {
+        allocator
+            .last_error()
+            .unwrap_or(efi::Status::OUT_OF_RESOURCES)
+    };
+
+    // Allocate the table of blocks before the first capture, so it is not
+    // part of the diff.
+    let table = unsafe { allocator.alloc(table_layout) } as *mut *mut u8;
+    if table.is_null() {
+        return Err(oom());
+    }
+
+    let mut n = 0;
+    let r = MemoryMap::capture(allocator).and_then(|before| {
+        while n < count {
+            let ptr = unsafe { allocator.alloc(layout) };
+            if ptr.is_null() {
+                return Err(oom());
+            }
+            unsafe { table.add(n).write(ptr) };
+            n += 1;
+        }
+
+        let after = MemoryMap::capture(allocator)?;
+        Ok(pages_of(&after, memory_type)
+            .saturating_sub(pages_of(&before, memory_type)))
+    });
+
+    unsafe {
+        for i in 0..n {
+            allocator.dealloc(*table.add(i), layout);
+        }
+        allocator.dealloc(table as *mut u8, table_layout);
+    }
+
+    r.map(|pages| PoolProbe { size, count, pages })
+}
+
+impl Drop for MemoryMap<'_> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl<'map> IntoIterator for &'map MemoryMap<'_> {
+    type Item = efi::MemoryDescriptor;
+    type IntoIter = Iter<'map>;
+
+    fn into_iter(self) -> Iter<'map> {
+        self.iter()
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = efi::MemoryDescriptor;
+
+    fn next(&mut self) -> Option<efi::MemoryDescriptor> {
+        if self.index >= self.len {
+            return None;
+        }
+
+        // The descriptors were written by the firmware, and the descriptor
+        // size is at least the size of the structure.
+        let d = unsafe {
+            core::ptr::read_unaligned(
+                self.buffer.add(self.index * self.stride)
+                    as *const efi::MemoryDescriptor,
+            )
+        };
+        self.index += 1;
+        Some(d)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.len - self.index;
+        (n, Some(n))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Capture the memory map of the mock firmware and verify that all
+    // descriptors are reported, honoring the descriptor size.
+    #[test]
+    fn capture() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(st, efi::LOADER_DATA)
+        };
+
+        let map = MemoryMap::capture(&allocator).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.key(), crate::mock::map_key());
+        let size = core::mem::size_of::<efi::MemoryDescriptor>();
+        assert!(map.descriptor_size() > size);
+        assert_eq!(map.descriptor_version(), efi::MEMORY_DESCRIPTOR_VERSION);
+
+        let types: Vec<u32> = map.iter().map(|v| v.r#type).collect();
+        assert_eq!(
+            types,
+            [
+                efi::CONVENTIONAL_MEMORY,
+                efi::LOADER_DATA,
+                efi::BOOT_SERVICES_DATA,
+            ],
+        );
+        let d = map.get(1).unwrap();
+        assert_eq!(d.physical_start, 0x10_0000 + 256 * 4096);
+        assert!(map.get(3).is_none());
+        assert_eq!(crate::mock::pool_live(), 1);
+
+        drop(map);
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Probe the pool of the mock firmware, which reports pool memory in
+    // whole pages, and verify the footprint covers the requested size.
+    #[test]
+    fn probe() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(st, efi::LOADER_DATA)
+        };
+
+        let probe = probe_pool(&allocator, 100, 256).unwrap();
+        assert_eq!(probe.size, 100);
+        assert_eq!(probe.count, 256);
+        assert!(probe.pages >= 7);
+        assert!(probe.footprint() >= 100);
+        assert_eq!(probe.overhead(), probe.footprint() - 100);
+        assert_eq!(crate::mock::pool_live(), 0);
+
+        assert_eq!(
+            probe_pool(&allocator, 0, 1).err().map(|v| v.as_usize()),
+            Some(efi::Status::INVALID_PARAMETER.as_usize()),
+        );
+    }
+}
@@ -0,0 +1,687 @@
+//! Mock Firmware
+//!
+//! This module provides a fake UEFI system-table for host tests. The
+//! boot-services are implemented on top of the allocator of the rust standard
+//! library, and are installed as `extern "efiapi"` function pointers, exactly
+//! like real firmware would provide them. Hence, the raw allocator paths can
+//! be exercised on the host, and any drift in the signatures of the `r-efi`
+//! definitions is caught at compile-time.
+//!
+//! Only the boot-services used by this crate are provided. All other entries
+//! of the tables are left zeroed and must not be called. State of the mock is
+//! kept per thread, so tests can run in parallel.
+
+use r_efi::efi;
+use std::cell::{Cell, RefCell};
+
+const PAGE_SIZE: usize = 4096;
+
+// Alignment of pool allocations placed in high memory. Every non-NULL
+// address with this alignment is at or above 4GiB.
+const POOL_HIGH_ALIGN: usize = 1 << 32;
+
+/// Offset applied by the mock `ConvertPointer()` to simulate a virtual
+/// address map.
+pub(crate) const VIRTUAL_OFFSET: usize = 0x1000_0000;
+
+// Header placed in front of every pool allocation of the mock, so we can
+// retrieve the allocation size when freeing the block. Its size retains the
+// 8-byte alignment UEFI guarantees for pool allocations. If misalignment is
+// simulated, the block is placed behind the header with the given offset.
+// The alignment of the allocation is recorded as well, since it differs for
+// blocks placed in high memory.
+#[repr(C, align(8))]
+struct PoolHeader {
+    size: usize,
+    misalign: usize,
+    align: usize,
+}
+
+thread_local! {
+    static POOL_LIVE: Cell<usize> = const { Cell::new(0) };
+    static POOL_BYTES: Cell<usize> = const { Cell::new(0) };
+    static POOL_FAIL: Cell<Option<efi::Status>> = const { Cell::new(None) };
+    static POOL_MISALIGN: Cell<usize> = const { Cell::new(0) };
+    static POOL_HIGH: Cell<bool> = const { Cell::new(false) };
+    static POOL_MEMORY_TYPE: Cell<Option<efi::MemoryType>> =
+        const { Cell::new(None) };
+    static PROCESSOR: Cell<usize> = const { Cell::new(0) };
+    static SET_MEM_CALLS: Cell<usize> = const { Cell::new(0) };
+    static TPL: Cell<efi::Tpl> = const { Cell::new(efi::TPL_APPLICATION) };
+    static TPL_RAISES: Cell<usize> = const { Cell::new(0) };
+    static TPL_INVERSIONS: Cell<usize> = const { Cell::new(0) };
+    static POOL_TPL: Cell<efi::Tpl> = const { Cell::new(0) };
+    static MAP_KEY: Cell<usize> = const { Cell::new(0) };
+    static EXIT_STALE: Cell<usize> = const { Cell::new(0) };
+    static PAGES: RefCell<Vec<PageBlock>> = const { RefCell::new(Vec::new()) };
+    static EVENTS: RefCell<Vec<MockEvent>> = const { RefCell::new(Vec::new()) };
+}
+
+// An event created via `CreateEvent()`. Events are never closed, and their
+// notification functions are only invoked when signalled explicitly via the
+// helpers of this module.
+struct MockEvent {
+    event_type: u32,
+    notify: Option<efi::EventNotify>,
+    context: *mut core::ffi::c_void,
+}
+
+// A block of pages allocated from the allocator of the standard library. The
+// pages of a block can be released individually, like firmware allows. Once
+// all pages of a block are released, the block is returned to the standard
+// library.
+struct PageBlock {
+    base: usize,
+    allocated: Vec<bool>,
+}
+
+/// Fake Firmware
+///
+/// This owns a system-table and boot-services table populated with the mock
+/// implementations of this module.
+pub(crate) struct Firmware {
+    system_table: Box<core::mem::MaybeUninit<efi::SystemTable>>,
+    boot_services: Box<core::mem::MaybeUninit<efi::BootServices>>,
+    _runtime_services: Box<core::mem::MaybeUninit<efi::RuntimeServices>>,
+    loaded_image: Box<
+        core::mem::MaybeUninit<efi::protocols::loaded_image::Protocol>,
+    >,
+    mp_services: Box<
+        core::mem::MaybeUninit<efi::protocols::mp_services::Protocol>,
+    >,
+}
+
+impl Firmware {
+    pub(crate) fn new() -> Firmware {
+        let mut st =
+            Box::new(core::mem::MaybeUninit::<efi::SystemTable>::zeroed());
+        let mut bs =
+            Box::new(core::mem::MaybeUninit::<efi::BootServices>::zeroed());
+        let mut rs =
+            Box::new(core::mem::MaybeUninit::<efi::RuntimeServices>::zeroed());
+        let mut li = Box::new(core::mem::MaybeUninit::<
+            efi::protocols::loaded_image::Protocol,
+        >::zeroed());
+        let mut mp = Box::new(core::mem::MaybeUninit::<
+            efi::protocols::mp_services::Protocol,
+        >::zeroed());
+
+        // The tables stay partially uninitialized (zeroed function pointers
+        // are not valid values). We only ever write and read the members we
+        // populate here, always via raw pointers.
+        unsafe {
+            let bs_ptr = bs.as_mut_ptr();
+            core::ptr::addr_of_mut!((*bs_ptr).hdr.signature)
+                .write(efi::BOOT_SERVICES_SIGNATURE);
+            core::ptr::addr_of_mut!((*bs_ptr).get_memory_map)
+                .write(get_memory_map);
+            core::ptr::addr_of_mut!((*bs_ptr).allocate_pool)
+                .write(allocate_pool);
+            core::ptr::addr_of_mut!((*bs_ptr).free_pool).write(free_pool);
+            core::ptr::addr_of_mut!((*bs_ptr).allocate_pages)
+                .write(allocate_pages);
+            core::ptr::addr_of_mut!((*bs_ptr).free_pages).write(free_pages);
+            core::ptr::addr_of_mut!((*bs_ptr).copy_mem).write(copy_mem);
+            core::ptr::addr_of_mut!((*bs_ptr).set_mem).write(set_mem);
+            core::ptr::addr_of_mut!((*bs_ptr).raise_tpl).write(raise_tpl);
+            core::ptr::addr_of_mut!((*bs_ptr).restore_tpl).write(restore_tpl);
+            core::ptr::addr_of_mut!((*bs_ptr).create_event)
+                .write(create_event);
+            core::ptr::addr_of_mut!((*bs_ptr).exit_boot_services)
+                .write(exit_boot_services);
+            core::ptr::addr_of_mut!((*bs_ptr).handle_protocol)
+                .write(handle_protocol);
+            core::ptr::addr_of_mut!((*bs_ptr).hdr.header_size)
+                .write(core::mem::size_of::<efi::BootServices>() as u32);
+            core::ptr::addr_of_mut!((*bs_ptr).hdr.crc32)
+                .write(crate::raw::table_crc32(&(*bs_ptr).hdr));
+
+            let st_ptr = st.as_mut_ptr();
+            core::ptr::addr_of_mut!((*st_ptr).hdr.signature)
+                .write(efi::SYSTEM_TABLE_SIGNATURE);
+            core::ptr::addr_of_mut!((*st_ptr).boot_services).write(bs_ptr);
+
+            let rs_ptr = rs.as_mut_ptr();
+            core::ptr::addr_of_mut!((*rs_ptr).hdr.signature)
+                .write(efi::RUNTIME_SERVICES_SIGNATURE);
+            core::ptr::addr_of_mut!((*rs_ptr).convert_pointer)
+                .write(convert_pointer);
+            core::ptr::addr_of_mut!((*st_ptr).runtime_services).write(rs_ptr);
+
+            let li_ptr = li.as_mut_ptr();
+            core::ptr::addr_of_mut!((*li_ptr).system_table).write(st_ptr);
+            core::ptr::addr_of_mut!((*li_ptr).image_data_type)
+                .write(efi::LOADER_DATA);
+
+            let mp_ptr = mp.as_mut_ptr();
+            core::ptr::addr_of_mut!((*mp_ptr).who_am_i).write(who_am_i);
+        }
+
+        Firmware {
+            system_table: st,
+            boot_services: bs,
+            _runtime_services: rs,
+            loaded_image: li,
+            mp_services: mp,
+        }
+    }
+
+    /// Return the image handle of the fake firmware
+    ///
+    /// The handle carries a `LoadedImage` protocol, whose data type is
+    /// `LOADER_DATA` unless changed via `set_image_data_type()`.
+    pub(crate) fn image_handle(&mut self) -> efi::Handle {
+        self.loaded_image.as_mut_ptr() as efi::Handle
+    }
+
+    /// Change the data type of the loaded image of the fake firmware
+    pub(crate) fn set_image_data_type(&mut self, memtype: efi::MemoryType) {
+        let li_ptr = self.loaded_image.as_mut_ptr();
+        unsafe {
+            core::ptr::addr_of_mut!((*li_ptr).image_data_type).write(memtype)
+        };
+    }
+
+    /// Patch the boot-services of the fake firmware
+    ///
+    /// Run `f` on the boot-services table (e.g., to replace one of its
+    /// functions), and refresh the checksum of the table afterwards, so it
+    /// still passes table checks.
+    pub(crate) fn patch_boot_services(
+        &mut self,
+        f: impl FnOnce(*mut efi::BootServices),
+    ) {
+        let bs_ptr = self.boot_services.as_mut_ptr();
+        f(bs_ptr);
+        unsafe {
+            core::ptr::addr_of_mut!((*bs_ptr).hdr.crc32)
+                .write(crate::raw::table_crc32(&(*bs_ptr).hdr));
+        }
+    }
+
+    /// Return the MP-Services protocol of the fake firmware
+    ///
+    /// Only `WhoAmI()` is provided. It reports the processor selected via
+    /// `set_processor()`, which is the BSP (processor 0) by default.
+    pub(crate) fn mp_services(
+        &mut self,
+    ) -> *mut efi::protocols::mp_services::Protocol {
+        self.mp_services.as_mut_ptr()
+    }
+
+    /// Return the system-table of the fake firmware
+    pub(crate) fn system_table(&mut self) -> *mut efi::SystemTable {
+        self.system_table.as_mut_ptr()
+    }
+}
+
+/// Select the processor the current thread reports via `WhoAmI()`
+pub(crate) fn set_processor(number: usize) {
+    PROCESSOR.with(|v| v.set(number));
+}
+
+/// Return the number of live pool allocations of the current thread
+pub(crate) fn pool_live() -> usize {
+    POOL_LIVE.with(|v| v.get())
+}
+
+/// Make all further pool allocations of the current thread fail
+///
+/// If `status` is `None`, pool allocations succeed again.
+pub(crate) fn pool_fail(status: Option<efi::Status>) {
+    POOL_FAIL.with(|v| v.set(status));
+}
+
+/// Misalign all further pool allocations of the current thread
+///
+/// Offset all returned pool pointers by `offset` bytes, which must be less
+/// than 8, to simulate firmware that violates the pool alignment guarantee.
+pub(crate) fn pool_misalign(offset: usize) {
+    assert!(offset < 8);
+    POOL_MISALIGN.with(|v| v.set(offset));
+}
+
+/// Place all further pool allocations of the current thread above 4GiB
+///
+/// If `high` is `false`, pool allocations are placed wherever the standard
+/// library puts them again.
+pub(crate) fn pool_high(high: bool) {
+    POOL_HIGH.with(|v| v.set(high));
+}
+
+/// Return the memory type of the last pool allocation of the current thread
+pub(crate) fn pool_memory_type() -> Option<efi::MemoryType> {
+    POOL_MEMORY_TYPE.with(|v| v.get())
+}
+
+/// Return the number of calls to `SetMem()` of the current thread
+pub(crate) fn set_mem_calls() -> usize {
+    SET_MEM_CALLS.with(|v| v.get())
+}
+
+/// Return the current TPL of the current thread
+pub(crate) fn tpl() -> efi::Tpl {
+    TPL.with(|v| v.get())
+}
+
+/// Return the number of calls to `RaiseTPL()` of the current thread
+pub(crate) fn tpl_raises() -> usize {
+    TPL_RAISES.with(|v| v.get())
+}
+
+/// Return the number of invalid TPL transitions of the current thread
+///
+/// This counts calls to `RaiseTPL()` with a TPL below the current one, and
+/// calls to `RestoreTPL()` with a TPL above the current one. Firmware does
+/// not allow either of them.
+pub(crate) fn tpl_inversions() -> usize {
+    TPL_INVERSIONS.with(|v| v.get())
+}
+
+/// Return the TPL of the last pool call of the current thread
+pub(crate) fn pool_tpl() -> efi::Tpl {
+    POOL_TPL.with(|v| v.get())
+}
+
+/// Signal all events of a given type of the current thread
+///
+/// This invokes the notification functions of all events created with the
+/// given event type, like firmware does for events of groups it signals
+/// (e.g., `EVT_SIGNAL_EXIT_BOOT_SERVICES`). It returns the number of
+/// notified events.
+pub(crate) fn signal_events(event_type: u32) -> usize {
+    let events: Vec<(efi::EventNotify, *mut core::ffi::c_void)> =
+        EVENTS.with(|v| {
+            v.borrow()
+                .iter()
+                .filter(|e| e.event_type == event_type)
+                .filter_map(|e| e.notify.map(|n| (n, e.context)))
+                .collect()
+        });
+
+    for (notify, context) in events.iter() {
+        notify(core::ptr::null_mut(), *context);
+    }
+
+    events.len()
+}
+
+/// Signal all `ExitBootServices()` events of the current thread
+pub(crate) fn signal_exit_boot_services() -> usize {
+    signal_events(efi::EVT_SIGNAL_EXIT_BOOT_SERVICES)
+}
+
+/// Invalidate the map key of the next `ExitBootServices()` calls
+///
+/// Make the next `count` calls to `ExitBootServices()` of the current thread
+/// change the memory map before checking the map key, so they fail like on
+/// firmware where an event callback allocated memory in the meantime.
+pub(crate) fn exit_stale(count: usize) {
+    EXIT_STALE.with(|v| v.set(count));
+}
+
+/// Return the number of live pages of the current thread
+pub(crate) fn pages_live() -> usize {
+    PAGES.with(|v| {
+        v.borrow()
+            .iter()
+            .map(|b| b.allocated.iter().filter(|v| **v).count())
+            .sum()
+    })
+}
+
+// Every change to the allocations of the mock changes its memory map, and
+// thus invalidates the current map key.
+fn bump_map_key() {
+    MAP_KEY.with(|v| v.set(v.get() + 1));
+}
+
+/// Return the current map key of the current thread
+pub(crate) fn map_key() -> usize {
+    MAP_KEY.with(|v| v.get())
+}
+
+// The memory map reported by the mock. Its content is static, except that
+// all live pool memory is reported as part of the `LOADER_DATA` region, in
+// whole pages like firmware carves the pool from pages. The map key
+// reflects the allocations of the mock. Descriptors are reported with
+// padding behind them, like firmware is allowed to, so callers must honor
+// the descriptor size.
+const MEMORY_MAP: [(efi::MemoryType, u64); 3] = [
+    (efi::CONVENTIONAL_MEMORY, 256),
+    (efi::LOADER_DATA, 16),
+    (efi::BOOT_SERVICES_DATA, 64),
+];
+const DESCRIPTOR_SIZE: usize =
+    core::mem::size_of::<efi::MemoryDescriptor>() + 8;
+
+extern "efiapi" fn get_memory_map(
+    map_size: *mut usize,
+    map: *mut efi::MemoryDescriptor,
+    map_key: *mut usize,
+    descriptor_size: *mut usize,
+    descriptor_version: *mut u32,
+) -> efi::Status {
+    let size = MEMORY_MAP.len() * DESCRIPTOR_SIZE;
+
+    unsafe {
+        *descriptor_size = DESCRIPTOR_SIZE;
+        if *map_size < size {
+            *map_size = size;
+            return efi::Status::BUFFER_TOO_SMALL;
+        }
+
+        let bytes = POOL_BYTES.with(|v| v.get());
+        let pool =
+            bytes / PAGE_SIZE + usize::from(bytes & (PAGE_SIZE - 1) != 0);
+        let mut address = 0x10_0000u64;
+        for (i, (memory_type, pages)) in MEMORY_MAP.iter().enumerate() {
+            let pages = match *memory_type {
+                efi::LOADER_DATA => pages + pool as u64,
+                _ => *pages,
+            };
+            let d = (map as *mut u8).add(i * DESCRIPTOR_SIZE);
+            core::ptr::write_bytes(d, 0xa5, DESCRIPTOR_SIZE);
+            core::ptr::write_unaligned(
+                d as *mut efi::MemoryDescriptor,
+                efi::MemoryDescriptor {
+                    r#type: *memory_type,
+                    physical_start: address,
+                    virtual_start: 0,
+                    number_of_pages: pages,
+                    attribute: 0,
+                },
+            );
+            address += pages * PAGE_SIZE as u64;
+        }
+
+        *map_size = size;
+        *map_key = MAP_KEY.with(|v| v.get());
+        *descriptor_version = efi::MEMORY_DESCRIPTOR_VERSION;
+    }
+
+    efi::Status::SUCCESS
+}
+
+extern "efiapi" fn exit_boot_services(
+    _image_handle: efi::Handle,
+    map_key: usize,
+) -> efi::Status {
+    if EXIT_STALE.with(|v| v.replace(v.get().saturating_sub(1))) > 0 {
+        bump_map_key();
+    }
+    if map_key != MAP_KEY.with(|v| v.get()) {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    signal_exit_boot_services();
+    efi::Status::SUCCESS
+}
+
+extern "efiapi" fn raise_tpl(new_tpl: efi::Tpl) -> efi::Tpl {
+    TPL_RAISES.with(|v| v.set(v.get() + 1));
+    if new_tpl < tpl() {
+        TPL_INVERSIONS.with(|v| v.set(v.get() + 1));
+    }
+    TPL.with(|v| v.replace(new_tpl))
+}
+
+extern "efiapi" fn restore_tpl(old_tpl: efi::Tpl) {
+    if old_tpl > tpl() {
+        TPL_INVERSIONS.with(|v| v.set(v.get() + 1));
+    }
+    TPL.with(|v| v.set(old_tpl));
+}
+
+extern "efiapi" fn allocate_pool(
+    memory_type: efi::MemoryType,
+    size: usize,
+    buffer: *mut *mut core::ffi::c_void,
+) -> efi::Status {
+    POOL_TPL.with(|v| v.set(tpl()));
+    if let Some(status) = POOL_FAIL.with(|v| v.get()) {
+        return status;
+    }
+
+    let header = core::mem::size_of::<PoolHeader>();
+    let misalign = POOL_MISALIGN.with(|v| v.get());
+    let align = if POOL_HIGH.with(|v| v.get()) {
+        POOL_HIGH_ALIGN
+    } else {
+        8
+    };
+    let layout = match size
+        .checked_add(header + misalign)
+        .and_then(|v| std::alloc::Layout::from_size_align(v, align).ok())
+    {
+        Some(v) => v,
+        None => return efi::Status::OUT_OF_RESOURCES,
+    };
+
+    unsafe {
+        let ptr = std::alloc::alloc(layout);
+        if ptr.is_null() {
+            return efi::Status::OUT_OF_RESOURCES;
+        }
+
+        core::ptr::write(
+            ptr as *mut PoolHeader,
+            PoolHeader {
+                size,
+                misalign,
+                align,
+            },
+        );
+        *buffer = ptr.add(header + misalign) as *mut core::ffi::c_void;
+    }
+
+    POOL_LIVE.with(|v| v.set(v.get() + 1));
+    POOL_BYTES.with(|v| v.set(v.get() + layout.size()));
+    POOL_MEMORY_TYPE.with(|v| v.set(Some(memory_type)));
+    bump_map_key();
+    efi::Status::SUCCESS
+}
+
+extern "efiapi" fn free_pool(buffer: *mut core::ffi::c_void) -> efi::Status {
+    POOL_TPL.with(|v| v.set(tpl()));
+    if buffer.is_null() {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    let header = core::mem::size_of::<PoolHeader>();
+
+    // The header is aligned, so any misalignment of the block is the offset
+    // it was placed at.
+    let misalign = buffer as usize & 7;
+
+    unsafe {
+        let ptr = (buffer as *mut u8).sub(header + misalign);
+        let h = core::ptr::read(ptr as *mut PoolHeader);
+        assert_eq!(h.misalign, misalign);
+        let layout = std::alloc::Layout::from_size_align(
+            h.size + header + misalign,
+            h.align,
+        )
+        .unwrap();
+
+        std::alloc::dealloc(ptr, layout);
+        POOL_BYTES.with(|v| v.set(v.get() - layout.size()));
+    }
+
+    POOL_LIVE.with(|v| v.set(v.get() - 1));
+    bump_map_key();
+    efi::Status::SUCCESS
+}
+
+extern "efiapi" fn allocate_pages(
+    allocate_type: efi::AllocateType,
+    _memory_type: efi::MemoryType,
+    pages: usize,
+    memory: *mut efi::PhysicalAddress,
+) -> efi::Status {
+    // Only `AllocateAnyPages` and `AllocateMaxAddress` can be served, since
+    // we have no control over the addresses of the standard library.
+    if allocate_type == efi::ALLOCATE_ADDRESS {
+        return efi::Status::NOT_FOUND;
+    }
+    if pages == 0 {
+        return efi::Status::INVALID_PARAMETER;
+    }
+
+    let layout = match pages
+        .checked_mul(PAGE_SIZE)
+        .and_then(|v| std::alloc::Layout::from_size_align(v, PAGE_SIZE).ok())
+    {
+        Some(v) => v,
+        None => return efi::Status::OUT_OF_RESOURCES,
+    };
+
+    let ptr = unsafe { std::alloc::alloc(layout) };
+    if ptr.is_null() {
+        return efi::Status::OUT_OF_RESOURCES;
+    }
+
+    let base = ptr as usize;
+    if allocate_type == efi::ALLOCATE_MAX_ADDRESS {
+        let max = unsafe { *memory };
+        if (base + layout.size() - 1) as efi::PhysicalAddress > max {
+            unsafe { std::alloc::dealloc(ptr, layout) };
+            return efi::Status::NOT_FOUND;
+        }
+    }
+
+    PAGES.with(|v| {
+        v.borrow_mut().push(PageBlock {
+            base,
+            allocated: vec![true; pages],
+        })
+    });
+
+    unsafe { *memory = base as efi::PhysicalAddress };
+    bump_map_key();
+    efi::Status::SUCCESS
+}
+
+extern "efiapi" fn free_pages(
+    memory: efi::PhysicalAddress,
+    pages: usize,
+) -> efi::Status {
+    let addr = memory as usize;
+
+    PAGES.with(|v| {
+        let mut blocks = v.borrow_mut();
+
+        // Find the block containing the range, and verify that all pages of
+        // the range are currently allocated.
+        let idx = blocks.iter().position(|b| {
+            addr >= b.base
+                && (addr - b.base) & (PAGE_SIZE - 1) == 0
+                && (addr - b.base) / PAGE_SIZE + pages <= b.allocated.len()
+        });
+        let idx = match idx {
+            Some(v) => v,
+            None => return efi::Status::NOT_FOUND,
+        };
+
+        let block = &mut blocks[idx];
+        let first = (addr - block.base) / PAGE_SIZE;
+        let range = &mut block.allocated[first..first + pages];
+        if range.iter().any(|v| !*v) {
+            return efi::Status::NOT_FOUND;
+        }
+        range.iter_mut().for_each(|v| *v = false);
+
+        if block.allocated.iter().all(|v| !*v) {
+            let block = blocks.swap_remove(idx);
+            let layout = std::alloc::Layout::from_size_align(
+                block.allocated.len() * PAGE_SIZE,
+                PAGE_SIZE,
+            )
+            .unwrap();
+            unsafe { std::alloc::dealloc(block.base as *mut u8, layout) };
+        }
+
+        bump_map_key();
+        efi::Status::SUCCESS
+    })
+}
+
+extern "efiapi" fn copy_mem(
+    destination: *mut core::ffi::c_void,
+    source: *mut core::ffi::c_void,
+    length: usize,
+) {
+    // `CopyMem()` must handle overlapping buffers.
+    unsafe {
+        core::ptr::copy(source as *const u8, destination as *mut u8, length)
+    };
+}
+
+extern "efiapi" fn set_mem(
+    buffer: *mut core::ffi::c_void,
+    size: usize,
+    value: u8,
+) {
+    SET_MEM_CALLS.with(|v| v.set(v.get() + 1));
+    unsafe { core::ptr::write_bytes(buffer as *mut u8, value, size) };
+}
+
+// The image handle of the mock points to its `LoadedImage` protocol, which is
+// the only protocol the mock provides.
+extern "efiapi" fn handle_protocol(
+    handle: efi::Handle,
+    protocol: *mut efi::Guid,
+    interface: *mut *mut core::ffi::c_void,
+) -> efi::Status {
+    let guid = unsafe { *protocol };
+
+    if handle.is_null() || guid != efi::protocols::loaded_image::PROTOCOL_GUID
+    {
+        return efi::Status::UNSUPPORTED;
+    }
+
+    unsafe { *interface = handle };
+    efi::Status::SUCCESS
+}
+
+extern "efiapi" fn who_am_i(
+    _this: *mut efi::protocols::mp_services::Protocol,
+    number: *mut usize,
+) -> efi::Status {
+    unsafe { *number = PROCESSOR.with(|v| v.get()) };
+    efi::Status::SUCCESS
+}
+
+extern "efiapi" fn create_event(
+    event_type: u32,
+    _notify_tpl: efi::Tpl,
+    notify: Option<efi::EventNotify>,
+    context: *mut core::ffi::c_void,
+    event: *mut efi::Event,
+) -> efi::Status {
+    let id = EVENTS.with(|v| {
+        let mut v = v.borrow_mut();
+        v.push(MockEvent {
+            event_type,
+            notify,
+            context,
+        });
+        v.len()
+    });
+
+    // Events are identified by their index, offset by one so they are never
+    // NULL.
+    unsafe { *event = id as efi::Event };
+    efi::Status::SUCCESS
+}
+
+extern "efiapi" fn convert_pointer(
+    _debug_disposition: usize,
+    address: *mut *mut core::ffi::c_void,
+) -> efi::Status {
+    unsafe {
+        *address = (*address as *mut u8).wrapping_add(VIRTUAL_OFFSET)
+            as *mut core::ffi::c_void;
+    }
+    efi::Status::SUCCESS
+}
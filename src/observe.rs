@@ -0,0 +1,303 @@
+//! Allocation Observers
+//!
+//! This module defines the `AllocObserver` trait, which allows hooking into
+//! the allocation paths of this crate. Observers are notified about every
+//! allocation, deallocation and allocation failure, and can thus implement
+//! cross-cutting concerns like statistics, tracing or leak-checks, without
+//! this crate having to anticipate every need.
+//!
+//! Observers are attached to an allocator via `Allocator::with_observer()`,
+//! or to a global bridge via the constant `Bridge::with_observer()`. Only a
+//! single observer can be attached to an allocator or bridge. To attach
+//! multiple observers, combine them via `Chain`.
+//!
+//! `Budget` is an observer for regression tests, which asserts that a code
+//! path stays within a given number of allocations and bytes.
+
+use core::sync::atomic;
+
+/// Allocation Observer
+///
+/// This trait is implemented by types that want to be notified about memory
+/// allocations. All methods have empty default implementations, so observers
+/// only need to implement the notifications they are interested in.
+///
+/// Observers are invoked synchronously on the allocation path. They must not
+/// allocate memory from the allocator they observe, and they should be cheap,
+/// since they delay every allocation.
+pub trait AllocObserver {
+    /// Notify about a successful allocation
+    ///
+    /// This is invoked after the memory block at `ptr` was allocated for the
+    /// given layout.
+    fn on_alloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {}
+
+    /// Notify about a deallocation
+    ///
+    /// This is invoked right before the memory block at `ptr` with the given
+    /// layout is released.
+    fn on_dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {}
+
+    /// Notify about a failed allocation
+    ///
+    /// This is invoked after an allocation for the given layout failed.
+    fn on_failure(&self, _layout: core::alloc::Layout) {}
+
+    /// Notify about a leaked block
+    ///
+    /// This is invoked instead of `on_dealloc()` if the memory block at `ptr`
+    /// with the given layout was released, but could not be returned to the
+    /// firmware (e.g., a release through a guarded bridge above
+    /// `TPL_NOTIFY`). The block stays allocated for the rest of the boot.
+    fn on_leak(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {}
+}
+
+/// Observer Chain
+///
+/// This combines two observers into one. Every notification is forwarded to
+/// the first observer and then to the second. Chains can be nested to combine
+/// any number of observers.
+pub struct Chain<A, B>(pub A, pub B);
+
+/// Allocation Budget
+///
+/// This observer counts allocations and the bytes allocated by them, so tests
+/// can assert that a code path does not exceed a given budget. This keeps the
+/// allocation count of a code path from creeping up unnoticed. Deallocations
+/// are not subtracted, since every allocation has its cost. Note that a
+/// resize in place is reported as release plus allocation, and thus counts
+/// as an allocation as well.
+///
+/// Use `measure()` to run a code path and retrieve its usage, or
+/// `assert_within()` to verify it stays within a budget.
+pub struct Budget {
+    allocations: atomic::AtomicUsize,
+    bytes: atomic::AtomicUsize,
+}
+
+/// Allocation Budget Usage
+///
+/// This is the usage recorded by a `Budget`, as returned by
+/// `Budget::usage()`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Usage {
+    /// Number of successful allocations.
+    pub allocations: usize,
+    /// Number of bytes allocated, as requested by the caller.
+    pub bytes: usize,
+}
+
+impl Budget {
+    /// Create Budget
+    ///
+    /// Create a new budget observer with no usage recorded. This is a
+    /// constant function, so the observer can be placed in a static variable
+    /// and attached to allocators.
+    pub const fn new() -> Budget {
+        Budget {
+            allocations: atomic::AtomicUsize::new(0),
+            bytes: atomic::AtomicUsize::new(0),
+        }
+    }
+
+    /// Reset the recorded usage to 0
+    pub fn reset(&self) {
+        self.allocations.store(0, atomic::Ordering::Relaxed);
+        self.bytes.store(0, atomic::Ordering::Relaxed);
+    }
+
+    /// Return the usage recorded since the last reset
+    pub fn usage(&self) -> Usage {
+        Usage {
+            allocations: self.allocations.load(atomic::Ordering::Relaxed),
+            bytes: self.bytes.load(atomic::Ordering::Relaxed),
+        }
+    }
+
+    /// Measure a Code Path
+    ///
+    /// Reset the recorded usage, run `f` and return its result together with
+    /// the usage recorded while it ran. Allocations of other code running
+    /// concurrently on the observed allocators are included.
+    pub fn measure<R>(&self, f: impl FnOnce() -> R) -> (R, Usage) {
+        self.reset();
+        let r = f();
+        (r, self.usage())
+    }
+
+    /// Assert a Code Path stays within Budget
+    ///
+    /// Run `f` via `measure()` and panic if it performed more than
+    /// `allocations` allocations, or allocated more than `bytes` bytes.
+    /// Otherwise, the result of `f` is returned.
+    pub fn assert_within<R>(
+        &self,
+        allocations: usize,
+        bytes: usize,
+        f: impl FnOnce() -> R,
+    ) -> R {
+        let (r, usage) = self.measure(f);
+
+        assert!(
+            usage.allocations <= allocations && usage.bytes <= bytes,
+            "allocation budget exceeded: {} allocations of {} bytes, \
+             budget is {} allocations of {} bytes",
+            usage.allocations,
+            usage.bytes,
+            allocations,
+            bytes,
+        );
+
+        r
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AllocObserver for () {}
+
+impl<T: AllocObserver + ?Sized> AllocObserver for &T {
+    fn on_alloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        (**self).on_alloc(ptr, layout)
+    }
+
+    fn on_dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        (**self).on_dealloc(ptr, layout)
+    }
+
+    fn on_failure(&self, layout: core::alloc::Layout) {
+        (**self).on_failure(layout)
+    }
+}
+
+impl AllocObserver for Budget {
+    fn on_alloc(&self, _ptr: *mut u8, layout: core::alloc::Layout) {
+        self.allocations.fetch_add(1, atomic::Ordering::Relaxed);
+        self.bytes.fetch_add(layout.size(), atomic::Ordering::Relaxed);
+    }
+}
+
+impl<A: AllocObserver, B: AllocObserver> AllocObserver for Chain<A, B> {
+    fn on_alloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        self.0.on_alloc(ptr, layout);
+        self.1.on_alloc(ptr, layout);
+    }
+
+    fn on_dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        self.0.on_dealloc(ptr, layout);
+        self.1.on_dealloc(ptr, layout);
+    }
+
+    fn on_failure(&self, layout: core::alloc::Layout) {
+        self.0.on_failure(layout);
+        self.1.on_failure(layout);
+    }
+
+    fn on_leak(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        self.0.on_leak(ptr, layout);
+        self.1.on_leak(ptr, layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r_efi::efi;
+
+    // Run allocations through an allocator with a budget attached and
+    // verify the usage is recorded.
+    #[test]
+    fn budget() {
+        static BUDGET: Budget = Budget::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(st, efi::LOADER_DATA)
+        }
+        .with_observer(&BUDGET);
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        let (_, usage) = BUDGET.measure(|| unsafe {
+            for _ in 0..3 {
+                let ptr = allocator.alloc(layout);
+                allocator.dealloc(ptr, layout);
+            }
+        });
+        assert_eq!(usage, Usage { allocations: 3, bytes: 192 });
+
+        BUDGET.assert_within(1, 64, || unsafe {
+            allocator.dealloc(allocator.alloc(layout), layout);
+        });
+        assert_eq!(BUDGET.usage(), Usage { allocations: 1, bytes: 64 });
+    }
+
+    // Verify that a chain forwards all notifications to both of its
+    // observers, first to the left one, then to the right one.
+    #[test]
+    fn chain() {
+        struct Log(&'static str);
+
+        impl AllocObserver for Log {
+            fn on_alloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+                EVENTS.lock().unwrap().push((self.0, "alloc"));
+            }
+
+            fn on_dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+                EVENTS.lock().unwrap().push((self.0, "dealloc"));
+            }
+
+            fn on_failure(&self, _layout: core::alloc::Layout) {
+                EVENTS.lock().unwrap().push((self.0, "failure"));
+            }
+        }
+
+        static EVENTS: std::sync::Mutex<Vec<(&str, &str)>> =
+            std::sync::Mutex::new(Vec::new());
+        static CHAIN: Chain<Log, Log> = Chain(Log("a"), Log("b"));
+
+        let observer: &dyn AllocObserver = &&CHAIN;
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        let mut block = [0u8; 64];
+
+        observer.on_alloc(block.as_mut_ptr(), layout);
+        observer.on_dealloc(block.as_mut_ptr(), layout);
+        observer.on_failure(layout);
+
+        assert_eq!(
+            *EVENTS.lock().unwrap(),
+            [
+                ("a", "alloc"),
+                ("b", "alloc"),
+                ("a", "dealloc"),
+                ("b", "dealloc"),
+                ("a", "failure"),
+                ("b", "failure"),
+            ],
+        );
+    }
+
+    // Verify that exceeding a budget panics.
+    #[test]
+    #[should_panic(expected = "allocation budget exceeded")]
+    fn budget_exceeded() {
+        static BUDGET: Budget = Budget::new();
+
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(st, efi::LOADER_DATA)
+        }
+        .with_observer(&BUDGET);
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        BUDGET.assert_within(1, 128, || unsafe {
+            allocator.dealloc(allocator.alloc(layout), layout);
+            allocator.dealloc(allocator.alloc(layout), layout);
+        });
+    }
+}
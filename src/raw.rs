@@ -6,89 +6,356 @@
 //!
 //! Use of the raw allocator is only recommended if the other exposes APIs are
 //! not an option.
+//!
+//! The `align` module exposes the alignment arithmetic used by the raw
+//! allocator, for code that has to call into `AllocatePool()` directly.
 
+use core::sync::atomic;
 use r_efi::efi;
 
-// UEFI guarantees 8-byte alignments through `AllocatePool()`. Any request
-// higher than this alignment needs to take special precautions to align the
-// returned pointer, and revert that step when freeing the memory block again.
-const POOL_ALIGNMENT: usize = 8usize;
+pub mod align {
+    //! Alignment Helpers
+    //!
+    //! UEFI guarantees 8-byte alignment for blocks returned by
+    //! `AllocatePool()`, but has no way to request higher alignments. This
+    //! module provides the arithmetic that `alloc()` and `dealloc()` use to
+    //! serve over-aligned requests from the pool allocator. It is public so
+    //! other code that calls into `AllocatePool()` directly can share the
+    //! block format.
+    //!
+    //! Blocks produced by these helpers match the blocks of `alloc()` only
+    //! without the `pool_quirks` feature. With it, `alloc()` gives every
+    //! block a marker and reserves extra space to realign misaligned pool
+    //! pointers, which these helpers do not. Hence, blocks of `alloc()` must
+    //! then only be released via `dealloc()`, and blocks of these helpers
+    //! never via `dealloc()`.
+    //!
+    //! An over-aligned request is served as follows:
+    //!
+    //!  1. `align_request()` computes the size to request from
+    //!     `AllocatePool()`, which includes space for padding and a marker.
+    //!
+    //!  2. `align_block()` takes the pointer returned by `AllocatePool()`,
+    //!     aligns it, and packs the original address as marker directly in
+    //!     front of the aligned block.
+    //!
+    //!  3. `unalign_block()` unpacks the marker again and returns the
+    //!     original address, which is to be passed to `FreePool()`.
+    //!
+    //! Requests with an alignment of at most `POOL_ALIGNMENT` are passed
+    //! through unchanged by all helpers. Hence, a block is always released
+    //! correctly as long as the same alignment is passed to all three
+    //! helpers.
 
-// Alignment Marker
-//
-// Since UEFI has no functions to allocate blocks of arbitrary alignment, we
-// have to work around this. We extend the allocation size by the required
-// alignment and then offset the pointer before returning it. This will
-// properly align the pointer to the given request.
-//
-// However, when freeing memory again, we have to somehow get back the original
-// pointer. Therefore, we store the original address directly in front of the
-// memory block that we just aligned. When freeing memory, we simply retrieve
-// this marker and free the original address.
-#[repr(C)]
-struct Marker(*mut u8);
-
-fn align_request(size: usize, align: usize) -> usize {
-    // If the alignment request is within UEFI guarantees, there is no need to
-    // adjust the size request. In all other cases, we might have to align the
-    // allocated memory block. Hence, we increment the request size by the
-    // alignment size. Strictly speaking, we only need `align - POOL_ALIGNMENT`
-    // as additional space, since the pool alignment is always guaranteed by
-    // UEFI. However, by adding the full alignment we are guaranteed
-    // `POOL_ALIGNMENT` extra space. This extra space is used to store a marker
-    // so we can retrieve the original pointer when freeing the memory space.
-    if align > POOL_ALIGNMENT {
-        size + align
+    /// Alignment Guaranteed by `AllocatePool()`
+    ///
+    /// UEFI guarantees 8-byte alignment for all pool allocations. Any request
+    /// higher than this alignment needs to take special precautions to align
+    /// the returned pointer, and revert that step when freeing the memory
+    /// block again.
+    pub const POOL_ALIGNMENT: usize = 8usize;
+
+    // Alignment Marker
+    //
+    // Since UEFI has no functions to allocate blocks of arbitrary alignment,
+    // we have to work around this. We extend the allocation size by the
+    // required alignment and then offset the pointer before returning it.
+    // This will properly align the pointer to the given request.
+    //
+    // However, when freeing memory again, we have to somehow get back the
+    // original pointer. Therefore, we store the original address directly in
+    // front of the memory block that we just aligned. When freeing memory, we
+    // simply retrieve this marker and free the original address.
+    #[repr(C)]
+    struct Marker(*mut u8);
+
+    // The marker is stored in the `POOL_ALIGNMENT` bytes directly in front of
+    // an over-aligned block. Verify at compile-time that it fits into this
+    // space and that its alignment is guaranteed there. This can only fail on
+    // targets with pointers wider than 64 bits, which UEFI does not support.
+    const _: () = assert!(POOL_ALIGNMENT >= core::mem::size_of::<Marker>());
+    const _: () = assert!(POOL_ALIGNMENT >= core::mem::align_of::<Marker>());
+
+    /// Compute Pool Request Size
+    ///
+    /// Return the number of bytes to request from `AllocatePool()` to serve a
+    /// block of `size` bytes with the alignment `align`. If `align` does not
+    /// exceed `POOL_ALIGNMENT`, this is `size`. Otherwise, the request is
+    /// extended by `align` bytes, which guarantees room for both the
+    /// alignment padding and the marker in front of the block.
+    ///
+    /// `align` must be a power of two. `None` is returned if the request
+    /// overflows the address-space, in which case it cannot be served.
+    pub fn align_request(size: usize, align: usize) -> Option<usize> {
+        // Strictly speaking, we only need `align - POOL_ALIGNMENT` as
+        // additional space, since the pool alignment is always guaranteed by
+        // UEFI. However, by adding the full alignment we are guaranteed
+        // `POOL_ALIGNMENT` extra space. This extra space is used to store a
+        // marker so we can retrieve the original pointer when freeing the
+        // memory space.
+        if align > POOL_ALIGNMENT {
+            size.checked_add(align)
+        } else {
+            Some(size)
+        }
+    }
+
+    /// Align a Pool Block
+    ///
+    /// Take a pointer returned by `AllocatePool()` and return the pointer to
+    /// the block aligned to `align`. For over-aligned requests, the original
+    /// address is packed as marker directly in front of the returned block.
+    /// For all other requests, `ptr` is returned unchanged.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// `ptr` must point to a pool block of at least `align_request(size,
+    /// align)` bytes, aligned to `POOL_ALIGNMENT`. The block must be writable.
+    /// `align` must be a power of two. The returned block is valid for `size`
+    /// bytes.
+    pub unsafe fn align_block(ptr: *mut u8, align: usize) -> *mut u8 {
+        if align > POOL_ALIGNMENT {
+            // In `align_request()` we guarantee the allocation size includes
+            // an additional `align` bytes. Since the pool allocation already
+            // guaranteed an alignment of `POOL_ALIGNMENT`, we know that
+            // `offset >= POOL_ALIGNMENT` here. That `POOL_ALIGNMENT` serves
+            // the needs of our `Marker` object is verified at compile-time.
+            let offset = align - (ptr as usize & (align - 1));
+            assert!(offset >= POOL_ALIGNMENT);
+
+            // We calculated the alignment-offset, so adjust the pointer and
+            // store the original address directly in front. This will allow
+            // `unalign_block()` to retrieve the original address, so it can
+            // free the entire memory block.
+            let aligned = ptr.add(offset);
+            core::ptr::write((aligned as *mut Marker).offset(-1), Marker(ptr));
+            aligned
+        } else {
+            ptr
+        }
+    }
+
+    /// Unalign a Pool Block
+    ///
+    /// This undoes what `align_block()` did. For over-aligned requests, the
+    /// marker in front of the block is unpacked and the original address is
+    /// returned. For all other requests, `ptr` is returned unchanged. The
+    /// returned pointer is suitable to be passed to `FreePool()`.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// `ptr` must have been returned by `align_block()` with the same
+    /// `align`, and the marker in front of it must not have been overwritten.
+    pub unsafe fn unalign_block(ptr: *mut u8, align: usize) -> *mut u8 {
+        if align > POOL_ALIGNMENT {
+            core::ptr::read((ptr as *mut Marker).offset(-1)).0
+        } else {
+            ptr
+        }
+    }
+}
+
+// Number of pool allocations that returned a pointer violating the pool
+// alignment guarantee of UEFI.
+static POOL_QUIRKS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+// Return the alignment that determines the block format of a request with
+// the given alignment. With the `pool_quirks` feature, every block carries a
+// marker, so a misaligned pool pointer can be realigned regardless of the
+// requested alignment.
+#[cfg(feature = "pool_quirks")]
+fn block_align(align: usize) -> usize {
+    core::cmp::max(align, 2 * align::POOL_ALIGNMENT)
+}
+
+#[cfg(not(feature = "pool_quirks"))]
+fn block_align(align: usize) -> usize {
+    align
+}
+
+// Return the size to request from the pool allocator for a block of `size`
+// bytes with the block alignment `align` (see `block_align()`). With the
+// `pool_quirks` feature, another `align` bytes are reserved, so a misaligned
+// pool pointer can be moved to the next aligned address with room for the
+// marker.
+fn request_size(size: usize, align: usize) -> Option<usize> {
+    let size = align::align_request(size, align)?;
+
+    if cfg!(feature = "pool_quirks") {
+        size.checked_add(align)
     } else {
-        size
-    }
-}
-
-unsafe fn align_block(ptr: *mut u8, align: usize) -> *mut u8 {
-    // This function takes a pointer returned by the pool-allocator, and aligns
-    // it to the requested alignment. If this alignment is smaller than the
-    // guaranteed pool alignment, there is nothing to be done. If it is bigger,
-    // we will have to offset the pointer. We rely on the caller using
-    // `align_request()` to increase the allocation size beforehand. We then
-    // store the original address as `Marker` in front of the aligned pointer,
-    // so `unalign_block()` can retrieve it again.
-    if align > POOL_ALIGNMENT {
-        // In `align_request()` we guarantee the allocation size includes an
-        // additional `align` bytes. Since the pool allocation already
-        // guaranteed an alignment of `POOL_ALIGNMENT`, we know that
-        // `offset >= POOL_ALIGNMENT` here. We then verify that
-        // `POOL_ALIGNMENT` serves the needs of our `Marker` object. Note that
-        // all but the first assertion are constant expressions, so the
-        // compiler will optimize them away.
-        let offset = align - (ptr as usize & (align - 1));
-        assert!(offset >= POOL_ALIGNMENT);
-        assert!(POOL_ALIGNMENT >= core::mem::size_of::<Marker>());
-        assert!(POOL_ALIGNMENT >= core::mem::align_of::<Marker>());
-
-        // We calculated the alignment-offset, so adjust the pointer and store
-        // the original address directly in front. This will allow
-        // `unalign_block()` to retrieve the original address, so it can free
-        // the entire memory block.
+        Some(size)
+    }
+}
+
+// Handle a pool pointer that violates `POOL_ALIGNMENT`. With the
+// `pool_quirks` feature, the block is aligned like any other block, but
+// with the extra space reserved by `request_size()` to keep room for the
+// marker. Otherwise, the block can only be served if it happens to satisfy
+// the requested alignment, since its format carries no marker. If it does
+// not, it is released again and the request fails.
+unsafe fn align_misaligned(
+    boot_services: *mut efi::BootServices,
+    ptr: *mut u8,
+    align: usize,
+) -> Result<*mut u8, efi::Status> {
+    POOL_QUIRKS.fetch_add(1, atomic::Ordering::Relaxed);
+
+    let align = block_align(align);
+    if cfg!(feature = "pool_quirks") {
+        let mut offset = align - (ptr as usize & (align - 1));
+        if offset < align::POOL_ALIGNMENT {
+            offset += align;
+        }
+
         let aligned = ptr.add(offset);
-        core::ptr::write((aligned as *mut Marker).offset(-1), Marker(ptr));
-        aligned
+        core::ptr::write((aligned as *mut *mut u8).offset(-1), ptr);
+        Ok(aligned)
+    } else if align <= align::POOL_ALIGNMENT
+        && ptr as usize & (align - 1) == 0
+    {
+        Ok(ptr)
     } else {
-        ptr
+        ((*boot_services).free_pool)(ptr as *mut _);
+        Err(efi::Status::ABORTED)
     }
 }
 
-unsafe fn unalign_block(ptr: *mut u8, align: usize) -> *mut u8 {
-    // This undoes what `align_block()` did. That is, we retrieve the original
-    // address that was stored directly in front of the aligned block, and
-    // return it to the caller. Note that this is only the case if the
-    // alignment exceeded the guaranteed alignment of the allocator.
-    if align > POOL_ALIGNMENT {
-        core::ptr::read((ptr as *mut Marker).offset(-1)).0
+/// Count Misaligned Pool Allocations
+///
+/// Return the number of pool allocations so far that returned a pointer
+/// violating the 8-byte alignment guaranteed by UEFI. Such firmware is
+/// buggy. Without the `pool_quirks` feature, allocations that cannot be
+/// served with such a pointer fail with `ABORTED`. With the feature, all
+/// blocks carry an alignment marker and misaligned pointers are realigned.
+pub fn pool_quirks() -> usize {
+    POOL_QUIRKS.load(atomic::Ordering::Relaxed)
+}
+
+// Return the number of bytes usable at `ptr`, which must be a block returned
+// by `alloc()` with the given layout. For over-aligned blocks, this includes
+// the part of the alignment padding that is located behind the block. Other
+// blocks report their requested size, since the pool allocator does not
+// expose the real size of its allocations.
+pub(crate) unsafe fn usable_size(
+    ptr: *mut u8,
+    layout: core::alloc::Layout,
+) -> usize {
+    let align = block_align(layout.align());
+
+    if align > align::POOL_ALIGNMENT {
+        // The request size was verified by `alloc()`.
+        let original = align::unalign_block(ptr, align);
+        request_size(layout.size(), align).unwrap()
+            - (ptr as usize - original as usize)
     } else {
-        ptr
+        layout.size()
+    }
+}
+
+// Check whether a block allocated with `old` can be reused for the layout
+// `new`. The block must be large enough, and `dealloc()` must find the
+// original pointer the same way for both layouts. The latter is the case if
+// neither is over-aligned, or if both use the same alignment.
+pub(crate) unsafe fn fits_in_place(
+    ptr: *mut u8,
+    old: core::alloc::Layout,
+    new: core::alloc::Layout,
+) -> bool {
+    let (old_align, new_align) =
+        (block_align(old.align()), block_align(new.align()));
+    let same_class = old_align == new_align
+        || (old_align <= align::POOL_ALIGNMENT
+            && new_align <= align::POOL_ALIGNMENT);
+
+    same_class && new.size() <= usable_size(ptr, old)
+}
+
+// Compute the CRC32 of the given byte iterator, as used by UEFI table
+// headers. This is the standard reflected CRC32 with polynomial 0x04c11db7.
+// It is implemented bitwise, since it is only used for diagnostics.
+#[cfg(any(test, feature = "check_tables"))]
+pub(crate) fn crc32(data: impl Iterator<Item = u8>) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
     }
+
+    !crc
 }
 
+// Compute the CRC32 of a UEFI table, as stored in its header. The checksum
+// covers `header_size` bytes, with the `crc32` member treated as 0.
+#[cfg(any(test, feature = "check_tables"))]
+pub(crate) unsafe fn table_crc32(hdr: *const efi::TableHeader) -> u32 {
+    let base = hdr as *const u8;
+    let crc_start = core::ptr::addr_of!((*hdr).crc32) as usize - hdr as usize;
+    let crc_end = crc_start + core::mem::size_of::<u32>();
+
+    crc32((0..(*hdr).header_size as usize).map(|i| {
+        if i >= crc_start && i < crc_end {
+            0
+        } else {
+            *base.add(i)
+        }
+    }))
+}
+
+// Verify the system-table and boot-services table before calling into the
+// firmware. A caller that retains a system-table pointer beyond its lifetime
+// (or whose tables were overwritten) would otherwise jump through a garbage
+// function pointer, with hard to diagnose effects. Instead, we panic with
+// the address of the offending table.
+//
+// The system-table only has its signature verified. The boot-services table
+// it points to is verified separately via `check_boot_services()`, so
+// callers that only deal with boot-services can skip the system-table.
+#[cfg(feature = "check_tables")]
+unsafe fn check_system_table(system_table: *mut efi::SystemTable) {
+    assert!(
+        (*system_table).hdr.signature == efi::SYSTEM_TABLE_SIGNATURE,
+        "invalid system-table signature at {:p}",
+        system_table,
+    );
+}
+
+#[cfg(not(feature = "check_tables"))]
+unsafe fn check_system_table(_system_table: *mut efi::SystemTable) {}
+
+// Verify the boot-services table, including its checksum.
+#[cfg(feature = "check_tables")]
+unsafe fn check_boot_services(bs: *mut efi::BootServices) {
+    assert!(
+        !bs.is_null() && (*bs).hdr.signature == efi::BOOT_SERVICES_SIGNATURE,
+        "invalid boot-services signature at {:p}",
+        bs,
+    );
+
+    // Bound the header size before reading the table, to avoid running off
+    // into unmapped memory if the header is garbage.
+    let size = (*bs).hdr.header_size as usize;
+    assert!(
+        size >= core::mem::size_of::<efi::TableHeader>()
+            && size <= 2 * core::mem::size_of::<efi::BootServices>(),
+        "invalid boot-services header size at {:p}",
+        bs,
+    );
+    assert!(
+        table_crc32(&(*bs).hdr) == (*bs).hdr.crc32,
+        "invalid boot-services checksum at {:p}",
+        bs,
+    );
+}
+
+#[cfg(not(feature = "check_tables"))]
+unsafe fn check_boot_services(_bs: *mut efi::BootServices) {}
+
 /// Allocate Memory from UEFI Boot-Services
 ///
 /// Use the UEFI `allocate_pool` boot-services to request a block of memory
@@ -119,6 +386,60 @@ pub unsafe fn alloc(
     layout: core::alloc::Layout,
     memory_type: efi::MemoryType,
 ) -> *mut u8 {
+    match alloc_status(system_table, layout, memory_type) {
+        Ok(ptr) => ptr,
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+// This is the backend of `alloc()`, but it retains the UEFI status code of a
+// failed request, rather than folding it into a null-pointer. The same safety
+// requirements as for `alloc()` apply. On success, the returned pointer is
+// guaranteed to be non-null.
+pub(crate) unsafe fn alloc_status(
+    system_table: *mut efi::SystemTable,
+    layout: core::alloc::Layout,
+    memory_type: efi::MemoryType,
+) -> Result<*mut u8, efi::Status> {
+    check_system_table(system_table);
+    boot_services_alloc_status(
+        (*system_table).boot_services,
+        layout,
+        memory_type,
+    )
+}
+
+/// Allocate Memory from UEFI Boot-Services Table
+///
+/// This is the same as `alloc()`, but takes the boot-services table directly,
+/// rather than the system-table. This serves callers that have no system-table
+/// at hand, and avoids the indirection through it.
+///
+/// Safety
+/// ------
+///
+/// The same requirements as for `alloc()` apply, but `allocate_pool` is called
+/// via the specified boot-services table. The returned pointer must be
+/// released via `dealloc_from_boot_services()`, or via `dealloc()` with a
+/// system-table referring to the same boot-services.
+pub unsafe fn alloc_from_boot_services(
+    boot_services: *mut efi::BootServices,
+    layout: core::alloc::Layout,
+    memory_type: efi::MemoryType,
+) -> *mut u8 {
+    match boot_services_alloc_status(boot_services, layout, memory_type) {
+        Ok(ptr) => ptr,
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+// This is the backend of both `alloc_status()` and
+// `alloc_from_boot_services()`.
+pub(crate) unsafe fn boot_services_alloc_status(
+    boot_services: *mut efi::BootServices,
+    layout: core::alloc::Layout,
+    memory_type: efi::MemoryType,
+) -> Result<*mut u8, efi::Status> {
     // `Layout` guarantees the size+align combination does not overflow.
     let align = layout.align();
     let size = layout.size();
@@ -129,19 +450,20 @@ pub unsafe fn alloc(
     // We need extra allocation space to guarantee large alignment requests. If
     // `size+align` overflows, there will be insufficient address-space for the
     // request, so make it fail early.
-    if size.checked_add(align).is_none() {
-        return core::ptr::null_mut();
-    }
+    let size_allocated = match request_size(size, block_align(align)) {
+        Some(v) => v,
+        None => return Err(efi::Status::OUT_OF_RESOURCES),
+    };
 
     // We forward the allocation request to `AllocatePool()`. This takes the
     // memory-type and size as argument, and places a pointer to the allocation
     // in an output argument. Note that UEFI guarantees 8-byte alignment (i.e.,
-    // `POOL_ALIGNMENT`). To support higher alignments, see the
-    // `align_request() / align_block() / unalign_block()` helpers.
+    // `POOL_ALIGNMENT`). To support higher alignments, see the helpers of
+    // the `align` module.
     let mut ptr: *mut core::ffi::c_void = core::ptr::null_mut();
-    let size_allocated = align_request(size, align);
+    check_boot_services(boot_services);
     let r = unsafe {
-        ((*(*system_table).boot_services).allocate_pool)(
+        ((*boot_services).allocate_pool)(
             memory_type,
             size_allocated,
             &mut ptr,
@@ -154,13 +476,21 @@ pub unsafe fn alloc(
     // since the 0-page is usually unmapped and not available for
     // EFI_CONVENTIONAL_MEMORY, a NULL pointer cannot be a valid return
     // pointer. Therefore, we treat both a function failure as well as a NULL
-    // pointer the same.
+    // pointer the same. For the latter, we report `OUT_OF_RESOURCES`, since
+    // there is no status code to forward.
     // No known UEFI implementation returns `NULL`, hence this is mostly a
     // safety net in case any unknown implementation fails to adhere.
-    if r.is_error() || ptr.is_null() {
-        core::ptr::null_mut()
+    // Buggy firmware might violate the pool alignment guarantee, which the
+    // block format relies on. See `align_misaligned()` for how this is
+    // handled.
+    if r.is_error() {
+        Err(r)
+    } else if ptr.is_null() {
+        Err(efi::Status::OUT_OF_RESOURCES)
+    } else if ptr as usize & (align::POOL_ALIGNMENT - 1) != 0 {
+        unsafe { align_misaligned(boot_services, ptr as *mut u8, align) }
     } else {
-        unsafe { align_block(ptr as *mut u8, align) }
+        Ok(unsafe { align::align_block(ptr as *mut u8, block_align(align)) })
     }
 }
 
@@ -182,19 +512,41 @@ pub unsafe fn dealloc(
     system_table: *mut efi::SystemTable,
     ptr: *mut u8,
     layout: core::alloc::Layout,
+) {
+    check_system_table(system_table);
+    dealloc_from_boot_services((*system_table).boot_services, ptr, layout);
+}
+
+/// Deallocate Memory from UEFI Boot-Services Table
+///
+/// This is the same as `dealloc()`, but takes the boot-services table
+/// directly, rather than the system-table.
+///
+/// Safety
+/// ------
+///
+/// The memory block must be the same as previously returned by `alloc()` or
+/// `alloc_from_boot_services()`, and must have been allocated through the
+/// specified boot-services table. The passed layout must match the layout
+/// used to allocate the memory block.
+pub unsafe fn dealloc_from_boot_services(
+    boot_services: *mut efi::BootServices,
+    ptr: *mut u8,
+    layout: core::alloc::Layout,
 ) {
     // UEFI never allows null-pointers for allocations, hence such a pointer
     // cannot have been retrieved through `alloc()` previously.
     assert!(!ptr.is_null());
 
     // Un-align the pointer to get access to the actual start of the block.
-    let original = unalign_block(
+    let original = align::unalign_block(
         ptr,
-        layout.align(),
+        block_align(layout.align()),
     ) as *mut core::ffi::c_void;
 
     // Release the memory block via the boot-services.
-    let r = ((*(*system_table).boot_services).free_pool)(original);
+    check_boot_services(boot_services);
+    let r = ((*boot_services).free_pool)(original);
 
     // The spec allows returning errors from `FreePool()`. However, it
     // must serve any valid requests. Only `INVALID_PARAMETER` is
@@ -205,6 +557,253 @@ pub unsafe fn dealloc(
     assert!(!r.is_error());
 }
 
+/// Reallocate Memory from UEFI Boot-Services
+///
+/// Move the memory block at `ptr`, previously allocated with `old_layout`,
+/// into a new block allocated with `new_layout` and `memory_type`. The
+/// contents are copied via the UEFI `copy_mem` boot-services, up to the
+/// smaller of both sizes. The old block is released afterwards. Since the
+/// pool allocator cannot resize blocks, a new block is always allocated, even
+/// if the old one would be large enough.
+///
+/// This returns a null-pointer if the new block could not be allocated. In
+/// this case, the old block is left untouched and remains valid.
+///
+/// Safety
+/// ------
+///
+/// The memory block must be the same as previously returned by `alloc()` or
+/// `alloc_from_boot_services()` with `old_layout`, and must have been
+/// allocated through the specified boot-services table. The size of
+/// `new_layout` must not be 0. Furthermore, the same requirements as for
+/// `alloc_from_boot_services()` apply to the new block.
+pub unsafe fn realloc(
+    boot_services: *mut efi::BootServices,
+    ptr: *mut u8,
+    old_layout: core::alloc::Layout,
+    new_layout: core::alloc::Layout,
+    memory_type: efi::MemoryType,
+) -> *mut u8 {
+    let new = alloc_from_boot_services(boot_services, new_layout, memory_type);
+    if new.is_null() {
+        return new;
+    }
+
+    // The offset of both blocks to their original pool pointer is hidden
+    // by `alloc()` and `dealloc()`, so only the caller-visible bytes are
+    // copied.
+    ((*boot_services).copy_mem)(
+        new as *mut core::ffi::c_void,
+        ptr as *mut core::ffi::c_void,
+        core::cmp::min(old_layout.size(), new_layout.size()),
+    );
+    dealloc_from_boot_services(boot_services, ptr, old_layout);
+
+    new
+}
+
+/// Allocate an Array from UEFI Boot-Services
+///
+/// Allocate memory for an array of `n` elements of type `T`, using `alloc()`
+/// with the memory layout of the array. The layout computation is checked,
+/// hence a request whose size overflows the address-space simply fails. The
+/// returned memory is uninitialized.
+///
+/// This returns a null-pointer if the request cannot be served. If the array
+/// has a size of 0 (i.e., `n` is 0 or `T` is zero-sized), no memory is
+/// allocated and a dangling, properly aligned pointer is returned.
+///
+/// Safety
+/// ------
+///
+/// The same requirements as for `alloc()` apply, except that zero-sized
+/// arrays are permitted. The returned pointer must be released via
+/// `dealloc_array()` with the same element count.
+pub unsafe fn alloc_array<T>(
+    system_table: *mut efi::SystemTable,
+    n: usize,
+    memory_type: efi::MemoryType,
+) -> *mut T {
+    let layout = match core::alloc::Layout::array::<T>(n) {
+        Ok(v) => v,
+        Err(_) => return core::ptr::null_mut(),
+    };
+
+    if layout.size() == 0 {
+        core::ptr::NonNull::dangling().as_ptr()
+    } else {
+        alloc(system_table, layout, memory_type) as *mut T
+    }
+}
+
+/// Deallocate an Array from UEFI Boot-Services
+///
+/// Release an array previously allocated through `alloc_array()`. The
+/// elements of the array are not dropped.
+///
+/// Safety
+/// ------
+///
+/// The pointer must be the same as previously returned by `alloc_array()`,
+/// and `n` must be the element count passed to it. Furthermore, the same
+/// requirements as for `dealloc()` apply.
+pub unsafe fn dealloc_array<T>(
+    system_table: *mut efi::SystemTable,
+    ptr: *mut T,
+    n: usize,
+) {
+    // The layout was already verified by `alloc_array()`.
+    let layout = core::alloc::Layout::array::<T>(n).unwrap();
+
+    if layout.size() > 0 {
+        dealloc(system_table, ptr as *mut u8, layout);
+    }
+}
+
+// UEFI page allocations always use 4KiB pages, regardless of the page size
+// of the platform.
+pub(crate) const PAGE_SIZE: usize = 4096usize;
+
+/// Page Allocation Type
+///
+/// This selects the physical address range that `alloc_pages()` and the
+/// `alloc::PageAllocator` serve allocations from. It corresponds to the
+/// `EFI_ALLOCATE_TYPE` of the UEFI specification.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AllocateType {
+    /// Allocate pages at any available address.
+    AnyPages,
+    /// Allocate pages at any available address, such that the allocation
+    /// ends at or below the given address.
+    MaxAddress(efi::PhysicalAddress),
+    /// Allocate pages at exactly the given address.
+    Address(efi::PhysicalAddress),
+}
+
+/// Page Allocation
+///
+/// This describes a range of pages allocated via `alloc_pages()`. It carries
+/// the physical address of the first page and the number of pages, as needed
+/// to release the range via `free_pages()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Pages {
+    /// Physical address of the first page.
+    pub address: efi::PhysicalAddress,
+    /// Number of pages.
+    pub pages: usize,
+}
+
+impl Pages {
+    /// Return a pointer to the first page
+    ///
+    /// UEFI identity-maps all memory during boot-services, so the physical
+    /// address can be used as pointer directly.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.address as usize as *mut u8
+    }
+
+    /// Return the size of the page range in bytes
+    pub fn size(&self) -> usize {
+        // `alloc_pages()` verified the range fits the address-space.
+        self.pages * PAGE_SIZE
+    }
+}
+
+/// Allocate Pages from UEFI Boot-Services
+///
+/// Use the UEFI `allocate_pages` boot-services to allocate `pages` pages of
+/// the given memory type. `allocate_type` selects the address range to serve
+/// the pages from (see `AllocateType`).
+///
+/// Requests are validated before calling into the firmware. Fixed addresses
+/// must be page-aligned, and the page range must fit the address-space of
+/// the platform (in particular, on 32-bit platforms memory beyond 4GiB is
+/// not addressable). Invalid requests fail with `INVALID_PARAMETER`. If the
+/// firmware returns NULL, or a range that is not addressable, the pages are
+/// released again and the request fails with `OUT_OF_RESOURCES`.
+///
+/// Safety
+/// ------
+///
+/// It must be safe for this function to call `allocate_pages` and
+/// `free_pages` of the specified boot-services table. The returned pages must
+/// be released via `free_pages()`, or accounted for otherwise.
+pub unsafe fn alloc_pages(
+    boot_services: *mut efi::BootServices,
+    allocate_type: AllocateType,
+    memory_type: efi::MemoryType,
+    pages: usize,
+) -> Result<Pages, efi::Status> {
+    let size = match pages.checked_mul(PAGE_SIZE) {
+        Some(v) if v > 0 => v,
+        _ => return Err(efi::Status::INVALID_PARAMETER),
+    };
+
+    let (allocate_type, mut address) = match allocate_type {
+        AllocateType::AnyPages => (efi::ALLOCATE_ANY_PAGES, 0),
+        AllocateType::MaxAddress(v) => {
+            (efi::ALLOCATE_MAX_ADDRESS, v)
+        }
+        AllocateType::Address(v) => {
+            if v & (PAGE_SIZE as u64 - 1) != 0
+                || page_range(v, size).is_none()
+            {
+                return Err(efi::Status::INVALID_PARAMETER);
+            }
+            (efi::ALLOCATE_ADDRESS, v)
+        }
+    };
+
+    check_boot_services(boot_services);
+    let r = ((*boot_services).allocate_pages)(
+        allocate_type,
+        memory_type,
+        pages,
+        &mut address,
+    );
+    if r.is_error() {
+        return Err(r);
+    }
+
+    // Like with pool allocations, NULL is never a valid address for rust
+    // pointers, so treat it as failure (but release the pages first). The
+    // same applies to ranges that the platform cannot address.
+    let pages = Pages { address, pages };
+    if address == 0 || page_range(address, size).is_none() {
+        free_pages(boot_services, pages);
+        return Err(efi::Status::OUT_OF_RESOURCES);
+    }
+
+    Ok(pages)
+}
+
+// Return the start address of a page range of `size` bytes at `address`, if
+// the range fits the address-space of the platform.
+fn page_range(address: efi::PhysicalAddress, size: usize) -> Option<usize> {
+    let start: usize = core::convert::TryFrom::try_from(address).ok()?;
+    start.checked_add(size - 1)?;
+    Some(start)
+}
+
+/// Release Pages to UEFI Boot-Services
+///
+/// Use the UEFI `free_pages` boot-services to release a range of pages. The
+/// range can be any part of a range allocated via `alloc_pages()`.
+///
+/// Safety
+/// ------
+///
+/// The pages must have been allocated via `alloc_pages()` through the
+/// specified boot-services table, and must not have been released before.
+pub unsafe fn free_pages(boot_services: *mut efi::BootServices, pages: Pages) {
+    check_boot_services(boot_services);
+    let r = ((*boot_services).free_pages)(pages.address, pages.pages);
+
+    // Like `FreePool()`, this can only fail for invalid requests, so we
+    // assert on the result for diagnostics.
+    assert!(!r.is_error());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,7 +816,7 @@ mod tests {
 
         // UEFI ABI specifies that allocation alignment minimum is always 8. So
         // this can be statically verified.
-        assert_eq!(POOL_ALIGNMENT, 8);
+        assert_eq!(align::POOL_ALIGNMENT, 8);
 
         // Loop over allocation-request sizes from 0-256 and alignments from
         // 1-128, and verify that in case of overalignment there is at least
@@ -225,11 +824,304 @@ mod tests {
         for i in 0..256 {
             for j in &[1, 2, 4, 8, 16, 32, 64, 128] {
                 if *j <= 8 {
-                    assert_eq!(align_request(i, *j), i);
+                    assert_eq!(align::align_request(i, *j), Some(i));
                 } else {
-                    assert!(align_request(i, *j) > i + ptrsize);
+                    assert!(align::align_request(i, *j).unwrap() > i + ptrsize);
                 }
             }
         }
+
+        // Requests that overflow the address-space cannot be served.
+        assert_eq!(align::align_request(usize::MAX, 8), Some(usize::MAX));
+        assert_eq!(align::align_request(usize::MAX - 8, 16), None);
+    }
+
+    // Verify that over-aligned blocks report the padding behind them as
+    // usable, and that blocks are only reused for compatible layouts. With
+    // `pool_quirks`, all blocks carry a marker and twice the padding.
+    #[test]
+    fn in_place() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let l8 = core::alloc::Layout::from_size_align(32, 8).unwrap();
+        let l64 = core::alloc::Layout::from_size_align(32, 64).unwrap();
+        let quirks = cfg!(feature = "pool_quirks");
+
+        unsafe {
+            let ptr = alloc(st, l8, efi::LOADER_DATA);
+            if quirks {
+                assert!(usable_size(ptr, l8) >= 32);
+            } else {
+                assert_eq!(usable_size(ptr, l8), 32);
+            }
+            assert!(fits_in_place(ptr, l8, l8));
+            assert!(!fits_in_place(ptr, l8, l64));
+            let l = core::alloc::Layout::from_size_align(16, 4).unwrap();
+            assert!(fits_in_place(ptr, l8, l));
+            dealloc(st, ptr, l8);
+
+            let ptr = alloc(st, l64, efi::LOADER_DATA);
+            let usable = usable_size(ptr, l64);
+            let slack = if quirks { 2 * 64 } else { 64 };
+            assert!((32..=32 + slack - 8).contains(&usable));
+            core::ptr::write_bytes(ptr, 0xff, usable);
+            assert!(!fits_in_place(ptr, l64, l8));
+            let l = core::alloc::Layout::from_size_align(usable, 64).unwrap();
+            assert!(fits_in_place(ptr, l64, l));
+            dealloc(st, ptr, l);
+            assert_eq!(crate::mock::pool_live(), 0);
+        }
+    }
+
+    // Verify the CRC32 implementation against the well-known check value
+    // of the algorithm.
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789".iter().copied()), 0xcbf43926);
+        assert_eq!(crc32(core::iter::empty()), 0);
+    }
+
+    // Verify that a corrupted boot-services table is caught before calling
+    // into it.
+    #[cfg(feature = "check_tables")]
+    #[test]
+    #[should_panic(expected = "invalid boot-services checksum")]
+    fn stale_tables() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            (*(*st).boot_services).hdr.revision ^= 1;
+            alloc(st, layout, efi::LOADER_DATA);
+        }
+    }
+
+    // Run allocations through the mock firmware, which provides the
+    // boot-services as `extern "efiapi"` function pointers. Verify that all
+    // alignments are served and that every block is returned to the pool.
+    #[test]
+    fn pool_roundtrip() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+
+        for size in &[1, 7, 8, 63, 4096] {
+            for align in &[1, 2, 4, 8, 16, 32, 64, 128, 4096] {
+                let layout = core::alloc::Layout::from_size_align(*size, *align)
+                    .unwrap();
+
+                unsafe {
+                    let ptr = alloc(st, layout, efi::LOADER_DATA);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0);
+                    assert_eq!(crate::mock::pool_live(), 1);
+                    assert_eq!(
+                        crate::mock::pool_memory_type(),
+                        Some(efi::LOADER_DATA),
+                    );
+
+                    core::ptr::write_bytes(ptr, 0xff, *size);
+                    dealloc(st, ptr, layout);
+                    assert_eq!(crate::mock::pool_live(), 0);
+                }
+            }
+        }
+    }
+
+    // Verify that the boot-services variants are interchangeable with the
+    // system-table variants, and that they never touch the system-table.
+    #[test]
+    fn boot_services() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let bs = unsafe { (*st).boot_services };
+        let layout = core::alloc::Layout::from_size_align(32, 64).unwrap();
+
+        unsafe {
+            (*st).hdr.signature = 0;
+
+            let ptr = alloc_from_boot_services(bs, layout, efi::LOADER_DATA);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 64, 0);
+            assert_eq!(crate::mock::pool_live(), 1);
+            dealloc_from_boot_services(bs, ptr, layout);
+            assert_eq!(crate::mock::pool_live(), 0);
+
+            (*st).hdr.signature = efi::SYSTEM_TABLE_SIGNATURE;
+
+            let ptr = alloc(st, layout, efi::LOADER_DATA);
+            assert!(!ptr.is_null());
+            dealloc_from_boot_services(bs, ptr, layout);
+            assert_eq!(crate::mock::pool_live(), 0);
+        }
+    }
+
+    // Move blocks between alignments and sizes, and verify the contents are
+    // retained and the old blocks are released.
+    #[test]
+    fn realloc_copy() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let bs = unsafe { (*st).boot_services };
+        let l8 = core::alloc::Layout::from_size_align(32, 8).unwrap();
+        let l64 = core::alloc::Layout::from_size_align(128, 64).unwrap();
+
+        unsafe {
+            let ptr = alloc_from_boot_services(bs, l8, efi::LOADER_DATA);
+            for i in 0..32 {
+                ptr.add(i).write(i as u8);
+            }
+
+            let ptr = realloc(bs, ptr, l8, l64, efi::LOADER_DATA);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 64, 0);
+            assert_eq!(crate::mock::pool_live(), 1);
+            for i in 0..32 {
+                assert_eq!(*ptr.add(i), i as u8);
+            }
+            core::ptr::write_bytes(ptr.add(32), 0xff, 96);
+
+            let ptr = realloc(bs, ptr, l64, l8, efi::LOADER_DATA);
+            assert!(!ptr.is_null());
+            assert_eq!(crate::mock::pool_live(), 1);
+            for i in 0..32 {
+                assert_eq!(*ptr.add(i), i as u8);
+            }
+
+            crate::mock::pool_fail(Some(efi::Status::OUT_OF_RESOURCES));
+            assert!(realloc(bs, ptr, l8, l64, efi::LOADER_DATA).is_null());
+            crate::mock::pool_fail(None);
+            assert_eq!(*ptr.add(31), 31);
+
+            dealloc_from_boot_services(bs, ptr, l8);
+            assert_eq!(crate::mock::pool_live(), 0);
+        }
+    }
+
+    // Allocate page ranges and verify invalid requests are rejected before
+    // reaching the firmware.
+    #[test]
+    fn pages() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let bs = unsafe { (*st).boot_services };
+
+        unsafe {
+            let p = alloc_pages(bs, AllocateType::AnyPages, efi::LOADER_DATA, 2)
+                .unwrap();
+            assert_eq!(p.pages, 2);
+            assert_eq!(p.size(), 8192);
+            assert_eq!(p.address % 4096, 0);
+            assert_eq!(crate::mock::pages_live(), 2);
+            core::ptr::write_bytes(p.as_ptr(), 0xff, p.size());
+            free_pages(bs, p);
+            assert_eq!(crate::mock::pages_live(), 0);
+
+            let t = AllocateType::MaxAddress(u64::MAX);
+            let p = alloc_pages(bs, t, efi::LOADER_DATA, 1).unwrap();
+            free_pages(bs, p);
+
+            for (t, pages) in &[
+                (AllocateType::AnyPages, 0),
+                (AllocateType::AnyPages, usize::MAX),
+                (AllocateType::Address(0x1001), 1),
+                (AllocateType::Address(usize::MAX as u64 & !0xfff), 2),
+            ] {
+                assert_eq!(
+                    alloc_pages(bs, *t, efi::LOADER_DATA, *pages)
+                        .map_err(|v| v.as_usize()),
+                    Err(efi::Status::INVALID_PARAMETER.as_usize()),
+                );
+            }
+            assert_eq!(crate::mock::pages_live(), 0);
+        }
+    }
+
+    // Verify that failures of `AllocatePool()` are reported as NULL by
+    // `alloc()`, and with the original status by `alloc_status()`.
+    #[test]
+    fn pool_failure() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        crate::mock::pool_fail(Some(efi::Status::INVALID_PARAMETER));
+        unsafe {
+            assert!(alloc(st, layout, efi::LOADER_DATA).is_null());
+            assert_eq!(
+                alloc_status(st, layout, efi::LOADER_DATA)
+                    .map_err(|v| v.as_usize()),
+                Err(efi::Status::INVALID_PARAMETER.as_usize()),
+            );
+        }
+        crate::mock::pool_fail(None);
+
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that misaligned pool pointers are counted, and never returned
+    // for requests they do not satisfy.
+    #[test]
+    fn misaligned() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let quirks = pool_quirks();
+
+        crate::mock::pool_misalign(4);
+        for align in &[1, 4, 8, 64] {
+            let layout =
+                core::alloc::Layout::from_size_align(32, *align).unwrap();
+
+            unsafe {
+                let ptr = alloc(st, layout, efi::LOADER_DATA);
+                if cfg!(feature = "pool_quirks") || *align <= 4 {
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0);
+                    core::ptr::write_bytes(ptr, 0xff, 32);
+                    dealloc(st, ptr, layout);
+                } else {
+                    assert!(ptr.is_null());
+                }
+            }
+        }
+        crate::mock::pool_misalign(0);
+
+        assert!(pool_quirks() >= quirks + 4);
+        assert_eq!(crate::mock::pool_live(), 0);
+    }
+
+    // Verify that arrays are allocated with the correct layout, that
+    // zero-sized arrays never reach the firmware, and that overflowing
+    // element counts are rejected.
+    #[test]
+    fn array() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+
+        unsafe {
+            let ptr = alloc_array::<u64>(st, 16, efi::LOADER_DATA);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % core::mem::align_of::<u64>(), 0);
+            assert_eq!(crate::mock::pool_live(), 1);
+            for i in 0..16 {
+                ptr.add(i).write(i as u64);
+            }
+            dealloc_array(st, ptr, 16);
+            assert_eq!(crate::mock::pool_live(), 0);
+
+            let ptr = alloc_array::<u64>(st, 0, efi::LOADER_DATA);
+            assert!(!ptr.is_null());
+            assert_eq!(crate::mock::pool_live(), 0);
+            dealloc_array(st, ptr, 0);
+
+            let ptr = alloc_array::<()>(st, 16, efi::LOADER_DATA);
+            assert!(!ptr.is_null());
+            assert_eq!(crate::mock::pool_live(), 0);
+            dealloc_array(st, ptr, 16);
+
+            let ptr = alloc_array::<u64>(st, usize::MAX / 4, efi::LOADER_DATA);
+            assert!(ptr.is_null());
+            assert_eq!(crate::mock::pool_live(), 0);
+        }
     }
 }
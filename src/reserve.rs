@@ -0,0 +1,219 @@
+//! Reserve Allocator
+//!
+//! When the firmware pool is exhausted, code unwinding from the failure often
+//! needs a few small allocations itself (e.g., to box an error or format a
+//! message). This module provides a tiny slab allocator that is held in
+//! reserve for exactly this case. It serves allocations smaller than
+//! `RESERVE_LIMIT` bytes from a static buffer embedded in the object, and
+//! never calls into the firmware.
+//!
+//! A reserve can be registered with a `global::Bridge` via
+//! `Bridge::with_reserve()`. The bridge then serves small allocations from
+//! the reserve whenever its regular allocator fails them, and returns blocks
+//! of the reserve to it when released. Larger allocations never touch the
+//! reserve, so it cannot be drained by a single large request.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! use r_efi_alloc::{global::Bridge, reserve::{Reserve, Slot}};
+//!
+//! // 64 slots of 64 bytes each, roughly 5KiB including bookkeeping.
+//! static RESERVE: Reserve<[Slot; 64]> = Reserve::new();
+//!
+//! #[global_allocator]
+//! static GLOBAL_ALLOCATOR: Bridge = Bridge::new().with_reserve(&RESERVE);
+//! ```
+
+use core::sync::atomic;
+
+/// Size Limit of Reserve Allocations
+///
+/// Only allocations smaller than this are served by a reserve.
+pub const RESERVE_LIMIT: usize = 64usize;
+
+/// Reserve Slot
+///
+/// This is a single slot of a reserve, which can hold one allocation. It has
+/// no public interface, and is only used to declare the capacity of a
+/// reserve (e.g., `Reserve<[Slot; 64]>`).
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+pub struct Slot {
+    data: [core::mem::MaybeUninit<u8>; RESERVE_LIMIT],
+    used: bool,
+}
+
+/// Reserve Allocator
+///
+/// This is a slab allocator with a fixed number of slots, each serving a
+/// single allocation smaller than `RESERVE_LIMIT` bytes with an alignment of
+/// at most 16. The slot array `S` is declared with the desired capacity
+/// (e.g., `Reserve<[Slot; 64]>`). References to it can be coerced to the
+/// unsized `Reserve` type, which is independent of the capacity.
+///
+/// Every slot is claimed and released via an atomic flag of its own, so no
+/// operation ever waits for another. Hence, the reserve can be used from
+/// callbacks that interrupt another operation on it.
+pub struct Reserve<S: ?Sized = [Slot]> {
+    capacity: usize,
+    slots: core::cell::UnsafeCell<S>,
+}
+
+// The data of a slot is only ever accessed by the owner of the block, which
+// claimed the slot via its atomic flag.
+unsafe impl<S: ?Sized> Sync for Reserve<S> {}
+
+impl<const N: usize> Reserve<[Slot; N]> {
+    /// Create Reserve
+    ///
+    /// Create a new reserve with `N` free slots. This is a constant function,
+    /// so the reserve can be used to initialize a static variable.
+    pub const fn new() -> Self {
+        let slot = Slot {
+            data: [core::mem::MaybeUninit::uninit(); RESERVE_LIMIT],
+            used: false,
+        };
+
+        Self {
+            capacity: N,
+            slots: core::cell::UnsafeCell::new([slot; N]),
+        }
+    }
+}
+
+impl<const N: usize> Default for Reserve<[Slot; N]> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: ?Sized> Reserve<S> {
+    fn base(&self) -> usize {
+        self.slots.get() as *mut Slot as usize
+    }
+
+    // Return a pointer to the slot at `index`. Its data must only be
+    // accessed while the slot is claimed.
+    fn slot(&self, index: usize) -> *mut Slot {
+        assert!(index < self.capacity);
+        unsafe { (self.slots.get() as *mut Slot).add(index) }
+    }
+
+    // Return the flag marking the slot at `index` as used. The slots live in
+    // an `UnsafeCell`, and `AtomicBool` has the same in-memory
+    // representation as `bool`, so the flag is accessed atomically in place.
+    // It is never accessed non-atomically.
+    fn used_flag(&self, index: usize) -> &atomic::AtomicBool {
+        let flag = unsafe { core::ptr::addr_of_mut!((*self.slot(index)).used) };
+        unsafe { &*(flag as *const atomic::AtomicBool) }
+    }
+
+    /// Return the number of slots of the reserve
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Return the number of slots currently in use
+    ///
+    /// Slots claimed or released concurrently might or might not be
+    /// included.
+    pub fn used(&self) -> usize {
+        (0..self.capacity)
+            .filter(|i| self.used_flag(*i).load(atomic::Ordering::Relaxed))
+            .count()
+    }
+
+    /// Check whether a pointer was allocated from this reserve
+    ///
+    /// Return true if `ptr` points into the slots of this reserve. This
+    /// allows routing deallocations to the correct allocator if multiple
+    /// allocators are in use.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let base = self.base();
+        let addr = ptr as usize;
+
+        addr >= base
+            && addr - base < self.capacity() * core::mem::size_of::<Slot>()
+    }
+
+    /// Allocate Memory from the Reserve
+    ///
+    /// Allocate a free slot for the given layout. This returns a null-pointer
+    /// if the layout is not smaller than `RESERVE_LIMIT` bytes, if its
+    /// alignment exceeds 16, or if all slots are in use.
+    pub fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if layout.size() >= RESERVE_LIMIT
+            || layout.align() > core::mem::align_of::<Slot>()
+        {
+            return core::ptr::null_mut();
+        }
+
+        for i in 0..self.capacity {
+            let claimed = self.used_flag(i).compare_exchange(
+                false,
+                true,
+                atomic::Ordering::Acquire,
+                atomic::Ordering::Relaxed,
+            );
+
+            if claimed.is_ok() {
+                let slot = self.slot(i);
+                return unsafe { (*slot).data.as_mut_ptr() as *mut u8 };
+            }
+        }
+
+        core::ptr::null_mut()
+    }
+
+    /// Deallocate Memory to the Reserve
+    ///
+    /// Release a block previously allocated via `alloc()`, so its slot can
+    /// serve further allocations.
+    ///
+    /// Safety
+    /// ------
+    ///
+    /// The block must have been allocated via `alloc()` of this reserve, and
+    /// must not be used afterwards.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, _layout: core::alloc::Layout) {
+        assert!(self.contains(ptr));
+
+        let index = (ptr as usize - self.base()) / core::mem::size_of::<Slot>();
+
+        // Release pairs with the Acquire of `alloc()`, so all accesses of the
+        // previous owner are done before the slot is claimed again.
+        self.used_flag(index).store(false, atomic::Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify that slots are handed out and reused, and that only small
+    // requests are served.
+    #[test]
+    fn slots() {
+        let r: Reserve<[Slot; 2]> = Reserve::new();
+        let r: &Reserve = &r;
+        let layout = |size, align| {
+            core::alloc::Layout::from_size_align(size, align).unwrap()
+        };
+
+        assert_eq!(r.capacity(), 2);
+        assert!(r.alloc(layout(64, 8)).is_null());
+        assert!(r.alloc(layout(8, 32)).is_null());
+
+        let p0 = r.alloc(layout(63, 16));
+        let p1 = r.alloc(layout(1, 1));
+        assert!(!p0.is_null() && !p1.is_null());
+        assert_eq!(p0 as usize % 16, 0);
+        assert!(r.contains(p1));
+        assert_eq!(r.used(), 2);
+        assert!(r.alloc(layout(1, 1)).is_null());
+
+        unsafe { r.dealloc(p0, layout(63, 16)) };
+        assert_eq!(r.alloc(layout(8, 8)), p0);
+    }
+}
@@ -0,0 +1,268 @@
+//! Allocation Statistics
+//!
+//! This module provides counters for memory allocations, which help to
+//! diagnose memory pressure in pre-boot environments, where no tooling of an
+//! operating system is available. The `Stats` type keeps the counters in
+//! atomics, and `Snapshot` is a plain copy of them at a given point in time.
+//!
+//! With the `stats` feature, every `alloc::Allocator` and `global::Bridge`
+//! keeps its own counters, available via `Allocator::stats()` and
+//! `Bridge::stats()`. `Stats` also implements `AllocObserver`, so it can be
+//! attached to any allocation path that supports observers.
+//!
+//! Furthermore, all allocators forward their counters to a set of global
+//! counters, available via `global()`. These describe the total heap
+//! footprint of the image, regardless of how many allocators are in use.
+
+use core::sync::atomic;
+
+/// Allocation Counters
+///
+/// This keeps counters of allocations, deallocations, failures and leaks, as
+/// well as the number of bytes currently in use and its peak. All counters are
+/// updated with relaxed atomics. They are purely diagnostic, and a snapshot
+/// taken while allocations are in flight might be slightly inconsistent.
+pub struct Stats {
+    bytes_in_use: atomic::AtomicUsize,
+    peak_bytes: atomic::AtomicUsize,
+    allocations: atomic::AtomicUsize,
+    deallocations: atomic::AtomicUsize,
+    failures: atomic::AtomicUsize,
+    leaks: atomic::AtomicUsize,
+    parent: Option<&'static Stats>,
+}
+
+// Global counters of all `alloc::Allocator` instances. Bridges are not
+// forwarded here, since they serve their allocations through allocators,
+// which are counted already.
+pub(crate) static GLOBAL: Stats = Stats::new();
+
+/// Allocation Counter Snapshot
+///
+/// This is a copy of the counters of a `Stats` object, as returned by
+/// `Stats::snapshot()`. Byte counts refer to the sizes requested by the
+/// caller, and do not include any overhead of the underlying allocator.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Snapshot {
+    /// Number of bytes currently allocated.
+    pub bytes_in_use: usize,
+    /// Highest number of bytes allocated at any time.
+    pub peak_bytes: usize,
+    /// Number of successful allocations.
+    pub allocations: usize,
+    /// Number of deallocations.
+    pub deallocations: usize,
+    /// Number of failed allocations.
+    pub failures: usize,
+    /// Number of blocks that were released, but could not be returned to
+    /// the firmware. Their bytes are still counted as in use.
+    pub leaks: usize,
+    /// Number of misaligned pointers returned by the pool allocator of the
+    /// firmware. This is a global count, shared by all allocators (see
+    /// `raw::pool_quirks()`).
+    pub pool_quirks: usize,
+}
+
+impl Stats {
+    /// Create Counters
+    ///
+    /// Create a new set of counters, all initialized to 0. This is a
+    /// constant function, so the counters can be used to initialize a
+    /// static variable.
+    pub const fn new() -> Stats {
+        Stats {
+            bytes_in_use: atomic::AtomicUsize::new(0),
+            peak_bytes: atomic::AtomicUsize::new(0),
+            allocations: atomic::AtomicUsize::new(0),
+            deallocations: atomic::AtomicUsize::new(0),
+            failures: atomic::AtomicUsize::new(0),
+            leaks: atomic::AtomicUsize::new(0),
+            parent: None,
+        }
+    }
+
+    /// Forward Counters to Parent
+    ///
+    /// Make these counters forward every record to the counters given as
+    /// `parent`, in addition to recording it themselves. This allows
+    /// aggregating the counters of multiple allocators.
+    pub const fn with_parent(self, parent: &'static Stats) -> Stats {
+        Stats {
+            parent: Some(parent),
+            ..self
+        }
+    }
+
+    /// Record a successful allocation of `size` bytes
+    pub fn record_alloc(&self, size: usize) {
+        let v = self.bytes_in_use.fetch_add(size, atomic::Ordering::Relaxed);
+        self.peak_bytes.fetch_max(v + size, atomic::Ordering::Relaxed);
+        self.allocations.fetch_add(1, atomic::Ordering::Relaxed);
+        if let Some(parent) = self.parent {
+            parent.record_alloc(size);
+        }
+    }
+
+    /// Record a deallocation of `size` bytes
+    ///
+    /// Every allocator keeps its own counters, and clones of an allocator
+    /// start with fresh ones. A block served by one allocator can thus be
+    /// released through another (e.g., a clone, or the new attachment of a
+    /// bridge after `swap()`), which has never recorded it. Hence, the number
+    /// of bytes in use saturates at 0 rather than wrapping around.
+    pub fn record_dealloc(&self, size: usize) {
+        let _ = self.bytes_in_use.fetch_update(
+            atomic::Ordering::Relaxed,
+            atomic::Ordering::Relaxed,
+            |v| Some(v.saturating_sub(size)),
+        );
+        self.deallocations.fetch_add(1, atomic::Ordering::Relaxed);
+        if let Some(parent) = self.parent {
+            parent.record_dealloc(size);
+        }
+    }
+
+    /// Record a failed allocation
+    pub fn record_failure(&self) {
+        self.failures.fetch_add(1, atomic::Ordering::Relaxed);
+        if let Some(parent) = self.parent {
+            parent.record_failure();
+        }
+    }
+
+    /// Record a leaked block
+    ///
+    /// A leaked block is still allocated, so the number of bytes in use is
+    /// left unchanged.
+    pub fn record_leak(&self) {
+        self.leaks.fetch_add(1, atomic::Ordering::Relaxed);
+        if let Some(parent) = self.parent {
+            parent.record_leak();
+        }
+    }
+
+    /// Take a Snapshot of the Counters
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            bytes_in_use: self.bytes_in_use.load(atomic::Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.load(atomic::Ordering::Relaxed),
+            allocations: self.allocations.load(atomic::Ordering::Relaxed),
+            deallocations: self.deallocations.load(atomic::Ordering::Relaxed),
+            failures: self.failures.load(atomic::Ordering::Relaxed),
+            leaks: self.leaks.load(atomic::Ordering::Relaxed),
+            pool_quirks: crate::raw::pool_quirks(),
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Query Global Allocation Statistics
+///
+/// Return a snapshot of the global counters, which sum up the counters of
+/// all `alloc::Allocator` instances of this image. Allocations through
+/// bridges are included via the allocators attached to them.
+pub fn global() -> Snapshot {
+    GLOBAL.snapshot()
+}
+
+impl crate::observe::AllocObserver for Stats {
+    fn on_alloc(&self, _ptr: *mut u8, layout: core::alloc::Layout) {
+        self.record_alloc(layout.size());
+    }
+
+    fn on_dealloc(&self, _ptr: *mut u8, layout: core::alloc::Layout) {
+        self.record_dealloc(layout.size());
+    }
+
+    fn on_failure(&self, _layout: core::alloc::Layout) {
+        self.record_failure();
+    }
+
+    fn on_leak(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+        self.record_leak();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verify that the counters track usage and its peak, and that foreign
+    // releases do not wrap the usage around.
+    #[test]
+    fn counters() {
+        let stats = Stats::new();
+
+        stats.record_alloc(64);
+        stats.record_alloc(32);
+        stats.record_dealloc(64);
+        stats.record_alloc(16);
+        stats.record_failure();
+        stats.record_leak();
+
+        // The quirk counter is global, so other tests might modify it.
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot,
+            Snapshot {
+                bytes_in_use: 48,
+                peak_bytes: 96,
+                allocations: 3,
+                deallocations: 1,
+                failures: 1,
+                leaks: 1,
+                pool_quirks: snapshot.pool_quirks,
+            },
+        );
+
+        // Releases of blocks recorded elsewhere saturate at 0.
+        stats.record_dealloc(64);
+        stats.record_alloc(8);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.bytes_in_use, 8);
+        assert_eq!(snapshot.peak_bytes, 96);
+    }
+
+    // Verify that records are forwarded to the parent, and that allocators
+    // contribute to the global counters.
+    #[test]
+    fn parent() {
+        static PARENT: Stats = Stats::new();
+
+        let a = Stats::new().with_parent(&PARENT);
+        let b = Stats::new().with_parent(&PARENT);
+        a.record_alloc(64);
+        b.record_alloc(32);
+        a.record_dealloc(64);
+        b.record_failure();
+
+        let snapshot = PARENT.snapshot();
+        assert_eq!(snapshot.bytes_in_use, 32);
+        assert_eq!(snapshot.peak_bytes, 96);
+        assert_eq!(snapshot.allocations, 2);
+        assert_eq!(snapshot.deallocations, 1);
+        assert_eq!(snapshot.failures, 1);
+        assert_eq!(a.snapshot().allocations, 1);
+
+        // Other tests allocate concurrently, so only verify the global
+        // counters advance.
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let allocator = unsafe {
+            crate::alloc::Allocator::from_system_table(
+                st,
+                r_efi::efi::LOADER_DATA,
+            )
+        };
+        let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+        let allocations = global().allocations;
+
+        unsafe { allocator.dealloc(allocator.alloc(layout), layout) };
+        assert!(global().allocations > allocations);
+    }
+}
@@ -0,0 +1,132 @@
+//! Standard Library Compatibility
+//!
+//! The UEFI platform support of the rust standard library uses this crate as
+//! its memory allocator. This module provides the interface it relies on,
+//! with signatures that are kept stable across releases of this crate. Other
+//! modules are free to evolve, and this module adapts them to the stable
+//! interface.
+//!
+//! All functions take the boot-services table directly, and report failures
+//! with the UEFI status code of the firmware. Both the pool allocator and
+//! the page allocator are covered. Like the `raw` module, this module does
+//! not track any state.
+
+use r_efi::efi;
+
+/// Allocate Pool Memory
+///
+/// Allocate a block satisfying the given layout from the pool allocator of
+/// the firmware, using the given memory type. Over-aligned layouts are
+/// supported. On failure, the status code of the firmware is returned.
+///
+/// Safety
+/// ------
+///
+/// The same requirements as for `raw::alloc_from_boot_services()` apply. The
+/// block must be released via `dealloc()`.
+pub unsafe fn alloc(
+    boot_services: *mut efi::BootServices,
+    layout: core::alloc::Layout,
+    memory_type: efi::MemoryType,
+) -> Result<core::ptr::NonNull<u8>, efi::Status> {
+    crate::raw::boot_services_alloc_status(boot_services, layout, memory_type)
+        .map(|v| core::ptr::NonNull::new_unchecked(v))
+}
+
+/// Deallocate Pool Memory
+///
+/// Release a block previously allocated via `alloc()`.
+///
+/// Safety
+/// ------
+///
+/// The same requirements as for `raw::dealloc_from_boot_services()` apply.
+pub unsafe fn dealloc(
+    boot_services: *mut efi::BootServices,
+    ptr: core::ptr::NonNull<u8>,
+    layout: core::alloc::Layout,
+) {
+    crate::raw::dealloc_from_boot_services(boot_services, ptr.as_ptr(), layout)
+}
+
+/// Allocate Pages
+///
+/// Allocate `pages` pages of the given memory type at any address from the
+/// page allocator of the firmware. On failure, the status code of the
+/// firmware is returned.
+///
+/// Safety
+/// ------
+///
+/// The same requirements as for `raw::alloc_pages()` apply. The pages must be
+/// released via `free_pages()`.
+pub unsafe fn alloc_pages(
+    boot_services: *mut efi::BootServices,
+    memory_type: efi::MemoryType,
+    pages: usize,
+) -> Result<core::ptr::NonNull<u8>, efi::Status> {
+    crate::raw::alloc_pages(
+        boot_services,
+        crate::alloc::AllocateType::AnyPages,
+        memory_type,
+        pages,
+    )
+    .map(|v| core::ptr::NonNull::new_unchecked(v.as_ptr()))
+}
+
+/// Release Pages
+///
+/// Release `pages` pages at `ptr`, previously allocated via
+/// `alloc_pages()`.
+///
+/// Safety
+/// ------
+///
+/// The same requirements as for `raw::free_pages()` apply.
+pub unsafe fn free_pages(
+    boot_services: *mut efi::BootServices,
+    ptr: core::ptr::NonNull<u8>,
+    pages: usize,
+) {
+    crate::raw::free_pages(
+        boot_services,
+        crate::raw::Pages {
+            address: ptr.as_ptr() as usize as efi::PhysicalAddress,
+            pages,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run pool and page allocations through the shim, and verify failures
+    // carry the status of the firmware.
+    #[test]
+    fn roundtrip() {
+        let mut fw = crate::mock::Firmware::new();
+        let st = fw.system_table();
+        let bs = unsafe { (*st).boot_services };
+        let layout = core::alloc::Layout::from_size_align(64, 64).unwrap();
+
+        unsafe {
+            let ptr = alloc(bs, layout, efi::LOADER_DATA).unwrap();
+            assert_eq!(ptr.as_ptr() as usize % 64, 0);
+            dealloc(bs, ptr, layout);
+            assert_eq!(crate::mock::pool_live(), 0);
+
+            crate::mock::pool_fail(Some(efi::Status::OUT_OF_RESOURCES));
+            assert_eq!(
+                alloc(bs, layout, efi::LOADER_DATA).map_err(|v| v.as_usize()),
+                Err(efi::Status::OUT_OF_RESOURCES.as_usize()),
+            );
+            crate::mock::pool_fail(None);
+
+            let ptr = alloc_pages(bs, efi::LOADER_DATA, 2).unwrap();
+            assert_eq!(crate::mock::pages_live(), 2);
+            free_pages(bs, ptr, 2);
+            assert_eq!(crate::mock::pages_live(), 0);
+        }
+    }
+}